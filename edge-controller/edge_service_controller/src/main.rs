@@ -1,10 +1,22 @@
 mod policies;
 
+use std::env;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
 use kube::{Config, Client};
 use log::{info, error};
 use anyhow::{anyhow, Result};
+use edge_service_lib::admission;
+use edge_service_lib::policy::PolicyConfig;
+use edge_service_lib::simulator::Simulator;
 
-type POLICY = policies::FromFile;
+/// Política base de los servicios, se elige con EDGE_CONTROLLER_POLICY.
+type FromFilePolicy = policies::Rules<policies::Scripted<policies::FromFile>>;
+type CapacityPolicy = policies::Rules<policies::Scripted<policies::Capacity>>;
+
+const DEFAULT_INVENTORY_INTERVAL_MS: u64 = 2000;
+const DEFAULT_NAMESPACE: &str = "kube-triton";
+const DEFAULT_WEBHOOK_PORT: u16 = 8443;
 
 #[tokio::main]
 async fn main() -> Result<()> {
@@ -17,6 +29,62 @@ async fn main() -> Result<()> {
     }
     else { env_logger::init(); }
 
+    match env::var("EDGE_CONTROLLER_POLICY").as_deref() {
+        Ok("from_file") | Err(_) => start::<FromFilePolicy>().await,
+        Ok("capacity") => start::<CapacityPolicy>().await,
+        Ok(other) => Err(anyhow!("Unknown policy {other}, expected from_file or capacity"))
+    }
+}
+
+async fn start<Policy: edge_service_lib::policy::Policy + 'static>() -> Result<()> {
+
+    // edge_service_controller simulate [script] < trace.jsonl > steps.jsonl
+    if env::args().nth(1).as_deref() == Some("simulate") {
+        let mut simulator = Simulator::<Policy>::from_default().await;
+        simulator.configure(&PolicyConfig { script: env::args().nth(2), ..Default::default() })?;
+        return simulator.run(std::io::stdin().lock(), std::io::stdout().lock());
+    }
+
+    // edge_service_controller crdgen > yaml_files/edgeservice-crd.yaml
+    // edge_service_controller crdgen webhook > yaml_files/edgeservice-webhook.yaml
+    if env::args().nth(1).as_deref() == Some("crdgen") {
+        let manifests = match env::args().nth(2).as_deref() {
+            Some("webhook") => {
+                let namespace = env::var("EDGE_CONTROLLER_NAMESPACE").unwrap_or(DEFAULT_NAMESPACE.to_string());
+                let (service, validating, mutating) = admission::webhook_manifests(&namespace, webhook_port()?);
+                [
+                    serde_yaml::to_string(&service)?,
+                    serde_yaml::to_string(&validating)?,
+                    serde_yaml::to_string(&mutating)?
+                ].join("---\n")
+            },
+            Some(other) => return Err(anyhow!("Unknown manifest {other}, expected webhook")),
+            None => serde_yaml::to_string(&admission::crd())?
+        };
+        print!("{manifests}");
+        return Ok(());
+    }
+
+    // Reproduce una grabación hecha con EDGE_CONTROLLER_RECORD_FILE.
+    if let Ok(path) = env::var("EDGE_CONTROLLER_REPLAY_FILE") {
+        let speed = env::var("EDGE_CONTROLLER_REPLAY_SPEED")
+            .map(|speed| speed.parse::<f64>())
+            .unwrap_or(Ok(1.0))?;
+        edge_service_lib::replay::<Policy>(Path::new(&path), speed).await?;
+        info!("Replay finished, graphs are still served until Ctrl-C.");
+        tokio::signal::ctrl_c().await?;
+        return Ok(());
+    }
+
+    // Sin Kubernetes: servicios y pods salen de un fichero de inventario.
+    if let Ok(path) = env::var("EDGE_CONTROLLER_INVENTORY") {
+        let endpoints_dir = env::var("EDGE_CONTROLLER_ENDPOINTS_DIR").ok().map(PathBuf::from);
+        let interval = env::var("EDGE_CONTROLLER_INVENTORY_INTERVAL_MS")
+            .map(|ms| ms.parse::<u64>())
+            .unwrap_or(Ok(DEFAULT_INVENTORY_INTERVAL_MS))?;
+        return edge_service_lib::run_local::<Policy>(PathBuf::from(path), endpoints_dir, Duration::from_millis(interval)).await;
+    }
+
     let cluster_config = Config::incluster();
     let client = match cluster_config {
         Ok(config) => {
@@ -32,7 +100,16 @@ async fn main() -> Result<()> {
     match client {
         
         Ok(client) => {
-            edge_service_lib::run::<POLICY>(client);
+            // Webhook de admisión, solo si hay certificado.
+            if let (Ok(cert), Ok(key)) = (env::var("EDGE_CONTROLLER_WEBHOOK_CERT"), env::var("EDGE_CONTROLLER_WEBHOOK_KEY")) {
+                let address = format!("0.0.0.0:{}", webhook_port()?);
+                tokio::spawn(async move {
+                    if let Err(e) = admission::serve::<Policy>(&address, Path::new(&cert), Path::new(&key)).await {
+                        error!("Admission webhook stopped: {e:#}");
+                    }
+                });
+            }
+            edge_service_lib::run::<Policy>(client);
             tokio::signal::ctrl_c().await.unwrap();
             Ok(())
         },
@@ -44,4 +121,9 @@ async fn main() -> Result<()> {
     }
 }
 
-
+fn webhook_port() -> Result<u16> {
+    let port = env::var("EDGE_CONTROLLER_WEBHOOK_PORT")
+        .map(|port| port.parse::<u16>())
+        .unwrap_or(Ok(DEFAULT_WEBHOOK_PORT))?;
+    Ok(port)
+}
@@ -0,0 +1,392 @@
+use std::collections::{BTreeMap, VecDeque};
+use std::time::Instant;
+
+use edge_service_lib::policy::*;
+use k8s_openapi::api::core::v1::Pod;
+use kube::ResourceExt;
+use serde::Deserialize;
+use uuid::Uuid;
+
+const HW_ANNOT: &str = "edgeservices.prueba.ucm.es/hw_info";
+const METRICS_ANNOT: &str = "edgeservices.prueba.ucm.es/triton_metrics";
+/// Peticiones por segundo que llegan de clientes a un pod de entrada.
+const DEMAND_ANNOT: &str = "edgeservices.prueba.ucm.es/demand";
+
+/// Estimated requests/s that a unit of hardware can serve.
+const REQS_PER_GPU_CORE: f64 = 0.01;
+const REQS_PER_GPU_GIB: f64 = 2.0;
+const REQS_PER_CPU_CORE: f64 = 0.5;
+
+/// Flows are computed in thousandths of request/s.
+const FLOW_SCALE: f64 = 1000.0;
+
+/// Builds the edges with a min-cost flow from the demand of each pod to
+/// the capacity of the pods in the service. Every hop costs the same, so
+/// demand is served locally when possible and otherwise forwarded once
+/// to a pod with spare capacity. The weight of each edge is the share
+/// of the requests of the pod that it should forward through it.
+#[derive(Debug, Default)]
+pub struct Capacity {
+    /// Last `total_inferences` seen for each pod, to estimate throughput.
+    observed: BTreeMap<Uuid, Observation>
+}
+
+#[derive(Clone, Copy, Debug)]
+struct Observation {
+    total: f64,
+    at: Instant,
+    throughput: Option<f64>
+}
+
+#[derive(Debug, Deserialize)]
+struct HwInfo {
+    #[serde(default)]
+    physical_cores: usize,
+    #[serde(default)]
+    gpus: Vec<GpuInfo>
+}
+
+#[derive(Debug, Deserialize)]
+struct GpuInfo {
+    memory: usize,
+    core_count: usize
+}
+
+#[derive(Debug, Default, Deserialize)]
+struct TritonMetrics {
+    total_inferences: Option<f64>,
+    pending_requests: Option<f64>
+}
+
+impl AsyncDefault for Capacity {
+    async fn default() -> Self {
+        Self { observed: BTreeMap::new() }
+    }
+}
+
+impl Policy for Capacity {
+
+    fn pod_added(&mut self, graph: &mut GraphWrapper, pods: &PodMap, pod: Uuid) -> Vec<Uuid> {
+        check_hw_info(graph, pods, pod);
+        self.observe(pods, pod);
+        self.rebalance(graph, pods)
+    }
+
+    fn pod_removed(&mut self, graph: &mut GraphWrapper, pods: &PodMap, pod: Uuid, _affected: &[Uuid]) -> Vec<Uuid> {
+        self.observed.remove(&pod);
+        self.rebalance(graph, pods)
+    }
+
+    fn pod_updated(&mut self, graph: &mut GraphWrapper, pods: &PodMap, pod: Uuid) -> Vec<Uuid> {
+        check_hw_info(graph, pods, pod);
+        self.observe(pods, pod);
+        self.rebalance(graph, pods)
+    }
+}
+
+impl Capacity {
+
+    /// Updates the throughput of `pod` from its `total_inferences`.
+    fn observe(&mut self, pods: &PodMap, pod: Uuid) {
+
+        let Some(total) = pods.get(&pod).and_then(triton_metrics).and_then(|metrics| metrics.total_inferences) else {
+            return;
+        };
+
+        let now = Instant::now();
+        let throughput = match self.observed.get(&pod) {
+            Some(previous) if total >= previous.total && now > previous.at => {
+                Some((total - previous.total) / (now - previous.at).as_secs_f64())
+            },
+            Some(previous) => previous.throughput,
+            None => None
+        };
+        self.observed.insert(pod, Observation { total, at: now, throughput });
+    }
+
+    /// Requests/s that `pod` can serve.
+    fn capacity(&self, uid: Uuid, pod: &Pod) -> f64 {
+
+        let hw_capacity = match hw_info(pod) {
+            Some(Ok(hw_info)) if !hw_info.gpus.is_empty() => hw_info.gpus.iter()
+                .map(|gpu| {
+                    let gib = gpu.memory as f64 / (1u64 << 30) as f64;
+                    (gpu.core_count as f64 * REQS_PER_GPU_CORE).min(gib * REQS_PER_GPU_GIB)
+                })
+                .sum(),
+            Some(Ok(hw_info)) => hw_info.physical_cores as f64 * REQS_PER_CPU_CORE,
+            Some(Err(_)) | None => 0.0
+        };
+
+        let metrics = triton_metrics(pod).unwrap_or_default();
+        match self.observed.get(&uid).and_then(|observation| observation.throughput) {
+            // Con peticiones encoladas, lo que sirve es su capacidad real.
+            Some(throughput) if metrics.pending_requests.is_some_and(|pending| pending > 0.0) => throughput,
+            Some(throughput) => hw_capacity.max(throughput),
+            None => hw_capacity
+        }
+    }
+
+    /// Recomputes every edge and weight. Returns the pods whose endpoints changed.
+    fn rebalance(&self, graph: &mut GraphWrapper, pods: &PodMap) -> Vec<Uuid> {
+
+        let nodes: Vec<Uuid> = graph.nodes().collect();
+        let capacities: Vec<f64> = nodes.iter()
+            .map(|uid| pods.get(uid).map_or(0.0, |pod| self.capacity(*uid, pod)))
+            .collect();
+        let demands: Vec<f64> = nodes.iter()
+            .map(|uid| pods.get(uid).and_then(demand).unwrap_or(0.0))
+            .collect();
+
+        // Red: fuente -> pod (demanda), pod -> pod (coste 1), pod -> sumidero (capacidad).
+        let n = nodes.len();
+        let (source, sink) = (n, n + 1);
+        let mut network = FlowNetwork::new(n + 2);
+        let mut local_arcs = Vec::with_capacity(n);
+        let mut forward_arcs = Vec::new();
+        for i in 0..n {
+            network.add_arc(source, i, scale(demands[i]), 0);
+            local_arcs.push(network.add_arc(i, sink, scale(capacities[i]), 0));
+            for j in (0..n).filter(|j| *j != i && !graph.is_blocked(nodes[i], nodes[*j])) {
+                forward_arcs.push((i, j, network.add_arc(i, j, i64::MAX / 4, 1)));
+            }
+        }
+
+        let served = network.min_cost_max_flow(source, sink);
+        let total_demand = demands.iter().map(|demand| scale(*demand)).sum::<i64>();
+        if served < total_demand {
+            graph.report_warning(None, "CapacityExceeded", format!(
+                "Demand of {:.2} req/s exceeds the capacity of the service, {:.2} req/s are not served",
+                total_demand as f64 / FLOW_SCALE,
+                (total_demand - served) as f64 / FLOW_SCALE
+            ));
+        }
+
+        let mut outgoing: Vec<BTreeMap<Uuid, i64>> = vec![BTreeMap::new(); n];
+        for (i, j, arc) in forward_arcs {
+            let flow = network.flow(arc);
+            if flow > 0 {
+                outgoing[i].insert(nodes[j], flow);
+            }
+        }
+
+        let mut affected = Vec::new();
+        for i in 0..n {
+            let node = nodes[i];
+            let targets: Vec<Uuid> = outgoing[i].keys().copied().collect();
+            let mut changed = graph.set_outgoing(node, &targets);
+
+            let local = network.flow(local_arcs[i]);
+            let total = (local + outgoing[i].values().sum::<i64>()) as f64;
+            let share = |flow: i64| (total > 0.0).then(|| flow as f64 / total);
+            for (target, flow) in outgoing[i].iter() {
+                changed |= graph.set_edge_weight(node, *target, share(*flow));
+            }
+            changed |= graph.set_edge_weight(node, node, share(local));
+
+            if changed {
+                affected.push(node);
+            }
+        }
+        affected
+    }
+}
+
+fn hw_info(pod: &Pod) -> Option<serde_json::Result<HwInfo>> {
+    pod.annotations().get(HW_ANNOT).map(|hw_info| serde_json::from_str(hw_info))
+}
+
+fn check_hw_info(graph: &mut GraphWrapper, pods: &PodMap, pod: Uuid) {
+    if let Some(Err(e)) = pods.get(&pod).and_then(hw_info) {
+        graph.report_warning(Some(pod), "HwInfoInvalid", format!("Failed to parse hw_info: {e}"));
+    }
+}
+
+fn triton_metrics(pod: &Pod) -> Option<TritonMetrics> {
+    serde_json::from_str(pod.annotations().get(METRICS_ANNOT)?).ok()
+}
+
+fn demand(pod: &Pod) -> Option<f64> {
+    pod.annotations().get(DEMAND_ANNOT)?.trim().parse().ok()
+}
+
+fn scale(value: f64) -> i64 {
+    (value.max(0.0) * FLOW_SCALE) as i64
+}
+
+/// Residual network for min-cost flow with successive shortest paths.
+struct FlowNetwork {
+    /// (to, residual capacity, cost) per arc. Arc `a ^ 1` is the reverse of `a`.
+    arcs: Vec<(usize, i64, i64)>,
+    adjacency: Vec<Vec<usize>>
+}
+
+impl FlowNetwork {
+
+    fn new(nodes: usize) -> Self {
+        Self { arcs: Vec::new(), adjacency: vec![Vec::new(); nodes] }
+    }
+
+    fn add_arc(&mut self, from: usize, to: usize, capacity: i64, cost: i64) -> usize {
+        let arc = self.arcs.len();
+        self.arcs.push((to, capacity, cost));
+        self.arcs.push((from, 0, -cost));
+        self.adjacency[from].push(arc);
+        self.adjacency[to].push(arc + 1);
+        arc
+    }
+
+    /// Flow sent through `arc`, the capacity left in its reverse.
+    fn flow(&self, arc: usize) -> i64 {
+        self.arcs[arc ^ 1].1
+    }
+
+    /// Sends as much flow as possible from `source` to `sink` at minimum
+    /// cost. Returns the flow sent.
+    fn min_cost_max_flow(&mut self, source: usize, sink: usize) -> i64 {
+
+        let mut total = 0;
+        loop {
+            // Bellman-Ford con cola (SPFA): hay costes negativos en los arcos inversos.
+            let n = self.adjacency.len();
+            let mut dist = vec![i64::MAX; n];
+            let mut via: Vec<Option<usize>> = vec![None; n];
+            let mut queued = vec![false; n];
+            let mut queue = VecDeque::from([source]);
+            dist[source] = 0;
+            while let Some(node) = queue.pop_front() {
+                queued[node] = false;
+                for &arc in self.adjacency[node].iter() {
+                    let (to, capacity, cost) = self.arcs[arc];
+                    if capacity > 0 && dist[node] + cost < dist[to] {
+                        dist[to] = dist[node] + cost;
+                        via[to] = Some(arc);
+                        if !queued[to] {
+                            queued[to] = true;
+                            queue.push_back(to);
+                        }
+                    }
+                }
+            }
+
+            if dist[sink] == i64::MAX {
+                return total;
+            }
+
+            let mut bottleneck = i64::MAX;
+            let mut node = sink;
+            while let Some(arc) = via[node] {
+                bottleneck = bottleneck.min(self.arcs[arc].1);
+                node = self.arcs[arc ^ 1].0;
+            }
+
+            let mut node = sink;
+            while let Some(arc) = via[node] {
+                self.arcs[arc].1 -= bottleneck;
+                self.arcs[arc ^ 1].1 += bottleneck;
+                node = self.arcs[arc ^ 1].0;
+            }
+            total += bottleneck;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::BTreeMap;
+    use edge_service_lib::simulator::{Simulator, TraceEvent};
+    use uuid::Uuid;
+    use super::*;
+
+    const POD_A: Uuid = Uuid::from_u128(0xa);
+    const POD_B: Uuid = Uuid::from_u128(0xb);
+
+    #[test]
+    fn flow_takes_cheapest_paths() {
+        // 0 -> 1 -> 3 cuesta 2, 0 -> 2 -> 3 cuesta 5 pero hace falta para el resto.
+        let mut network = FlowNetwork::new(4);
+        let cheap = network.add_arc(0, 1, 4, 1);
+        let expensive = network.add_arc(0, 2, 10, 4);
+        network.add_arc(1, 3, 10, 1);
+        network.add_arc(2, 3, 10, 1);
+
+        assert_eq!(network.min_cost_max_flow(0, 3), 14);
+        assert_eq!(network.flow(cheap), 4);
+        assert_eq!(network.flow(expensive), 10);
+    }
+
+    #[test]
+    fn flow_is_limited_by_capacity() {
+        // Demanda 5 en el nodo 1, que solo sirve 2 y reenvía al 2, que sirve 1.
+        let (source, sink) = (0, 3);
+        let mut network = FlowNetwork::new(4);
+        network.add_arc(source, 1, 5, 0);
+        let local = network.add_arc(1, sink, 2, 0);
+        let forward = network.add_arc(1, 2, i64::MAX / 4, 1);
+        network.add_arc(2, sink, 1, 0);
+
+        assert_eq!(network.min_cost_max_flow(source, sink), 3);
+        assert_eq!(network.flow(local), 2);
+        assert_eq!(network.flow(forward), 1);
+    }
+
+    fn pod(uid: Uuid, cores: usize, demand: f64) -> TraceEvent {
+        TraceEvent {
+            timestamp_ms: None,
+            uid,
+            name: format!("pod-{uid}"),
+            ip: Some(format!("10.0.0.{}", uid.as_u128())),
+            port: None,
+            ready: true,
+            node_name: None,
+            labels: BTreeMap::new(),
+            annotations: BTreeMap::from([
+                (HW_ANNOT.to_string(), format!("{{\"physical_cores\": {cores}}}")),
+                (DEMAND_ANNOT.to_string(), demand.to_string())
+            ])
+        }
+    }
+
+    fn weights(simulator: &Simulator<Capacity>, pod: Uuid) -> BTreeMap<Uuid, Option<f64>> {
+        simulator.endpoints(pod).unwrap().into_iter()
+            .map(|neighbor| (neighbor.uuid, neighbor.weight))
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn rebalance_forwards_excess_demand() {
+        let mut simulator = Simulator::<Capacity>::from_default().await;
+
+        // pod-a sirve 1 req/s (2 cores) y recibe 3.
+        let step = simulator.step(&pod(POD_A, 2, 3.0)).unwrap();
+        assert!(step.edges.is_empty());
+        assert_eq!(step.warnings.len(), 1);
+        assert_eq!(step.warnings[0].reason, "CapacityExceeded");
+        assert_eq!(weights(&simulator, POD_A), BTreeMap::from([(POD_A, Some(1.0))]));
+
+        // pod-b sirve 4 req/s sin demanda propia, recibe lo que le falta a pod-a.
+        let step = simulator.step(&pod(POD_B, 8, 0.0)).unwrap();
+        assert!(step.warnings.is_empty());
+        assert_eq!(step.edges.len(), 1);
+        assert_eq!((step.edges[0].from, step.edges[0].to), (POD_A, POD_B));
+        assert!(step.notified.contains_key(&POD_A));
+
+        let a = weights(&simulator, POD_A);
+        assert!((a[&POD_A].unwrap() - 1.0 / 3.0).abs() < 1e-3);
+        assert!((a[&POD_B].unwrap() - 2.0 / 3.0).abs() < 1e-3);
+        // Lo que le llega a pod-b lo procesa entero.
+        assert_eq!(weights(&simulator, POD_B), BTreeMap::from([(POD_B, Some(1.0))]));
+    }
+
+    #[tokio::test]
+    async fn rebalance_serves_locally_with_spare_capacity() {
+        let mut simulator = Simulator::<Capacity>::from_default().await;
+        simulator.step(&pod(POD_A, 8, 1.0)).unwrap();
+        let step = simulator.step(&pod(POD_B, 8, 1.0)).unwrap();
+
+        assert!(step.edges.is_empty());
+        assert!(step.warnings.is_empty());
+        assert_eq!(weights(&simulator, POD_A), BTreeMap::from([(POD_A, Some(1.0))]));
+        assert_eq!(weights(&simulator, POD_B), BTreeMap::from([(POD_B, Some(1.0))]));
+    }
+}
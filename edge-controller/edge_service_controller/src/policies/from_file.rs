@@ -12,27 +12,39 @@ const GRAPH_FILE_PATH: &str = "./graph.json";
 #[derive(Debug)]
 pub struct FromFile {
     target_graph: DiGraphMap<Uuid, ()>,
+    /// Error al leer el fichero, pendiente de notificar.
+    parse_error: Option<String>
 }
 
 impl AsyncDefault for FromFile {
     
     async fn default() -> Self {
-        let path = Path::new(GRAPH_FILE_PATH);
-        if !path.exists() { log::error!("File ./graph.json not found."); }
-        else { log::info!("Found graph.json file."); }
+        Self::from_path(Path::new(GRAPH_FILE_PATH)).await
+    }
+}
+
+impl FromFile {
+
+    /// Lee el grafo objetivo de `path`. Si no se puede leer la política
+    /// queda sin aristas y avisa con el primer pod añadido.
+    pub async fn from_path(path: &Path) -> Self {
+        if !path.exists() { log::error!("File {} not found.", path.display()); }
+        else { log::info!("Found {} file.", path.display()); }
 
         let graph = parse_graph_file(path).await;
         match graph {
             Ok(g) => {
                 log::info!("Succesfully parsed graph file");
                 Self {
-                    target_graph: g
+                    target_graph: g,
+                    parse_error: None
                 }
             },
             Err(e) => {
                 log::error!("Failed to parse graph file: {e}");
                 Self {
-                    target_graph: DiGraphMap::new()
+                    target_graph: DiGraphMap::new(),
+                    parse_error: Some(format!("Failed to parse graph file {}: {e}", path.display()))
                 }
             }
         }
@@ -43,6 +55,10 @@ impl Policy for FromFile {
 
     fn pod_added(&mut self, graph: &mut GraphWrapper, pods: &PodMap, pod: Uuid) -> Vec<Uuid> {
         
+        if let Some(error) = self.parse_error.take() {
+            graph.report_warning(None, "GraphFileInvalid", error);
+        }
+
         if !self.target_graph.contains_node(pod) {
             log::warn!("The service does not contain pod {pod}");
             return Vec::new();
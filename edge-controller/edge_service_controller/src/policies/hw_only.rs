@@ -13,13 +13,17 @@ pub struct HwOnly {
 impl Policy for HwOnly {
     fn pod_added(&mut self, graph: &mut GraphWrapper, pods: &PodMap, pod: uuid::Uuid) -> Vec<uuid::Uuid> {
         log::info!("Pod added: {pod}");
-        if let Some(hw_info) = get_hw_info(pods, &pod) {
-            self.already_added.insert(pod);
-            let tmp = serde_json::to_string_pretty(&hw_info).unwrap();
-            log::info!("hw_info for pod {pod}:\n{tmp}");
-        }
-        else {
-            log::warn!("Pod {pod} missing hw_info.");
+        match get_hw_info(pods, &pod) {
+            Some(Ok(hw_info)) => {
+                self.already_added.insert(pod);
+                let tmp = serde_json::to_string_pretty(&hw_info).unwrap();
+                log::info!("hw_info for pod {pod}:\n{tmp}");
+            },
+            Some(Err(e)) => {
+                log::error!("Failed to parse hw_info for pod {pod}: {e}");
+                graph.report_warning(Some(pod), "HwInfoInvalid", format!("Failed to parse hw_info: {e}"));
+            },
+            None => log::warn!("Pod {pod} missing hw_info.")
         }
 
         Vec::new()
@@ -44,18 +48,13 @@ impl AsyncDefault for HwOnly {
     }
 }
 
+/// None si el pod no tiene la anotación.
 #[inline]
-fn get_hw_info(pods: &PodMap, pod: &Uuid) -> Option<JsonValue> {
+fn get_hw_info(pods: &PodMap, pod: &Uuid) -> Option<serde_json::Result<JsonValue>> {
     
     let hw_info = pods.get(pod)?
         .annotations()
         .get("edgeservices.prueba.ucm.es/hw_info")?;
 
-    match serde_json::from_str(hw_info.as_str()) {
-        Ok(hw_info) => Some(hw_info),
-        Err(e) => {
-            log::error!("Failed to parse hw_info for pod {pod}: {e}");
-            None
-        }
-    }
+    Some(serde_json::from_str(hw_info.as_str()))
 }
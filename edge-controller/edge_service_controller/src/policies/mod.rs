@@ -2,7 +2,16 @@
 mod noop;
 mod hw_only;
 mod from_file;
+mod capacity;
+mod script;
+mod rules;
 
 pub use noop::NoOp;
 pub use hw_only::HwOnly;
 pub use from_file::FromFile;
+pub use capacity::Capacity;
+pub use script::Scripted;
+pub use rules::Rules;
+
+#[cfg(test)]
+mod tests;
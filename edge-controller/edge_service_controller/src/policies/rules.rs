@@ -0,0 +1,272 @@
+use std::collections::BTreeMap;
+
+use anyhow::{anyhow, Result};
+use edge_service_lib::policy::*;
+use k8s_openapi::api::core::v1::Pod;
+use kube::ResourceExt;
+use uuid::Uuid;
+
+/// Builds the graph from the `rules` of the EdgeService, or runs the
+/// policy `P` if the service has no rules.
+///
+/// The rules are evaluated against every pod on each event, so the
+/// graph follows the labels of the pods and of their nodes.
+#[derive(Debug)]
+pub struct Rules<P: Policy> {
+    inner: P,
+    rules: Option<TopologyRules>,
+    /// The rules were removed and the graph still has their edges.
+    reset_pending: bool
+}
+
+impl<P: Policy> AsyncDefault for Rules<P> {
+    async fn default() -> Self {
+        Self {
+            inner: P::default().await,
+            rules: None,
+            reset_pending: false
+        }
+    }
+}
+
+impl<P: Policy> Policy for Rules<P> {
+
+    fn pod_added(&mut self, graph: &mut GraphWrapper, pods: &PodMap, pod: Uuid) -> Vec<Uuid> {
+        match &self.rules {
+            Some(rules) => rebuild(rules, graph, pods),
+            None if self.reset_pending => self.reset(graph, pods),
+            None => self.inner.pod_added(graph, pods, pod)
+        }
+    }
+
+    fn pod_removed(&mut self, graph: &mut GraphWrapper, pods: &PodMap, pod: Uuid, affected: &[Uuid]) -> Vec<Uuid> {
+        match &self.rules {
+            Some(rules) => rebuild(rules, graph, pods),
+            None if self.reset_pending => self.reset(graph, pods),
+            None => self.inner.pod_removed(graph, pods, pod, affected)
+        }
+    }
+
+    fn pod_updated(&mut self, graph: &mut GraphWrapper, pods: &PodMap, pod: Uuid) -> Vec<Uuid> {
+        match &self.rules {
+            Some(rules) => rebuild(rules, graph, pods),
+            None if self.reset_pending => self.reset(graph, pods),
+            None => self.inner.pod_updated(graph, pods, pod)
+        }
+    }
+
+    fn configure(&mut self, config: &PolicyConfig) -> Result<()> {
+        if let Some(rules) = &config.rules {
+            if config.script.is_some() {
+                return Err(anyhow!("script and rules cannot be used together"));
+            }
+            if rules.connect.is_empty() {
+                return Err(anyhow!("rules must have at least one connect rule"));
+            }
+            log::info!("Using topology rules for service {}", config.service_uid);
+        }
+        self.inner.configure(config)?;
+        // Las aristas de las reglas se sustituyen en el siguiente evento.
+        self.reset_pending = config.rules.is_none() && (self.reset_pending || self.rules.is_some());
+        self.rules = config.rules.clone();
+        Ok(())
+    }
+}
+
+impl<P: Policy> Rules<P> {
+
+    /// Replaces the edges built by the rules with the ones of the inner
+    /// policy, as if every pod had just been added. Pinned edges are kept.
+    fn reset(&mut self, graph: &mut GraphWrapper, pods: &PodMap) -> Vec<Uuid> {
+
+        self.reset_pending = false;
+        let nodes: Vec<Uuid> = graph.nodes().collect();
+        let mut affected: Vec<Uuid> = nodes.iter()
+            .copied()
+            .filter(|node| graph.set_outgoing(*node, &[]))
+            .collect();
+        for node in nodes {
+            affected.extend(self.inner.pod_added(graph, pods, node));
+        }
+        affected.sort();
+        affected.dedup();
+        affected
+    }
+}
+
+/// Recomputes the outgoing edges of every pod. Returns the pods whose
+/// endpoints changed.
+fn rebuild(rules: &TopologyRules, graph: &mut GraphWrapper, pods: &PodMap) -> Vec<Uuid> {
+
+    let nodes: Vec<Uuid> = graph.nodes().collect();
+    let zones: BTreeMap<Uuid, Option<String>> = nodes.iter()
+        .map(|uid| (*uid, pods.get(uid).and_then(|pod| zone(graph, pod))))
+        .collect();
+
+    let mut affected = Vec::new();
+    for from in nodes.iter().copied() {
+        let Some(from_pod) = pods.get(&from) else { continue };
+
+        let mut targets: Vec<Uuid> = nodes.iter()
+            .copied()
+            .filter(|to| *to != from && !graph.is_blocked(from, *to))
+            .filter(|to| pods.get(to).is_some_and(|to_pod| {
+                rules.connect.iter().any(|rule| connects(rule, graph, from_pod, to_pod, &zones[&from], &zones[to]))
+            }))
+            .collect();
+
+        if let Some(max) = rules.max_neighbours {
+            // Primero la misma zona, después los vecinos actuales para no mover el grafo sin motivo.
+            targets.sort_by_key(|to| (zones[to] != zones[&from], !graph.contains_edge(from, *to), *to));
+            targets.truncate(max as usize);
+        }
+
+        if graph.set_outgoing(from, &targets) {
+            affected.push(from);
+        }
+    }
+    affected
+}
+
+fn connects(rule: &ConnectRule, graph: &GraphWrapper, from: &Pod, to: &Pod, from_zone: &Option<String>, to_zone: &Option<String>) -> bool {
+    matches(&rule.from, graph, from)
+        && matches(&rule.to, graph, to)
+        && (!rule.same_zone || (from_zone.is_some() && from_zone == to_zone))
+}
+
+fn matches(selector: &PodMatch, graph: &GraphWrapper, pod: &Pod) -> bool {
+
+    let pod_labels = pod.labels();
+    let pod_matches = selector.pod_labels.iter().all(|(key, value)| pod_labels.get(key) == Some(value));
+    if !pod_matches {
+        return false;
+    }
+    if selector.node_labels.is_empty() {
+        return true;
+    }
+    graph.pod_node(pod).is_some_and(|node| {
+        selector.node_labels.iter().all(|(key, value)| node.labels.get(key) == Some(value))
+    })
+}
+
+fn zone(graph: &GraphWrapper, pod: &Pod) -> Option<String> {
+    graph.pod_node(pod)?.zone().map(str::to_string)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::BTreeMap;
+    use edge_service_lib::simulator::{Simulator, TraceEvent};
+    use uuid::Uuid;
+    use crate::policies::{NoOp, Scripted};
+    use super::*;
+
+    const ZONE_LABEL: &str = "topology.kubernetes.io/zone";
+    const POD_A: Uuid = Uuid::from_u128(0xa);
+    const POD_B: Uuid = Uuid::from_u128(0xb);
+    const POD_C: Uuid = Uuid::from_u128(0xc);
+
+    fn pod(uid: Uuid, node: &str, labels: &[(&str, &str)]) -> TraceEvent {
+        TraceEvent {
+            timestamp_ms: None,
+            uid,
+            name: format!("pod-{uid}"),
+            ip: Some(format!("10.0.0.{}", uid.as_u128())),
+            port: None,
+            ready: true,
+            node_name: Some(node.to_string()),
+            labels: labels.iter().map(|(key, value)| (key.to_string(), value.to_string())).collect(),
+            annotations: BTreeMap::new()
+        }
+    }
+
+    fn rules(connect: Vec<ConnectRule>, max_neighbours: Option<u32>) -> PolicyConfig {
+        PolicyConfig { rules: Some(TopologyRules { connect, max_neighbours }), ..Default::default() }
+    }
+
+    fn labels(labels: &[(&str, &str)]) -> BTreeMap<String, String> {
+        labels.iter().map(|(key, value)| (key.to_string(), value.to_string())).collect()
+    }
+
+    fn edges<T: Policy>(simulator: &Simulator<T>) -> Vec<(Uuid, Uuid)> {
+        let mut edges: Vec<(Uuid, Uuid)> = simulator.graph().all_edges().map(|(from, to, _)| (from, to)).collect();
+        edges.sort();
+        edges
+    }
+
+    /// pod-a y pod-b en la zona z1, pod-c en la z2.
+    async fn zoned_simulator(config: PolicyConfig) -> Simulator<Rules<NoOp>> {
+        let mut simulator = Simulator::<Rules<NoOp>>::from_default().await;
+        simulator.configure(&config).unwrap();
+        simulator.set_nodes(BTreeMap::from([
+            ("node-1".to_string(), NodeInfo { labels: labels(&[(ZONE_LABEL, "z1"), ("gpu", "true")]), maintenance: false }),
+            ("node-2".to_string(), NodeInfo { labels: labels(&[(ZONE_LABEL, "z2")]), maintenance: false })
+        ]));
+        simulator
+    }
+
+    #[tokio::test]
+    async fn connects_matching_labels() {
+        let rule = ConnectRule {
+            from: PodMatch { pod_labels: labels(&[("role", "edge")]), ..Default::default() },
+            to: PodMatch { pod_labels: labels(&[("role", "cloud")]), node_labels: labels(&[("gpu", "true")]) },
+            same_zone: false
+        };
+        let mut simulator = zoned_simulator(rules(vec![rule], None)).await;
+        simulator.step(&pod(POD_A, "node-1", &[("role", "edge")])).unwrap();
+        simulator.step(&pod(POD_B, "node-1", &[("role", "cloud")])).unwrap();
+        // pod-c es cloud pero su nodo no tiene GPU.
+        let step = simulator.step(&pod(POD_C, "node-2", &[("role", "cloud")])).unwrap();
+
+        assert_eq!(edges(&simulator), [(POD_A, POD_B)]);
+        assert!(step.notified.keys().all(|pod| *pod == POD_C));
+    }
+
+    #[tokio::test]
+    async fn same_zone_only() {
+        let rule = ConnectRule { same_zone: true, ..Default::default() };
+        let mut simulator = zoned_simulator(rules(vec![rule], None)).await;
+        simulator.step(&pod(POD_A, "node-1", &[])).unwrap();
+        simulator.step(&pod(POD_B, "node-1", &[])).unwrap();
+        simulator.step(&pod(POD_C, "node-2", &[])).unwrap();
+
+        assert_eq!(edges(&simulator), [(POD_A, POD_B), (POD_B, POD_A)]);
+    }
+
+    #[tokio::test]
+    async fn max_neighbours_prefers_same_zone() {
+        let mut simulator = zoned_simulator(rules(vec![ConnectRule::default()], Some(1))).await;
+        simulator.step(&pod(POD_C, "node-2", &[])).unwrap();
+        simulator.step(&pod(POD_B, "node-1", &[])).unwrap();
+        simulator.step(&pod(POD_A, "node-1", &[])).unwrap();
+
+        // pod-c no tiene a nadie en su zona y se queda con el vecino que ya tenía.
+        assert_eq!(edges(&simulator), [(POD_A, POD_B), (POD_B, POD_A), (POD_C, POD_B)]);
+    }
+
+    #[tokio::test]
+    async fn failed_configure_keeps_rules() {
+        let mut simulator = Simulator::<Rules<Scripted<NoOp>>>::from_default().await;
+        simulator.configure(&rules(vec![ConnectRule::default()], None)).unwrap();
+        simulator.step(&pod(POD_A, "node-1", &[])).unwrap();
+
+        // El script no es válido, la configuración anterior sigue entera.
+        let invalid = PolicyConfig { script: Some("../script.rhai".to_string()), ..Default::default() };
+        assert!(simulator.configure(&invalid).is_err());
+        simulator.step(&pod(POD_B, "node-1", &[])).unwrap();
+        assert_eq!(edges(&simulator), [(POD_A, POD_B), (POD_B, POD_A)]);
+    }
+
+    #[tokio::test]
+    async fn removing_rules_clears_their_edges() {
+        let mut simulator = zoned_simulator(rules(vec![ConnectRule::default()], None)).await;
+        simulator.step(&pod(POD_A, "node-1", &[])).unwrap();
+        simulator.step(&pod(POD_B, "node-1", &[])).unwrap();
+        assert_eq!(edges(&simulator), [(POD_A, POD_B), (POD_B, POD_A)]);
+
+        simulator.configure(&PolicyConfig::default()).unwrap();
+        let step = simulator.step(&pod(POD_C, "node-2", &[])).unwrap();
+        assert!(edges(&simulator).is_empty());
+        assert!(step.notified.contains_key(&POD_A) && step.notified.contains_key(&POD_B));
+    }
+}
@@ -0,0 +1,470 @@
+use std::collections::BTreeSet;
+use std::env;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+
+use anyhow::{anyhow, Context, Result};
+use edge_service_lib::policy::*;
+use k8s_openapi::api::core::v1::Pod;
+use kube::ResourceExt;
+use petgraph::Direction;
+use rhai::{Array, CallFnOptions, Dynamic, Engine, EvalAltResult, Map, Scope, AST};
+use serde_json::Value as JsonValue;
+use uuid::Uuid;
+
+/// Directorio del que se leen los scripts nombrados en los EdgeService.
+const DEFAULT_SCRIPTS_DIR: &str = "./scripts";
+const DEFAULT_MAX_OPERATIONS: u64 = 1_000_000;
+const HW_ANNOT: &str = "edgeservices.prueba.ucm.es/hw_info";
+
+/// Runs the Rhai script named in the `script` field of the EdgeService,
+/// or the policy `P` if the service has no script.
+///
+/// The script may define any of
+/// `pod_added(graph, pods, pod)`, `pod_removed(graph, pods, pod, affected)`
+/// and `pod_updated(graph, pods, pod)`, returning an array with the
+/// pods whose endpoints changed. Pods are identified by their UID string.
+#[derive(Debug)]
+pub struct Scripted<P: Policy> {
+    inner: P,
+    script: Option<Script>
+}
+
+#[derive(Debug)]
+struct Script {
+    name: String,
+    engine: Engine,
+    ast: AST
+}
+
+impl<P: Policy> AsyncDefault for Scripted<P> {
+    async fn default() -> Self {
+        Self {
+            inner: P::default().await,
+            script: None
+        }
+    }
+}
+
+impl<P: Policy> Policy for Scripted<P> {
+
+    fn pod_added(&mut self, graph: &mut GraphWrapper, pods: &PodMap, pod: Uuid) -> Vec<Uuid> {
+        match &self.script {
+            Some(script) => script.run(graph, pods, pod, "pod_added", vec![]),
+            None => self.inner.pod_added(graph, pods, pod)
+        }
+    }
+
+    fn pod_removed(&mut self, graph: &mut GraphWrapper, pods: &PodMap, pod: Uuid, affected: &[Uuid]) -> Vec<Uuid> {
+        match &self.script {
+            Some(script) => script.run(graph, pods, pod, "pod_removed", vec![uuid_array(affected.iter().copied()).into()]),
+            None => self.inner.pod_removed(graph, pods, pod, affected)
+        }
+    }
+
+    fn pod_updated(&mut self, graph: &mut GraphWrapper, pods: &PodMap, pod: Uuid) -> Vec<Uuid> {
+        match &self.script {
+            Some(script) => script.run(graph, pods, pod, "pod_updated", vec![]),
+            None => self.inner.pod_updated(graph, pods, pod)
+        }
+    }
+
+    fn configure(&mut self, config: &PolicyConfig) -> Result<()> {
+        match &config.script {
+            Some(name) => {
+                self.script = Some(Script::load(name)?);
+                log::info!("Using script {name} for service {}", config.service_uid);
+            },
+            None => self.script = None
+        }
+        self.inner.configure(config)
+    }
+}
+
+impl Script {
+
+    fn load(name: &str) -> Result<Self> {
+
+        // Solo se aceptan nombres de fichero, no rutas.
+        if Path::new(name).file_name().and_then(|file| file.to_str()) != Some(name) {
+            return Err(anyhow!("Invalid script name {name}"));
+        }
+
+        let dir = env::var("EDGE_CONTROLLER_SCRIPTS_DIR").unwrap_or(DEFAULT_SCRIPTS_DIR.to_string());
+        let path = PathBuf::from(dir).join(name);
+        let source = std::fs::read_to_string(&path)
+            .with_context(|| format!("Failed to read script {}", path.display()))?;
+        Self::compile(name, &source)
+    }
+
+    fn compile(name: &str, source: &str) -> Result<Self> {
+
+        let engine = sandboxed_engine();
+        let ast = engine.compile(source)
+            .map_err(|e| anyhow!("Failed to compile script {name}: {e}"))?;
+
+        Ok(Self { name: name.to_string(), engine, ast })
+    }
+
+    /// Calls `function` on a copy of the graph. The changes are applied to
+    /// `graph` only if the script ends without errors.
+    fn run(&self, graph: &mut GraphWrapper, pods: &PodMap, pod: Uuid, function: &str, extra: Vec<Dynamic>) -> Vec<Uuid> {
+
+        let arity = 3 + extra.len();
+        if !self.ast.iter_functions().any(|f| f.name == function && f.params.len() == arity) {
+            return Vec::new();
+        }
+
+        let view = ScriptGraph::new(graph);
+        let mut args = vec![Dynamic::from(view.clone()), Dynamic::from(pods_map(pods)), Dynamic::from(pod.to_string())];
+        args.extend(extra);
+
+        let options = CallFnOptions::new().eval_ast(false);
+        let result = self.engine.call_fn_with_options::<Dynamic>(options, &mut Scope::new(), &self.ast, function, args);
+        let returned = match result.map(parse_uuid_array) {
+            Ok(Ok(returned)) => returned,
+            Ok(Err(e)) | Err(e) => {
+                log::error!("Script {} failed in {function}: {e}", self.name);
+                graph.report_warning(Some(pod), "ScriptError", format!("Script {} failed in {function}: {e}", self.name));
+                return Vec::new();
+            }
+        };
+
+        let state = view.0.lock().unwrap();
+        let mut affected = Vec::new();
+        for op in state.ops.iter() {
+            match *op {
+                EdgeOp::Add(from, to) => graph.add_edge(from, to),
+                EdgeOp::Remove(from, to) => { graph.remove_edge(from, to); }
+            }
+            affected.push(op.source());
+        }
+        for (reason, message) in state.warnings.iter() {
+            graph.report_warning(Some(pod), reason, message.clone());
+        }
+
+        affected.extend(returned);
+        let mut seen = BTreeSet::new();
+        affected.retain(|uid| seen.insert(*uid));
+        affected
+    }
+}
+
+fn sandboxed_engine() -> Engine {
+
+    let max_operations = env::var("EDGE_CONTROLLER_SCRIPT_MAX_OPERATIONS")
+        .ok()
+        .and_then(|max| max.parse().ok())
+        .unwrap_or(DEFAULT_MAX_OPERATIONS);
+
+    let mut engine = Engine::new();
+    engine.set_max_operations(max_operations)
+        .set_max_call_levels(32)
+        .set_max_expr_depths(64, 32)
+        .set_max_string_size(64 * 1024)
+        .set_max_array_size(10_000)
+        .set_max_map_size(10_000)
+        .disable_symbol("eval")
+        .on_print(|text| log::info!("[script] {text}"))
+        .on_debug(|text, _, pos| log::debug!("[script {pos}] {text}"));
+
+    engine.register_type_with_name::<ScriptGraph>("Graph")
+        .register_fn("node_count", |graph: &mut ScriptGraph| graph.0.lock().unwrap().nodes.len() as i64)
+        .register_fn("nodes", ScriptGraph::nodes)
+        .register_fn("contains_node", ScriptGraph::contains_node)
+        .register_fn("contains_edge", ScriptGraph::contains_edge)
+        .register_fn("outgoing", |graph: &mut ScriptGraph, node: &str| graph.neighbors(node, Direction::Outgoing))
+        .register_fn("incoming", |graph: &mut ScriptGraph, node: &str| graph.neighbors(node, Direction::Incoming))
+        .register_fn("add_edge", ScriptGraph::add_edge)
+        .register_fn("remove_edge", ScriptGraph::remove_edge)
+        .register_fn("warn", |graph: &mut ScriptGraph, message: &str| graph.warn("ScriptWarning", message))
+        .register_fn("warn", ScriptGraph::warn);
+    engine
+}
+
+#[derive(Clone, Copy, Debug)]
+enum EdgeOp {
+    Add(Uuid, Uuid),
+    Remove(Uuid, Uuid)
+}
+
+impl EdgeOp {
+    fn source(&self) -> Uuid {
+        match self {
+            EdgeOp::Add(from, _) | EdgeOp::Remove(from, _) => *from
+        }
+    }
+}
+
+#[derive(Debug, Default)]
+struct GraphState {
+    nodes: BTreeSet<Uuid>,
+    edges: BTreeSet<(Uuid, Uuid)>,
+    pinned: BTreeSet<(Uuid, Uuid)>,
+    blocked: BTreeSet<(Uuid, Uuid)>,
+    /// Cambios hechos por el script, en orden.
+    ops: Vec<EdgeOp>,
+    warnings: Vec<(String, String)>
+}
+
+/// Copy of the graph handed to scripts. It follows the same rules as
+/// GraphWrapper for pinned and blocked edges.
+#[derive(Clone, Debug)]
+struct ScriptGraph(Arc<Mutex<GraphState>>);
+
+type ScriptResult<T> = Result<T, Box<EvalAltResult>>;
+
+impl ScriptGraph {
+
+    fn new(graph: &GraphWrapper) -> Self {
+
+        let nodes: BTreeSet<Uuid> = graph.nodes().collect();
+        let mut state = GraphState { nodes, ..Default::default() };
+        for &from in state.nodes.iter() {
+            for &to in state.nodes.iter() {
+                if graph.contains_edge(from, to) { state.edges.insert((from, to)); }
+                if graph.is_pinned(from, to) { state.pinned.insert((from, to)); }
+                if graph.is_blocked(from, to) { state.blocked.insert((from, to)); }
+            }
+        }
+        Self(Arc::new(Mutex::new(state)))
+    }
+
+    fn nodes(&mut self) -> Array {
+        uuid_array(self.0.lock().unwrap().nodes.iter().copied())
+    }
+
+    fn contains_node(&mut self, node: &str) -> ScriptResult<bool> {
+        let node = parse_uuid(node)?;
+        Ok(self.0.lock().unwrap().nodes.contains(&node))
+    }
+
+    fn contains_edge(&mut self, from: &str, to: &str) -> ScriptResult<bool> {
+        let edge = (parse_uuid(from)?, parse_uuid(to)?);
+        Ok(self.0.lock().unwrap().edges.contains(&edge))
+    }
+
+    fn neighbors(&mut self, node: &str, dir: Direction) -> ScriptResult<Array> {
+        let node = parse_uuid(node)?;
+        let state = self.0.lock().unwrap();
+        let neighbors = state.edges.iter()
+            .filter_map(|&(from, to)| match dir {
+                Direction::Outgoing if from == node => Some(to),
+                Direction::Incoming if to == node => Some(from),
+                _ => None
+            });
+        Ok(uuid_array(neighbors))
+    }
+
+    /// False if the edge already existed, is blocked or its pods are not in the graph.
+    fn add_edge(&mut self, from: &str, to: &str) -> ScriptResult<bool> {
+        let (from, to) = (parse_uuid(from)?, parse_uuid(to)?);
+        let mut state = self.0.lock().unwrap();
+        if !state.nodes.contains(&from) || !state.nodes.contains(&to) || state.blocked.contains(&(from, to)) {
+            return Ok(false);
+        }
+
+        let added = state.edges.insert((from, to));
+        if added { state.ops.push(EdgeOp::Add(from, to)); }
+        Ok(added)
+    }
+
+    /// False if the edge did not exist or is pinned.
+    fn remove_edge(&mut self, from: &str, to: &str) -> ScriptResult<bool> {
+        let (from, to) = (parse_uuid(from)?, parse_uuid(to)?);
+        let mut state = self.0.lock().unwrap();
+        if state.pinned.contains(&(from, to)) {
+            return Ok(false);
+        }
+
+        let removed = state.edges.remove(&(from, to));
+        if removed { state.ops.push(EdgeOp::Remove(from, to)); }
+        Ok(removed)
+    }
+
+    fn warn(&mut self, reason: &str, message: &str) {
+        self.0.lock().unwrap().warnings.push((reason.to_string(), message.to_string()));
+    }
+}
+
+/// Pods as a map from UID to `#{ name, ip, node, labels, annotations, hw_info }`.
+fn pods_map(pods: &PodMap) -> Map {
+    pods.iter()
+        .map(|(uid, pod)| (uid.to_string().into(), Dynamic::from(pod_map(pod))))
+        .collect()
+}
+
+fn pod_map(pod: &Pod) -> Map {
+
+    let strings = |map: &std::collections::BTreeMap<String, String>| -> Map {
+        map.iter()
+            .map(|(key, value)| (key.into(), Dynamic::from(value.clone())))
+            .collect()
+    };
+    let optional = |value: Option<String>| value.map(Dynamic::from).unwrap_or(Dynamic::UNIT);
+
+    let hw_info = pod.annotations()
+        .get(HW_ANNOT)
+        .and_then(|hw_info| serde_json::from_str::<JsonValue>(hw_info).ok())
+        .and_then(|hw_info| rhai::serde::to_dynamic(hw_info).ok())
+        .unwrap_or(Dynamic::UNIT);
+
+    let mut map = Map::new();
+    map.insert("name".into(), Dynamic::from(pod.name_any()));
+    map.insert("ip".into(), optional(pod.status.as_ref().and_then(|status| status.pod_ip.clone())));
+    map.insert("node".into(), optional(pod.spec.as_ref().and_then(|spec| spec.node_name.clone())));
+    map.insert("labels".into(), Dynamic::from(strings(pod.labels())));
+    map.insert("annotations".into(), Dynamic::from(strings(pod.annotations())));
+    map.insert("hw_info".into(), hw_info);
+    map
+}
+
+fn uuid_array(uuids: impl Iterator<Item = Uuid>) -> Array {
+    uuids.map(|uid| Dynamic::from(uid.to_string())).collect()
+}
+
+fn parse_uuid(text: &str) -> ScriptResult<Uuid> {
+    Uuid::parse_str(text).map_err(|e| format!("Invalid pod UID {text}: {e}").into())
+}
+
+/// The value returned by a script: an array of pod UIDs, or nothing.
+fn parse_uuid_array(value: Dynamic) -> ScriptResult<Vec<Uuid>> {
+
+    if value.is_unit() {
+        return Ok(Vec::new());
+    }
+
+    let array = value.try_cast::<Array>()
+        .ok_or_else(|| Box::<EvalAltResult>::from("Script must return an array of pod UIDs"))?;
+    array.into_iter()
+        .map(|uid| match uid.into_string() {
+            Ok(text) => parse_uuid(&text),
+            Err(kind) => Err(format!("Expected a pod UID, found {kind}").into())
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::BTreeMap;
+    use edge_service_lib::simulator::{Simulator, TraceEvent};
+    use uuid::Uuid;
+    use crate::policies::NoOp;
+    use super::*;
+
+    const POD_A: Uuid = Uuid::from_u128(0xa);
+    const POD_B: Uuid = Uuid::from_u128(0xb);
+    const POD_C: Uuid = Uuid::from_u128(0xc);
+
+    fn scripted(source: &str) -> Simulator<Scripted<NoOp>> {
+        let script = Script::compile("test.rhai", source).unwrap();
+        Simulator::new(Scripted { inner: NoOp(), script: Some(script) })
+    }
+
+    fn pod(uid: Uuid) -> TraceEvent {
+        TraceEvent {
+            timestamp_ms: None,
+            uid,
+            name: format!("pod-{uid}"),
+            ip: Some(format!("10.0.0.{}", uid.as_u128())),
+            port: None,
+            ready: true,
+            node_name: None,
+            labels: BTreeMap::new(),
+            annotations: BTreeMap::new()
+        }
+    }
+
+    fn edges<T: Policy>(simulator: &Simulator<T>) -> Vec<(Uuid, Uuid)> {
+        let mut edges: Vec<(Uuid, Uuid)> = simulator.graph().all_edges().map(|(from, to, _)| (from, to)).collect();
+        edges.sort();
+        edges
+    }
+
+    #[test]
+    fn replays_script_ops() {
+        // Cada pod nuevo se conecta con todos y el primero deja de apuntar al segundo.
+        let mut simulator = scripted(r#"
+            fn pod_added(graph, pods, pod) {
+                for other in graph.nodes() {
+                    if other != pod {
+                        graph.add_edge(pod, other);
+                        graph.add_edge(other, pod);
+                    }
+                }
+                if graph.node_count() == 3 {
+                    let first = "00000000-0000-0000-0000-00000000000a";
+                    graph.remove_edge(first, "00000000-0000-0000-0000-00000000000b");
+                    graph.warn("Pruned", `removed ${first}`);
+                }
+            }
+        "#);
+        simulator.step(&pod(POD_A)).unwrap();
+        simulator.step(&pod(POD_B)).unwrap();
+        assert_eq!(edges(&simulator), [(POD_A, POD_B), (POD_B, POD_A)]);
+
+        let step = simulator.step(&pod(POD_C)).unwrap();
+        assert_eq!(edges(&simulator), [(POD_A, POD_C), (POD_B, POD_A), (POD_B, POD_C), (POD_C, POD_A), (POD_C, POD_B)]);
+        assert!([POD_A, POD_B, POD_C].iter().all(|pod| step.notified.contains_key(pod)));
+        assert_eq!(step.warnings.len(), 1);
+        assert_eq!(step.warnings[0].reason, "Pruned");
+    }
+
+    #[test]
+    fn runtime_error_discards_changes() {
+        let mut simulator = scripted(r#"
+            fn pod_added(graph, pods, pod) {
+                for other in graph.nodes() {
+                    if other != pod { graph.add_edge(pod, other); }
+                }
+                if graph.node_count() > 1 { throw "boom"; }
+            }
+        "#);
+        simulator.step(&pod(POD_A)).unwrap();
+        let step = simulator.step(&pod(POD_B)).unwrap();
+
+        assert!(edges(&simulator).is_empty());
+        assert_eq!(step.warnings.len(), 1);
+        assert_eq!(step.warnings[0].reason, "ScriptError");
+        assert!(step.warnings[0].message.contains("boom"));
+    }
+
+    #[test]
+    fn invalid_return_is_an_error() {
+        let mut simulator = scripted(r#"fn pod_added(graph, pods, pod) { 42 }"#);
+        let step = simulator.step(&pod(POD_A)).unwrap();
+        assert_eq!(step.warnings[0].reason, "ScriptError");
+    }
+
+    #[test]
+    fn engine_limits_stop_scripts() {
+        let mut simulator = scripted(r#"
+            fn pod_added(graph, pods, pod) {
+                graph.add_edge(pod, pod);
+                loop { }
+            }
+        "#);
+        let step = simulator.step(&pod(POD_A)).unwrap();
+        assert!(edges(&simulator).is_empty());
+        assert_eq!(step.warnings[0].reason, "ScriptError");
+
+        // Recursión sin fin: límite de llamadas.
+        let mut simulator = scripted(r#"
+            fn deeper(n) { deeper(n + 1) }
+            fn pod_added(graph, pods, pod) { deeper(0) }
+        "#);
+        let step = simulator.step(&pod(POD_A)).unwrap();
+        assert_eq!(step.warnings[0].reason, "ScriptError");
+
+        // eval está deshabilitado.
+        assert!(Script::compile("eval.rhai", r#"fn pod_added(graph, pods, pod) { eval("1") }"#).is_err());
+    }
+
+    #[test]
+    fn only_bare_file_names() {
+        for name in ["../policy.rhai", "scripts/policy.rhai", "/etc/policy.rhai", ""] {
+            let error = Script::load(name).unwrap_err();
+            assert!(error.to_string().contains("Invalid script name"), "{name}: {error}");
+        }
+    }
+}
@@ -0,0 +1,103 @@
+//! Ejecuta las políticas sobre la traza de `tests/fixtures` con el simulador.
+
+use std::collections::BTreeMap;
+use std::io::BufRead;
+use std::path::Path;
+use edge_service_lib::policy::Policy;
+use edge_service_lib::simulator::{Edge, PodEventKind, SimulationStep, Simulator, TraceEvent};
+use uuid::Uuid;
+use super::{FromFile, HwOnly};
+
+const FIXTURES_DIR: &str = concat!(env!("CARGO_MANIFEST_DIR"), "/tests/fixtures");
+
+const POD_A: Uuid = Uuid::from_u128(0xa);
+const POD_B: Uuid = Uuid::from_u128(0xb);
+const POD_C: Uuid = Uuid::from_u128(0xc);
+
+fn run_trace<T: Policy>(mut simulator: Simulator<T>) -> Vec<SimulationStep> {
+    let trace = std::fs::read(Path::new(FIXTURES_DIR).join("trace.jsonl")).unwrap();
+    trace.lines()
+        .map(|line| serde_json::from_str::<TraceEvent>(&line.unwrap()).unwrap())
+        .map(|event| simulator.step(&event).unwrap())
+        .collect()
+}
+
+fn edges(step: &SimulationStep) -> Vec<(Uuid, Uuid)> {
+    let mut edges: Vec<(Uuid, Uuid)> = step.edges.iter().map(|Edge { from, to }| (*from, *to)).collect();
+    edges.sort();
+    edges
+}
+
+/// Vecinos publicados a cada pod notificado, incluido el propio pod.
+fn notified(step: &SimulationStep) -> BTreeMap<Uuid, Vec<Uuid>> {
+    step.notified.iter()
+        .map(|(pod, neighbors)| {
+            let mut uids: Vec<Uuid> = neighbors.iter().map(|n| n.uuid).collect();
+            uids.sort();
+            (*pod, uids)
+        })
+        .collect()
+}
+
+#[tokio::test]
+async fn hw_only_trace() {
+    let steps = run_trace(Simulator::<HwOnly>::from_default().await);
+    assert_eq!(steps.len(), 4);
+
+    assert!(steps[..3].iter().all(|step| matches!(step.event, Some(PodEventKind::Added))));
+    assert!(matches!(steps[3].event, Some(PodEventKind::Removed)));
+
+    // HwOnly no añade aristas, solo se notifica al pod que llega.
+    assert!(steps.iter().all(|step| step.edges.is_empty()));
+    assert_eq!(notified(&steps[0]), BTreeMap::from([(POD_A, vec![POD_A])]));
+    assert_eq!(notified(&steps[1]), BTreeMap::from([(POD_B, vec![POD_B])]));
+    assert_eq!(notified(&steps[2]), BTreeMap::from([(POD_C, vec![POD_C])]));
+    assert!(notified(&steps[3]).is_empty());
+
+    // El hw_info de pod-c no es JSON válido.
+    assert!(steps[..2].iter().all(|step| step.warnings.is_empty()));
+    assert_eq!(steps[2].warnings.len(), 1);
+    assert_eq!(steps[2].warnings[0].pod, Some(POD_C));
+    assert_eq!(steps[2].warnings[0].reason, "HwInfoInvalid");
+}
+
+#[tokio::test]
+async fn from_file_trace() {
+    let policy = FromFile::from_path(&Path::new(FIXTURES_DIR).join("graph.json")).await;
+    let steps = run_trace(Simulator::new(policy));
+    assert_eq!(steps.len(), 4);
+
+    assert!(steps[0].edges.is_empty());
+    assert_eq!(notified(&steps[0]), BTreeMap::from([(POD_A, vec![POD_A])]));
+
+    assert_eq!(edges(&steps[1]), [(POD_A, POD_B)]);
+    assert_eq!(notified(&steps[1]), BTreeMap::from([(POD_A, vec![POD_A, POD_B]), (POD_B, vec![POD_B])]));
+
+    assert_eq!(edges(&steps[2]), [(POD_A, POD_B), (POD_A, POD_C), (POD_B, POD_C)]);
+    assert_eq!(notified(&steps[2]), BTreeMap::from([
+        (POD_A, vec![POD_A, POD_B, POD_C]),
+        (POD_B, vec![POD_B, POD_C]),
+        (POD_C, vec![POD_C])
+    ]));
+
+    // Al quitar pod-b solo se avisa a quien lo tenía como destino.
+    assert!(matches!(steps[3].event, Some(PodEventKind::Removed)));
+    assert_eq!(edges(&steps[3]), [(POD_A, POD_C)]);
+    assert_eq!(notified(&steps[3]), BTreeMap::from([(POD_A, vec![POD_A, POD_C])]));
+
+    let graph = &steps[3].graph;
+    assert!(graph.contains(&POD_A.to_string()) && graph.contains(&POD_C.to_string()));
+    assert!(!graph.contains(&POD_B.to_string()));
+    assert!(steps.iter().all(|step| step.warnings.is_empty()));
+}
+
+#[tokio::test]
+async fn from_file_missing_graph() {
+    let policy = FromFile::from_path(&Path::new(FIXTURES_DIR).join("missing.json")).await;
+    let steps = run_trace(Simulator::new(policy));
+
+    assert!(steps.iter().all(|step| step.edges.is_empty()));
+    assert_eq!(steps[0].warnings.len(), 1);
+    assert_eq!(steps[0].warnings[0].reason, "GraphFileInvalid");
+    assert!(steps[1..].iter().all(|step| step.warnings.is_empty()));
+}
@@ -0,0 +1,372 @@
+//! Validating and defaulting admission webhook for EdgeService resources,
+//! and the manifests that register it.
+
+use std::collections::BTreeMap;
+use std::convert::Infallible;
+use std::fs::File;
+use std::io::BufReader;
+use std::path::Path;
+use std::sync::Arc;
+use anyhow::{anyhow, Context, Result};
+use base64::Engine;
+use hyper::server::conn::Http;
+use hyper::service::service_fn;
+use hyper::{Body, Method, Request, Response, StatusCode};
+use json_patch::{AddOperation, PatchOperation, RemoveOperation};
+use k8s_openapi::api::admissionregistration::v1::{
+    MutatingWebhook, MutatingWebhookConfiguration, RuleWithOperations, ServiceReference,
+    ValidatingWebhook, ValidatingWebhookConfiguration
+};
+use k8s_openapi::api::core::v1::{Service, ServicePort, ServiceSpec};
+use k8s_openapi::apiextensions_apiserver::pkg::apis::apiextensions::v1::CustomResourceDefinition;
+use k8s_openapi::apimachinery::pkg::util::intstr::IntOrString;
+use kube::api::{DynamicObject, ObjectMeta};
+use kube::core::admission::{AdmissionRequest, AdmissionResponse, AdmissionReview};
+use kube::CustomResourceExt;
+use log::{error, info, warn};
+use serde_json::Value as JsonValue;
+use tokio::net::TcpListener;
+use tokio_rustls::rustls::{Certificate, PrivateKey, ServerConfig};
+use tokio_rustls::TlsAcceptor;
+use uuid::Uuid;
+use crate::controller::{EdgeNodeSpec, EdgeService};
+use crate::policy::{Policy, PolicyConfig};
+
+const VALIDATE_PATH: &str = "/validate";
+const MUTATE_PATH: &str = "/mutate";
+const WEBHOOK_NAME: &str = "edge-controller-webhook";
+/// Longitud máxima de un valor de label en Kubernetes.
+const MAX_LABEL_VALUE_LEN: usize = 63;
+
+/// The EdgeService CRD, generated from `EdgeNodeSpec`.
+pub fn crd() -> CustomResourceDefinition {
+    EdgeService::crd()
+}
+
+/// Service and webhook configurations for a controller running in
+/// `namespace` with the webhook on `port`. The CA bundle is injected by
+/// cert-manager from the certificate `<namespace>/edge-controller-webhook`.
+pub fn webhook_manifests(namespace: &str, port: u16) -> (Service, ValidatingWebhookConfiguration, MutatingWebhookConfiguration) {
+
+    let service = Service {
+        metadata: ObjectMeta {
+            name: Some(WEBHOOK_NAME.to_string()),
+            namespace: Some(namespace.to_string()),
+            ..Default::default()
+        },
+        spec: Some(ServiceSpec {
+            selector: Some(BTreeMap::from([("app".to_string(), "edge-controller".to_string())])),
+            ports: Some(vec![ServicePort {
+                port: 443,
+                target_port: Some(IntOrString::Int(port as i32)),
+                ..Default::default()
+            }]),
+            ..Default::default()
+        }),
+        ..Default::default()
+    };
+
+    let metadata = ObjectMeta {
+        name: Some(WEBHOOK_NAME.to_string()),
+        annotations: Some(BTreeMap::from([(
+            "cert-manager.io/inject-ca-from".to_string(),
+            format!("{namespace}/{WEBHOOK_NAME}")
+        )])),
+        ..Default::default()
+    };
+    let client_config = |path: &str| k8s_openapi::api::admissionregistration::v1::WebhookClientConfig {
+        service: Some(ServiceReference {
+            name: WEBHOOK_NAME.to_string(),
+            namespace: namespace.to_string(),
+            path: Some(path.to_string()),
+            port: Some(443)
+        }),
+        ..Default::default()
+    };
+    let rules = Some(vec![RuleWithOperations {
+        api_groups: Some(vec!["prueba.ucm.es".to_string()]),
+        api_versions: Some(vec!["v1".to_string()]),
+        operations: Some(vec!["CREATE".to_string(), "UPDATE".to_string()]),
+        resources: Some(vec!["edgeservices".to_string()]),
+        ..Default::default()
+    }]);
+
+    let validating = ValidatingWebhookConfiguration {
+        metadata: metadata.clone(),
+        webhooks: Some(vec![ValidatingWebhook {
+            name: "validate.edgeservices.prueba.ucm.es".to_string(),
+            admission_review_versions: vec!["v1".to_string()],
+            client_config: client_config(VALIDATE_PATH),
+            rules: rules.clone(),
+            side_effects: "None".to_string(),
+            failure_policy: Some("Fail".to_string()),
+            ..Default::default()
+        }])
+    };
+
+    let mutating = MutatingWebhookConfiguration {
+        metadata,
+        webhooks: Some(vec![MutatingWebhook {
+            name: "default.edgeservices.prueba.ucm.es".to_string(),
+            admission_review_versions: vec!["v1".to_string()],
+            client_config: client_config(MUTATE_PATH),
+            rules,
+            side_effects: "None".to_string(),
+            failure_policy: Some("Fail".to_string()),
+            ..Default::default()
+        }])
+    };
+
+    (service, validating, mutating)
+}
+
+/// Serves the webhook over HTTPS on `address` until an error occurs
+/// while listening.
+pub async fn serve<T: Policy + 'static>(address: &str, cert: &Path, key: &Path) -> Result<()> {
+
+    let acceptor = TlsAcceptor::from(Arc::new(tls_config(cert, key)?));
+    let listener = TcpListener::bind(address).await
+        .with_context(|| format!("Failed to listen on {address}"))?;
+    info!("Admission webhook listening on {address}");
+
+    loop {
+        let (stream, addr) = listener.accept().await?;
+        let acceptor = acceptor.clone();
+        tokio::spawn(async move {
+            let stream = match acceptor.accept(stream).await {
+                Ok(stream) => stream,
+                Err(e) => {
+                    warn!("TLS handshake with {addr} failed: {e}");
+                    return;
+                }
+            };
+            if let Err(e) = Http::new().serve_connection(stream, service_fn(handle::<T>)).await {
+                error!("Admission webhook connection failed: {e}");
+            }
+        });
+    }
+}
+
+fn tls_config(cert: &Path, key: &Path) -> Result<ServerConfig> {
+
+    let certs = rustls_pemfile::certs(&mut BufReader::new(File::open(cert)
+        .with_context(|| format!("Failed to open certificate {}", cert.display()))?))?
+        .into_iter()
+        .map(Certificate)
+        .collect();
+
+    let key = rustls_pemfile::read_all(&mut BufReader::new(File::open(key)
+        .with_context(|| format!("Failed to open key {}", key.display()))?))?
+        .into_iter()
+        .find_map(|item| match item {
+            rustls_pemfile::Item::RSAKey(key)
+            | rustls_pemfile::Item::PKCS8Key(key)
+            | rustls_pemfile::Item::ECKey(key) => Some(PrivateKey(key)),
+            _ => None
+        })
+        .context("No private key found")?;
+
+    let config = ServerConfig::builder()
+        .with_safe_defaults()
+        .with_no_client_auth()
+        .with_single_cert(certs, key)?;
+    Ok(config)
+}
+
+async fn handle<T: Policy>(request: Request<Body>) -> Result<Response<Body>, Infallible> {
+
+    let response = match (request.method(), request.uri().path()) {
+        (&Method::POST, VALIDATE_PATH) => review(request, validate::<T>).await,
+        (&Method::POST, MUTATE_PATH) => review(request, mutate).await,
+        _ => {
+            let mut response = Response::new(Body::empty());
+            *response.status_mut() = StatusCode::NOT_FOUND;
+            return Ok(response);
+        }
+    };
+
+    let review = response.unwrap_or_else(|e| {
+        error!("Invalid admission review: {e:#}");
+        AdmissionResponse::invalid(format!("{e:#}")).into_review()
+    });
+    let mut body = serde_json::to_value(&review).unwrap();
+    // El API server espera el parche en base64, no como lista de bytes.
+    if let Some(patch) = review.response.as_ref().and_then(|response| response.patch.as_ref()) {
+        body["response"]["patch"] = JsonValue::String(base64::engine::general_purpose::STANDARD.encode(patch));
+    }
+    let body = serde_json::to_vec(&body).unwrap();
+    let mut response = Response::new(Body::from(body));
+    response.headers_mut().insert("content-type", "application/json".parse().unwrap());
+    Ok(response)
+}
+
+/// Reads an AdmissionReview and answers it with `check`.
+async fn review<F, Fut>(request: Request<Body>, check: F) -> Result<AdmissionReview<DynamicObject>>
+where F: FnOnce(AdmissionRequest<DynamicObject>) -> Fut,
+      Fut: std::future::Future<Output = AdmissionResponse>
+{
+    let body = hyper::body::to_bytes(request.into_body()).await?;
+    let review: AdmissionReview<DynamicObject> = serde_json::from_slice(&body)?;
+    let request: AdmissionRequest<DynamicObject> = review.try_into()
+        .map_err(|_| anyhow!("Admission review without request"))?;
+    Ok(check(request).await.into_review())
+}
+
+/// Rejects specs that the controller would not be able to run.
+async fn validate<T: Policy>(request: AdmissionRequest<DynamicObject>) -> AdmissionResponse {
+
+    let response = AdmissionResponse::from(&request);
+    let Some(object) = &request.object else { return response };
+    match check_spec::<T>(object).await {
+        Ok(_) => response,
+        Err(e) => {
+            info!("Rejected EdgeService {}: {e:#}", request.name);
+            response.deny(format!("{e:#}"))
+        }
+    }
+}
+
+async fn check_spec<T: Policy>(object: &DynamicObject) -> Result<()> {
+
+    let spec: EdgeNodeSpec = serde_json::from_value(object.data.get("spec").cloned().unwrap_or_default())
+        .context("Invalid spec")?;
+
+    let selector = &spec.selector;
+    if selector.is_empty() {
+        return Err(anyhow!("spec.selector must not be empty"));
+    }
+    let valid_chars = selector.chars().all(|c| c.is_ascii_alphanumeric() || matches!(c, '-' | '_' | '.'));
+    let valid_ends = selector.starts_with(|c: char| c.is_ascii_alphanumeric())
+        && selector.ends_with(|c: char| c.is_ascii_alphanumeric());
+    if selector.len() > MAX_LABEL_VALUE_LEN || !valid_chars || !valid_ends {
+        return Err(anyhow!("spec.selector {selector:?} is not a valid label value"));
+    }
+    if spec.script.is_some() && spec.rules.is_some() {
+        return Err(anyhow!("spec.script and spec.rules cannot be used together"));
+    }
+
+    // La política se configura igual que lo hará el controlador.
+    let service_uid = object.metadata.uid.as_deref()
+        .and_then(|uid| Uuid::parse_str(uid).ok())
+        .unwrap_or_default();
+    let mut policy = T::default().await;
+    policy.configure(&PolicyConfig { service_uid, script: spec.script, rules: spec.rules })
+        .context("Invalid policy configuration")?;
+    Ok(())
+}
+
+/// Fills in the defaults of the spec.
+async fn mutate(request: AdmissionRequest<DynamicObject>) -> AdmissionResponse {
+
+    let response = AdmissionResponse::from(&request);
+    let Some(spec) = request.object.as_ref().and_then(|object| object.data.get("spec")) else { return response };
+
+    let mut patch = Vec::new();
+    if spec.get("dry_run").is_none_or(JsonValue::is_null) {
+        patch.push(PatchOperation::Add(AddOperation { path: "/spec/dry_run".to_string(), value: JsonValue::Bool(false) }));
+    }
+    // Un script vacío es lo mismo que no tener script.
+    if spec.get("script").and_then(JsonValue::as_str).is_some_and(|script| script.trim().is_empty()) {
+        patch.push(PatchOperation::Remove(RemoveOperation { path: "/spec/script".to_string() }));
+    }
+
+    if patch.is_empty() {
+        return response;
+    }
+    match response.with_patch(json_patch::Patch(patch)) {
+        Ok(response) => response,
+        Err(e) => AdmissionResponse::invalid(e)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+    use crate::policy::{AsyncDefault, GraphWrapper, PodMap};
+    use super::*;
+
+    #[derive(Debug)]
+    struct Accept;
+
+    impl AsyncDefault for Accept {
+        async fn default() -> Self { Accept }
+    }
+
+    impl Policy for Accept {
+        fn pod_added(&mut self, _graph: &mut GraphWrapper, _pods: &PodMap, _pod: Uuid) -> Vec<Uuid> { Vec::new() }
+        fn pod_removed(&mut self, _graph: &mut GraphWrapper, _pods: &PodMap, _pod: Uuid, _affected: &[Uuid]) -> Vec<Uuid> { Vec::new() }
+        fn pod_updated(&mut self, _graph: &mut GraphWrapper, _pods: &PodMap, _pod: Uuid) -> Vec<Uuid> { Vec::new() }
+    }
+
+    /// Request del API server para crear un EdgeService con `spec`.
+    fn request(spec: JsonValue) -> AdmissionRequest<DynamicObject> {
+        let review: AdmissionReview<DynamicObject> = serde_json::from_value(json!({
+            "apiVersion": "admission.k8s.io/v1",
+            "kind": "AdmissionReview",
+            "request": {
+                "uid": "705ab4f5-6393-11e8-b7cc-42010a800002",
+                "kind": { "group": "prueba.ucm.es", "version": "v1", "kind": "EdgeService" },
+                "resource": { "group": "prueba.ucm.es", "version": "v1", "resource": "edgeservices" },
+                "name": "web",
+                "namespace": "default",
+                "operation": "CREATE",
+                "userInfo": { "username": "admin" },
+                "object": {
+                    "apiVersion": "prueba.ucm.es/v1",
+                    "kind": "EdgeService",
+                    "metadata": { "name": "web", "namespace": "default" },
+                    "spec": spec
+                }
+            }
+        })).unwrap();
+        review.try_into().unwrap()
+    }
+
+    fn patch(response: &AdmissionResponse) -> JsonValue {
+        response.patch.as_deref().map(|patch| serde_json::from_slice(patch).unwrap()).unwrap_or_default()
+    }
+
+    #[tokio::test]
+    async fn validate_accepts_valid_spec() {
+        let response = validate::<Accept>(request(json!({ "selector": "web-app_1.0", "script": "zones.rhai" }))).await;
+        assert!(response.allowed);
+    }
+
+    #[tokio::test]
+    async fn validate_rejects_bad_selector() {
+        for selector in ["", "-web", "web app", &"a".repeat(MAX_LABEL_VALUE_LEN + 1)] {
+            let response = validate::<Accept>(request(json!({ "selector": selector }))).await;
+            assert!(!response.allowed, "selector {selector:?} was accepted");
+        }
+    }
+
+    #[tokio::test]
+    async fn validate_rejects_script_with_rules() {
+        let spec = json!({ "selector": "web", "script": "zones.rhai", "rules": { "connect": [{}] } });
+        let response = validate::<Accept>(request(spec.clone())).await;
+        assert!(!response.allowed);
+
+        let object = request(spec).object.unwrap();
+        let error = check_spec::<Accept>(&object).await.unwrap_err();
+        assert!(format!("{error:#}").contains("cannot be used together"));
+    }
+
+    #[tokio::test]
+    async fn mutate_defaults_dry_run() {
+        let response = mutate(request(json!({ "selector": "web" }))).await;
+        assert!(response.allowed);
+        assert_eq!(patch(&response), json!([{ "op": "add", "path": "/spec/dry_run", "value": false }]));
+    }
+
+    #[tokio::test]
+    async fn mutate_removes_empty_script() {
+        let response = mutate(request(json!({ "selector": "web", "dry_run": true, "script": "  " }))).await;
+        assert_eq!(patch(&response), json!([{ "op": "remove", "path": "/spec/script" }]));
+    }
+
+    #[tokio::test]
+    async fn mutate_leaves_complete_spec() {
+        let response = mutate(request(json!({ "selector": "web", "dry_run": false, "script": "zones.rhai" }))).await;
+        assert!(response.allowed && response.patch.is_none());
+    }
+}
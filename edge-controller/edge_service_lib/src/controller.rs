@@ -4,28 +4,76 @@ use schemars::JsonSchema;
 use kube::runtime::finalizer::Event as Finalizer;
 use tokio::sync::mpsc;
 use uuid::Uuid;
-use std::{sync::Arc, time::Duration};
+use std::{collections::BTreeMap, sync::Arc, time::Duration};
 use kube::runtime::{finalizer, watcher};
 use kube::runtime::{controller::Action, Controller};
-use kube::{Api, Client, CustomResource};
+use kube::{Api, Client, CustomResource, Resource};
 use futures::StreamExt;
 use thiserror::Error;
-use crate::endpoint_watcher::Message;
+use crate::endpoint_watcher::{Message, ServiceInfo};
+use crate::metrics::ControllerMetrics;
 
 const FINALIZER_NAME: &str = "edgeservice.prueba.ucm.es/deletion";
 
-/// This provides a hook for generating the CRD yaml (in crdgen.rs)
+/// Pods selected by an EdgeService and how their graph is built. The CRD
+/// is generated from this type (`edge_service_controller crdgen`).
 #[derive(CustomResource, Deserialize, Serialize, Clone, Debug, JsonSchema)]
 #[cfg_attr(test, derive(Default))]
 #[kube(kind = "EdgeService", group = "prueba.ucm.es", version = "v1", namespaced)]
 #[kube(status = "EdgeServiceStatus", shortname = "eservice")]
 pub struct EdgeNodeSpec {
-    pub selector: String
+    pub selector: String,
+    /// Compute the graph without patching the pods.
+    #[serde(default)]
+    pub dry_run: bool,
+    /// Script run by the script policy, from the controller's scripts directory.
+    #[serde(default)]
+    pub script: Option<String>,
+    /// Topology rules evaluated by the controller instead of its default
+    /// policy. Cannot be combined with `script`.
+    #[serde(default)]
+    pub rules: Option<TopologyRules>
 }
-/// The status object of `Document`
+
+/// Declarative topology of a service. The graph has an edge from each
+/// pod to every pod that a `connect` rule matches, up to `max_neighbours`.
+#[derive(Deserialize, Serialize, Clone, Debug, Default, PartialEq, Eq, JsonSchema)]
+pub struct TopologyRules {
+    #[serde(default)]
+    pub connect: Vec<ConnectRule>,
+    /// Maximum outgoing neighbours of each pod. Pods in the same zone go first.
+    #[serde(default)]
+    pub max_neighbours: Option<u32>
+}
+
+/// Connects every pod matching `from` to every pod matching `to`.
+#[derive(Deserialize, Serialize, Clone, Debug, Default, PartialEq, Eq, JsonSchema)]
+pub struct ConnectRule {
+    #[serde(default)]
+    pub from: PodMatch,
+    #[serde(default)]
+    pub to: PodMatch,
+    /// Only connect pods whose nodes are in the same zone.
+    #[serde(default)]
+    pub same_zone: bool
+}
+
+/// Labels that a pod and its node must have. Empty matches every pod.
+#[derive(Deserialize, Serialize, Clone, Debug, Default, PartialEq, Eq, JsonSchema)]
+pub struct PodMatch {
+    #[serde(default)]
+    pub pod_labels: BTreeMap<String, String>,
+    #[serde(default)]
+    pub node_labels: BTreeMap<String, String>
+}
+/// Status of an EdgeService, written by the endpoint watcher.
 #[derive(Deserialize, Serialize, Clone, Default, Debug, JsonSchema)]
 pub struct EdgeServiceStatus {
-    pub prueba: i32
+    #[serde(default)]
+    pub prueba: i32,
+    /// Pods excluded as targets by the maintenance annotation.
+    #[serde(default)]
+    pub maintenance: Vec<String>
 }
 
 #[derive(Error, Debug)]
@@ -40,11 +88,14 @@ type Result<T, E = Error> = std::result::Result<T, E>;
 
 // Context for our reconciler
 pub struct Context {
-    client: Client
+    client: Client,
+    metrics: Arc<ControllerMetrics>
 }
 
-fn error_policy(_doc: Arc<EdgeService>, error: &Error, _ctx: Arc<Context>) -> Action {
+fn error_policy(doc: Arc<EdgeService>, error: &Error, ctx: Arc<Context>) -> Action {
     error!("Error en reconcile: {}", error);
+    let service = doc.metadata.uid.as_deref().unwrap_or_default();
+    ctx.metrics.reconcile_errors.with_label_values(&[service]).inc();
     Action::requeue(Duration::from_secs(5))
 }
 
@@ -61,11 +112,15 @@ async fn reconcile(tservice: Arc<EdgeService>, ctx: Arc<Context>, sender: mpsc::
     
         let action = match event {
             Finalizer::Apply(tservice) => {
-                sender.send(Message::NewService { 
-                    service_uid: tsevice_uid,
+                sender.send(Message::NewService(ServiceInfo { 
+                    uid: tsevice_uid,
+                    reference: tservice.object_ref(&()),
                     namespace: tservice.metadata.namespace.clone().expect("Missing tservice namespace"),
-                    selector: tservice.spec.selector.clone()
-                })
+                    selector: tservice.spec.selector.clone(),
+                    dry_run: tservice.spec.dry_run,
+                    script: tservice.spec.script.clone(),
+                    rules: tservice.spec.rules.clone()
+                }))
                 .await
                 .expect("Failed to send message.");
                 Action::requeue(Duration::from_secs(300))
@@ -84,10 +139,10 @@ async fn reconcile(tservice: Arc<EdgeService>, ctx: Arc<Context>, sender: mpsc::
     .map_err(|e| Error::FinalizerError(Box::new(e)))
 }
 
-pub fn run(client: Client, sender: mpsc::Sender<Message>) {
+pub fn run(client: Client, sender: mpsc::Sender<Message>, metrics: Arc<ControllerMetrics>) {
 
     let tservices = Api::<EdgeService>::all(client.clone());
-    let context = Arc::new(Context { client });
+    let context = Arc::new(Context { client, metrics });
 
     tokio::spawn(async move {
         let sender = sender;
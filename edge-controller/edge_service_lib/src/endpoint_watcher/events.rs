@@ -0,0 +1,92 @@
+use std::env;
+use k8s_openapi::api::core::v1::{ObjectReference, Pod};
+use kube::runtime::events::{Event, EventType, Recorder, Reporter};
+use kube::{Client, Resource};
+
+const REPORTER_NAME: &str = "edge-service-controller";
+
+pub const REASON_POD_JOINED: &str = "PodJoined";
+pub const REASON_POD_LEFT: &str = "PodLeft";
+pub const REASON_PATCH_FAILED: &str = "PatchFailed";
+pub const REASON_POLICY_CONFIG_INVALID: &str = "PolicyConfigInvalid";
+
+/// Publishes Kubernetes Events on an EdgeService and, if
+/// EDGE_CONTROLLER_POD_EVENTS=true, on the affected pods too.
+#[derive(Clone)]
+pub struct ServiceEvents {
+    /// None without Kubernetes: events are only logged.
+    target: Option<(Client, Recorder)>,
+    pod_events: bool
+}
+
+impl std::fmt::Debug for ServiceEvents {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "ServiceEvents {{ enabled: {}, pod_events: {} }}", self.target.is_some(), self.pod_events)
+    }
+}
+
+impl ServiceEvents {
+
+    pub fn new(client: Client, service: ObjectReference) -> Self {
+        let pod_events = env::var("EDGE_CONTROLLER_POD_EVENTS")
+            .is_ok_and(|x| x == "true");
+
+        Self {
+            target: Some((client.clone(), Recorder::new(client, reporter(), service))),
+            pod_events
+        }
+    }
+
+    pub fn disabled() -> Self {
+        Self { target: None, pod_events: false }
+    }
+
+    pub fn normal(&self, reason: &str, note: String, pod: Option<&Pod>) {
+        self.publish(EventType::Normal, reason, note, pod);
+    }
+
+    pub fn warning(&self, reason: &str, note: String, pod: Option<&Pod>) {
+        self.publish(EventType::Warning, reason, note, pod);
+    }
+
+    fn publish(&self, type_: EventType, reason: &str, note: String, pod: Option<&Pod>) {
+
+        let Some((client, recorder)) = &self.target else {
+            log::info!("Event {reason}: {note}");
+            return;
+        };
+
+        let pod_ref = pod.map(|pod| pod.object_ref(&()));
+        let reason = reason.to_string();
+        let secondary = pod_ref.clone();
+        let recorders = match pod_ref {
+            Some(pod_ref) if self.pod_events => {
+                let pod_recorder = Recorder::new(client.clone(), reporter(), pod_ref);
+                vec![recorder.clone(), pod_recorder]
+            },
+            _ => vec![recorder.clone()]
+        };
+
+        tokio::spawn(async move {
+            for recorder in recorders {
+                let event = Event {
+                    type_,
+                    reason: reason.clone(),
+                    note: Some(note.clone()),
+                    action: reason.clone(),
+                    secondary: secondary.clone()
+                };
+                if let Err(e) = recorder.publish(event).await {
+                    log::error!("Failed to publish event {reason}: {e}");
+                }
+            }
+        });
+    }
+}
+
+fn reporter() -> Reporter {
+    Reporter {
+        controller: REPORTER_NAME.to_string(),
+        instance: env::var("POD_NAME").ok()
+    }
+}
@@ -0,0 +1,196 @@
+use std::env;
+use std::str::FromStr;
+use std::sync::Arc;
+use serde::Deserialize;
+use subtle::ConstantTimeEq;
+use tide::{Request, Response, StatusCode};
+use tokio::sync::{mpsc, oneshot};
+use uuid::Uuid;
+use super::Message;
+use crate::metrics::ControllerMetrics;
+use super::overrides::EdgeOverride;
+
+const LISTEN_ADDRESS: &str = "0.0.0.0:9091";
+
+/// Token que deben enviar (Authorization: Bearer <token>) las peticiones
+/// que modifican el grafo. Si no está definido, no se aceptan escrituras.
+const API_TOKEN_VAR: &str = "EDGE_CONTROLLER_API_TOKEN";
+
+#[derive(Clone)]
+struct State {
+    sender: mpsc::Sender<Message>,
+    metrics: Arc<ControllerMetrics>
+}
+
+#[derive(Debug, Deserialize)]
+struct HistoryQuery {
+    from: Option<u64>,
+    to: Option<u64>
+}
+
+#[derive(Debug, Deserialize)]
+struct SnapshotQuery {
+    at: u64
+}
+
+pub async fn graph_export_server(sender: mpsc::Sender<Message>, metrics: Arc<ControllerMetrics>) {
+
+    let mut server = tide::with_state(State { sender, metrics });
+    server.at("/metrics").get(export_metrics);
+    server.at("/:service").get(export_graph);
+    server.at("/:service/history").get(export_history);
+    server.at("/:service/snapshot").get(export_snapshot);
+    server.at("/:service/dry-run").get(export_dry_run);
+    server.at("/:service/endpoints/:pod").get(export_endpoints);
+    server.at("/:service/overrides")
+        .get(export_overrides)
+        .post(apply_override);
+    server.listen(LISTEN_ADDRESS).await.expect("HTTP server ended.");
+}
+
+fn service_uid(request: &Request<State>) -> tide::Result<Uuid> {
+    let uid = Uuid::from_str(request.param("service")?)
+        .map_err(|e| tide::Error::new(StatusCode::BadRequest, e))?;
+    Ok(uid)
+}
+
+async fn export_metrics(request: Request<State>) -> tide::Result<Response> {
+    let response = Response::builder(StatusCode::Ok)
+        .content_type("text/plain; version=0.0.4")
+        .body(request.state().metrics.encode())
+        .build();
+    Ok(response)
+}
+
+async fn export_graph(request: Request<State>) -> tide::Result<String> {
+
+    let (s, r) = oneshot::channel::<String>();
+    request.state().sender.send(Message::ExportGraph {
+        service_uid: service_uid(&request)?,
+        response_to: s
+    }).await?;
+
+    Ok(r.await?)
+}
+
+async fn export_history(request: Request<State>) -> tide::Result<Response> {
+
+    let query: HistoryQuery = request.query()?;
+    let (s, r) = oneshot::channel::<String>();
+    request.state().sender.send(Message::ExportHistory {
+        service_uid: service_uid(&request)?,
+        from: query.from,
+        to: query.to,
+        response_to: s
+    }).await?;
+
+    let response = Response::builder(StatusCode::Ok)
+        .content_type(tide::http::mime::JSON)
+        .body(r.await?)
+        .build();
+    Ok(response)
+}
+
+async fn export_snapshot(request: Request<State>) -> tide::Result<Response> {
+
+    let query: SnapshotQuery = request.query()?;
+    let (s, r) = oneshot::channel::<Option<String>>();
+    request.state().sender.send(Message::ExportSnapshot {
+        service_uid: service_uid(&request)?,
+        at: query.at,
+        response_to: s
+    }).await?;
+
+    let response = match r.await? {
+        Some(graph) => Response::builder(StatusCode::Ok).body(graph).build(),
+        None => Response::builder(StatusCode::NotFound)
+            .body(format!("No history recorded for instant {}", query.at))
+            .build()
+    };
+    Ok(response)
+}
+
+async fn export_dry_run(request: Request<State>) -> tide::Result<Response> {
+
+    let (s, r) = oneshot::channel::<String>();
+    request.state().sender.send(Message::ExportDryRun {
+        service_uid: service_uid(&request)?,
+        response_to: s
+    }).await?;
+
+    let response = Response::builder(StatusCode::Ok)
+        .content_type(tide::http::mime::JSON)
+        .body(r.await?)
+        .build();
+    Ok(response)
+}
+
+async fn export_endpoints(request: Request<State>) -> tide::Result<Response> {
+
+    let pod_name = request.param("pod")?.to_string();
+    let (s, r) = oneshot::channel::<Option<String>>();
+    request.state().sender.send(Message::ExportEndpoints {
+        service_uid: service_uid(&request)?,
+        pod_name: pod_name.clone(),
+        response_to: s
+    }).await?;
+
+    let response = match r.await? {
+        Some(endpoints) => Response::builder(StatusCode::Ok)
+            .content_type(tide::http::mime::JSON)
+            .body(endpoints)
+            .build(),
+        None => Response::builder(StatusCode::NotFound)
+            .body(format!("No endpoints published for pod {pod_name}"))
+            .build()
+    };
+    Ok(response)
+}
+
+async fn export_overrides(request: Request<State>) -> tide::Result<Response> {
+
+    let (s, r) = oneshot::channel::<String>();
+    request.state().sender.send(Message::ExportOverrides {
+        service_uid: service_uid(&request)?,
+        response_to: s
+    }).await?;
+
+    let response = Response::builder(StatusCode::Ok)
+        .content_type(tide::http::mime::JSON)
+        .body(r.await?)
+        .build();
+    Ok(response)
+}
+
+async fn apply_override(mut request: Request<State>) -> tide::Result<Response> {
+
+    authorize(&request)?;
+    let over: EdgeOverride = request.body_json().await?;
+    let (s, r) = oneshot::channel::<anyhow::Result<()>>();
+    request.state().sender.send(Message::ApplyOverride {
+        service_uid: service_uid(&request)?,
+        over,
+        response_to: s
+    }).await?;
+
+    let response = match r.await? {
+        Ok(_) => Response::new(StatusCode::NoContent),
+        Err(e) => Response::builder(StatusCode::Conflict).body(e.to_string()).build()
+    };
+    Ok(response)
+}
+
+fn authorize(request: &Request<State>) -> tide::Result<()> {
+
+    let token = env::var(API_TOKEN_VAR)
+        .map_err(|_| tide::Error::from_str(StatusCode::Forbidden, "Write endpoints are disabled"))?;
+
+    let authorized = request.header("Authorization")
+        .and_then(|values| values.get(0))
+        .and_then(|value| value.as_str().strip_prefix("Bearer "))
+        // Comparación en tiempo constante para no filtrar el token por los tiempos de respuesta.
+        .is_some_and(|received| received.as_bytes().ct_eq(token.as_bytes()).into());
+
+    if authorized { Ok(()) }
+    else { Err(tide::Error::from_str(StatusCode::Unauthorized, "Invalid token")) }
+}
@@ -0,0 +1,163 @@
+use std::collections::VecDeque;
+use std::env;
+use std::time::{SystemTime, UNIX_EPOCH};
+use petgraph::graphmap::DiGraphMap;
+use serde::Serialize;
+use uuid::Uuid;
+use crate::policy::{GraphChange, PodGraph};
+use super::overrides::EdgeOverride;
+
+/// Numero de cambios que se guardan por servicio si no
+/// se indica otro valor con EDGE_CONTROLLER_HISTORY_SIZE.
+const DEFAULT_HISTORY_SIZE: usize = 4096;
+
+/// Cause recorded for changes made by the controller itself
+/// (e.g. edges dropped because a pod left the service).
+pub const CONTROLLER_CAUSE: &str = "controller";
+
+/// Cause recorded for changes requested through the override API.
+pub const OVERRIDE_CAUSE: &str = "override";
+
+#[derive(Clone, Copy, Debug, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum PodEventKind {
+    Added,
+    Updated,
+    Removed
+}
+
+impl PodEventKind {
+    pub const ALL: [PodEventKind; 3] = [PodEventKind::Added, PodEventKind::Updated, PodEventKind::Removed];
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            PodEventKind::Added => "added",
+            PodEventKind::Updated => "updated",
+            PodEventKind::Removed => "removed"
+        }
+    }
+}
+
+/// Pod event that triggered a set of graph changes.
+#[derive(Clone, Debug, Serialize)]
+pub struct PodEvent {
+    pub kind: PodEventKind,
+    pub pod: Uuid,
+    pub pod_name: String
+}
+
+/// What caused a set of graph changes.
+#[derive(Clone, Debug, Serialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum Trigger {
+    Pod(PodEvent),
+    Override(EdgeOverride)
+}
+
+#[derive(Clone, Debug, Serialize)]
+pub struct HistoryEntry {
+    pub timestamp_ms: u64,
+    #[serde(flatten)]
+    pub change: GraphChange,
+    pub cause: String,
+    pub trigger: Trigger
+}
+
+/// Bounded log of the changes applied to the graph of a service.
+///
+/// `base` holds the graph as it was before the oldest entry still
+/// in the log, so any instant after `base_timestamp_ms` can be rebuilt
+/// by replaying the entries on top of it.
+#[derive(Debug)]
+pub struct GraphHistory {
+    base: PodGraph,
+    base_timestamp_ms: u64,
+    entries: VecDeque<HistoryEntry>,
+    capacity: usize
+}
+
+impl GraphHistory {
+
+    pub fn new() -> Self {
+        let capacity = env::var("EDGE_CONTROLLER_HISTORY_SIZE")
+            .ok()
+            .and_then(|x| x.parse().ok())
+            .unwrap_or(DEFAULT_HISTORY_SIZE);
+
+        Self {
+            base: DiGraphMap::new(),
+            base_timestamp_ms: now_ms(),
+            entries: VecDeque::with_capacity(capacity),
+            capacity
+        }
+    }
+
+    pub fn record(&mut self, trigger: &Trigger, cause: &str, changes: Vec<GraphChange>) {
+
+        let timestamp_ms = now_ms();
+        for change in changes {
+            if self.entries.len() >= self.capacity {
+                // Consolidar la entrada más antigua en el grafo base.
+                if let Some(oldest) = self.entries.pop_front() {
+                    apply_change(&mut self.base, oldest.change);
+                    self.base_timestamp_ms = oldest.timestamp_ms;
+                }
+            }
+
+            if self.capacity > 0 {
+                self.entries.push_back(HistoryEntry {
+                    timestamp_ms,
+                    change,
+                    cause: cause.to_string(),
+                    trigger: trigger.clone()
+                });
+            }
+        }
+    }
+
+    /// Entries with `from <= timestamp <= to`. Missing bounds are open.
+    pub fn range(&self, from: Option<u64>, to: Option<u64>) -> Vec<&HistoryEntry> {
+        self.entries.iter()
+            .filter(|entry| from.is_none_or(|from| entry.timestamp_ms >= from))
+            .filter(|entry| to.is_none_or(|to| entry.timestamp_ms <= to))
+            .collect()
+    }
+
+    /// Rebuilds the graph as it was at instant `at`. Returns None if
+    /// the entries needed to rebuild it have already been discarded.
+    pub fn snapshot_at(&self, at: u64) -> Option<PodGraph> {
+
+        if at < self.base_timestamp_ms {
+            return None;
+        }
+
+        let mut graph = self.base.clone();
+        self.entries.iter()
+            .take_while(|entry| entry.timestamp_ms <= at)
+            .for_each(|entry| apply_change(&mut graph, entry.change));
+
+        Some(graph)
+    }
+}
+
+fn apply_change(graph: &mut PodGraph, change: GraphChange) {
+    match change {
+        GraphChange::NodeAdded { node } => { graph.add_node(node); },
+        GraphChange::NodeRemoved { node } => { graph.remove_node(node); },
+        GraphChange::EdgeAdded { from, to } => { graph.add_edge(from, to, ()); },
+        GraphChange::EdgeRemoved { from, to } => { graph.remove_edge(from, to); }
+    }
+}
+
+pub fn now_ms() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_millis() as u64)
+        .unwrap_or(0)
+}
+
+/// Short name of a policy type, used as the cause of its changes.
+pub fn policy_name<T>() -> &'static str {
+    let full_name = std::any::type_name::<T>();
+    full_name.rsplit("::").next().unwrap_or(full_name)
+}
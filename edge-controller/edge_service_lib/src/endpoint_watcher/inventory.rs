@@ -0,0 +1,210 @@
+use super::{Message, NodeInfo, ServiceInfo};
+
+use std::collections::BTreeMap;
+use std::path::PathBuf;
+use std::time::{Duration, SystemTime};
+use anyhow::{Context, Result};
+use k8s_openapi::api::core::v1::{ObjectReference, Pod};
+use log::{error, info};
+use serde::Deserialize;
+use serde_json::Value as JsonValue;
+use tokio::sync::mpsc;
+use uuid::Uuid;
+use crate::controller::TopologyRules;
+use crate::simulator::TraceEvent;
+
+const HW_ANNOT: &str = "edgeservices.prueba.ucm.es/hw_info";
+
+/// Services and pods of a deployment without Kubernetes.
+///
+/// ```json
+/// { "services": [ { "uid": "...", "name": "detector", "pods": [
+///     { "uid": "...", "name": "detector-1", "ip": "10.0.0.3", "ready": true, "hw_info": { ... } }
+/// ] } ],
+///   "nodes": { "node-1": { "labels": { "topology.kubernetes.io/zone": "lab" }, "maintenance": false } } }
+/// ```
+#[derive(Clone, Debug, Default, Deserialize)]
+pub struct Inventory {
+    #[serde(default)]
+    pub services: Vec<InventoryService>,
+    #[serde(default)]
+    pub nodes: BTreeMap<String, NodeInfo>
+}
+
+#[derive(Clone, Debug, PartialEq, Deserialize)]
+pub struct InventoryService {
+    pub uid: Uuid,
+    pub name: String,
+    #[serde(default)]
+    pub dry_run: bool,
+    #[serde(default)]
+    pub script: Option<String>,
+    #[serde(default)]
+    pub rules: Option<TopologyRules>,
+    #[serde(default)]
+    pub pods: Vec<InventoryPod>
+}
+
+#[derive(Clone, Debug, PartialEq, Deserialize)]
+pub struct InventoryPod {
+    pub uid: Uuid,
+    pub name: String,
+    pub ip: String,
+    #[serde(default)]
+    pub port: Option<u16>,
+    #[serde(default = "default_ready")]
+    pub ready: bool,
+    #[serde(default)]
+    pub hw_info: Option<JsonValue>,
+    #[serde(default)]
+    pub node_name: Option<String>,
+    #[serde(default)]
+    pub labels: BTreeMap<String, String>,
+    #[serde(default)]
+    pub annotations: BTreeMap<String, String>
+}
+
+fn default_ready() -> bool { true }
+
+impl InventoryService {
+
+    fn info(&self) -> ServiceInfo {
+        ServiceInfo {
+            uid: self.uid,
+            reference: ObjectReference {
+                kind: Some("EdgeService".to_string()),
+                name: Some(self.name.clone()),
+                uid: Some(self.uid.to_string()),
+                ..Default::default()
+            },
+            namespace: String::new(),
+            selector: String::new(),
+            dry_run: self.dry_run,
+            script: self.script.clone(),
+            rules: self.rules.clone()
+        }
+    }
+
+    fn pod(&self, uid: Uuid) -> Option<&InventoryPod> {
+        self.pods.iter().find(|pod| pod.uid == uid)
+    }
+}
+
+impl InventoryPod {
+
+    fn to_pod(&self) -> Pod {
+        let mut annotations = self.annotations.clone();
+        if let Some(hw_info) = &self.hw_info {
+            annotations.insert(HW_ANNOT.to_string(), hw_info.to_string());
+        }
+
+        TraceEvent {
+            timestamp_ms: None,
+            uid: self.uid,
+            name: self.name.clone(),
+            ip: Some(self.ip.clone()),
+            port: self.port,
+            ready: self.ready,
+            node_name: self.node_name.clone(),
+            labels: self.labels.clone(),
+            annotations
+        }.to_pod()
+    }
+
+    fn message(&self, service_uid: Uuid) -> Message {
+        match self.ready {
+            true => Message::PodReady { service_uid, pod: self.to_pod() },
+            false => Message::PodUnready { service_uid, pod: self.to_pod() }
+        }
+    }
+}
+
+impl Inventory {
+
+    fn service(&self, uid: Uuid) -> Option<&InventoryService> {
+        self.services.iter().find(|service| service.uid == uid)
+    }
+
+    /// Messages that take the endpoint_watcher loop from `self` to `new`.
+    fn diff(&self, new: &Inventory) -> Vec<Message> {
+
+        let mut messages = Vec::new();
+        for (node, info) in new.nodes.iter() {
+            if self.nodes.get(node) != Some(info) {
+                messages.push(Message::NodeChanged { node: node.clone(), info: Some(info.clone()) });
+            }
+        }
+        for node in self.nodes.keys().filter(|node| !new.nodes.contains_key(*node)) {
+            messages.push(Message::NodeChanged { node: node.clone(), info: None });
+        }
+
+        for old in self.services.iter() {
+            if new.service(old.uid).is_none() {
+                messages.push(Message::DeleteService { service_uid: old.uid });
+            }
+        }
+
+        for service in new.services.iter() {
+            let old = self.service(service.uid);
+            if old.is_none_or(|old| old.dry_run != service.dry_run || old.script != service.script || old.rules != service.rules) {
+                messages.push(Message::NewService(service.info()));
+            }
+
+            // Pods borrados del inventario.
+            for pod in old.iter().flat_map(|old| old.pods.iter()) {
+                if service.pod(pod.uid).is_none() {
+                    messages.push(Message::PodUnready { service_uid: service.uid, pod: pod.to_pod() });
+                }
+            }
+
+            for pod in service.pods.iter() {
+                if old.and_then(|old| old.pod(pod.uid)) != Some(pod) {
+                    messages.push(pod.message(service.uid));
+                }
+            }
+        }
+        messages
+    }
+}
+
+async fn read_inventory(path: &PathBuf) -> Result<Inventory> {
+    let content = tokio::fs::read_to_string(path)
+        .await
+        .with_context(|| format!("Failed to read inventory {}", path.display()))?;
+    let inventory = serde_json::from_str(&content)
+        .with_context(|| format!("Invalid inventory {}", path.display()))?;
+    Ok(inventory)
+}
+
+/// Polls the inventory file and sends the changes to the endpoint_watcher
+/// loop. An invalid file is ignored until it changes again.
+pub async fn watch_inventory(path: PathBuf, interval: Duration, sender: mpsc::Sender<Message>) {
+
+    info!("Watching inventory {}", path.display());
+    let mut current = Inventory::default();
+    let mut last_modified: Option<SystemTime> = None;
+    loop {
+        let modified = tokio::fs::metadata(&path).await
+            .and_then(|metadata| metadata.modified());
+
+        match modified {
+            Ok(modified) if last_modified != Some(modified) => {
+                last_modified = Some(modified);
+                match read_inventory(&path).await {
+                    Ok(new) => {
+                        info!("Inventory {} changed", path.display());
+                        for msg in current.diff(&new) {
+                            sender.send(msg).await.expect("Failed to send message");
+                        }
+                        current = new;
+                    },
+                    Err(e) => error!("{e:#}")
+                }
+            },
+            Ok(_) => (),
+            Err(e) => error!("Failed to read inventory {}: {e}", path.display())
+        }
+
+        tokio::time::sleep(interval).await;
+    }
+}
@@ -1,48 +1,136 @@
+mod events;
+mod export_server;
+mod history;
+mod inventory;
+mod nodes;
+mod notifier;
+mod overrides;
+mod recording;
+mod service_graph;
 mod service_watcher;
 
-use std::{collections::HashMap, str::FromStr}; 
-use k8s_openapi::api::core::v1::Pod;
+pub(crate) use overrides::EdgeOverrides;
+pub(crate) use service_graph::{ServiceGraph, PROXY_CONTAINER};
+pub use history::PodEventKind;
+pub use nodes::NodeInfo;
+pub use overrides::Edge;
+pub use service_graph::Neighbor;
+
+use std::collections::{hash_map::Entry, BTreeMap, HashMap};
+use std::env;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::Duration;
+use k8s_openapi::api::core::v1::{ObjectReference, Pod};
 use log::{debug, info};
 use kube::Client;
 
 use tokio::sync::{mpsc, oneshot};
 use service_watcher::ServiceWatcher;
+use notifier::PodServices;
+use crate::metrics::ControllerMetrics;
+use crate::controller::TopologyRules;
 use crate::policy::Policy;
 use uuid::Uuid;
-use anyhow::Context;
+use overrides::EdgeOverride;
+use recording::Recorder;
+use serde::{Deserialize, Serialize};
 
 const CHANNEL_SIZE: usize = 128;
 
+/// Datos de un EdgeService que necesita su ServiceWatcher.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct ServiceInfo {
+    pub uid: Uuid,
+    pub reference: ObjectReference,
+    pub namespace: String,
+    pub selector: String,
+    pub dry_run: bool,
+    #[serde(default)]
+    pub script: Option<String>,
+    #[serde(default)]
+    pub rules: Option<TopologyRules>
+}
+
+/// De dónde salen los pods de los servicios y adónde van sus endpoints.
+#[derive(Clone)]
+pub enum Backend {
+    /// Pods watched by label, endpoints written to pod annotations.
+    Kubernetes(Client),
+    /// Pods sent to the loop by the inventory watcher or a replay.
+    /// Endpoints are written to `endpoints_dir`, if any, and served
+    /// on /:service/endpoints/:pod.
+    Local { endpoints_dir: Option<PathBuf> }
+}
+
 #[derive(Debug)]
 pub enum Message {
-    NewService { service_uid: Uuid, namespace: String, selector: String },
+    NewService(ServiceInfo),
     DeleteService  { service_uid: Uuid },
     PodReady { service_uid: Uuid, pod: Pod },
     PodUnready { service_uid: Uuid, pod: Pod },
-    ExportGraph { service_uid: Uuid, response_to: oneshot::Sender<String> }
+    ExportGraph { service_uid: Uuid, response_to: oneshot::Sender<String> },
+    ExportHistory { service_uid: Uuid, from: Option<u64>, to: Option<u64>, response_to: oneshot::Sender<String> },
+    ExportSnapshot { service_uid: Uuid, at: u64, response_to: oneshot::Sender<Option<String>> },
+    ApplyOverride { service_uid: Uuid, over: EdgeOverride, response_to: oneshot::Sender<anyhow::Result<()>> },
+    ExportOverrides { service_uid: Uuid, response_to: oneshot::Sender<String> },
+    ExportDryRun { service_uid: Uuid, response_to: oneshot::Sender<String> },
+    ExportEndpoints { service_uid: Uuid, pod_name: String, response_to: oneshot::Sender<Option<String>> },
+    /// None if the node was deleted.
+    NodeChanged { node: String, info: Option<NodeInfo> }
 }
 
-pub fn run<T: Policy>(client: Client) -> mpsc::Sender<Message> {
+/// Starts the loop that keeps the graph of every service.
+pub fn run<T: Policy>(backend: Backend, metrics: Arc<ControllerMetrics>) -> mpsc::Sender<Message> {
 
     let (sender, mut receiver) = mpsc::channel(CHANNEL_SIZE);
     let msg_sender = sender.clone();
+    let watcher_metrics = Arc::clone(&metrics);
     tokio::spawn(async move {
         
+        let metrics = watcher_metrics;
+        let global_dry_run = env::var("EDGE_CONTROLLER_DRY_RUN").is_ok_and(|x| x == "true");
+        if global_dry_run { info!("Running in dry-run mode, pods will not be patched."); }
+        let recorder = match env::var("EDGE_CONTROLLER_RECORD_FILE") {
+            Ok(path) => Recorder::new(Path::new(&path)).await
+                .map_err(|e| log::error!("Pod events will not be recorded: {e:#}"))
+                .ok(),
+            Err(_) => None
+        };
+        if let Backend::Kubernetes(client) = &backend {
+            nodes::start_node_watcher(client.clone(), msg_sender.clone());
+        }
+        let mut nodes: BTreeMap<String, NodeInfo> = BTreeMap::new();
         let mut service_watchers: HashMap<Uuid, ServiceWatcher<T>> = HashMap::new();
+        let pod_services = PodServices::default();
         loop {
             let msg = receiver.recv().await.expect("Channel closed.");
+            let depth = msg_sender.max_capacity() - msg_sender.capacity();
+            metrics.channel_depth.set(depth as i64);
+            if let Some(recorder) = &recorder {
+                recorder.record(&msg);
+            }
             match msg {
-                Message::NewService { service_uid, namespace, selector } => {
-                    if !service_watchers.contains_key(&service_uid) {
-                        let service = ServiceWatcher::new(service_uid, client.clone(), msg_sender.clone(), &namespace, selector).await;
-                        info!("Adding watcher for service {service_uid}");
-                        service_watchers.insert(service_uid, service);
-                    }   
+                Message::NewService(mut info) => {
+                    info.dry_run |= global_dry_run;
+                    let service_uid = info.uid;
+                    match service_watchers.entry(service_uid) {
+                        Entry::Vacant(entry) => {
+                            let mut service = ServiceWatcher::new(info, &backend, msg_sender.clone(), pod_services.clone(), Arc::clone(&metrics)).await;
+                            service.set_nodes(&nodes);
+                            info!("Adding watcher for service {service_uid}");
+                            entry.insert(service);
+                        },
+                        Entry::Occupied(mut entry) => entry.get_mut().update(&info)
+                    }
                 },
                 Message::DeleteService{service_uid}=> {
-                    if service_watchers.contains_key(&service_uid) {
+                    if let Some(service) = service_watchers.remove(&service_uid) {
                         info!("Removing watcher for service {service_uid}");
-                        service_watchers.remove(&service_uid);
+                        let pods = service.pod_uids();
+                        drop(service);
+                        metrics.remove_service(&service_uid.to_string());
+                        republish_single(&mut service_watchers, &pod_services, &pods);
                     }
                 },
                 Message::PodReady { service_uid, pod } => {
@@ -55,16 +143,80 @@ pub fn run<T: Policy>(client: Client) -> mpsc::Sender<Message> {
                 Message::PodUnready { service_uid, pod } => {
                     if let Some(service) = service_watchers.get_mut(&service_uid) {
                         info!("Deleted por for service {service_uid}");
+                        // Si deja de estar en varios servicios recupera la anotación antigua.
+                        let left = service_graph::pod_uid(&pod).ok()
+                            .filter(|uid| pod_services.services(*uid).contains(&service_uid));
                         service.remove_pod(pod).expect("AAA");
+                        if let Some(uid) = left {
+                            republish_single(&mut service_watchers, &pod_services, &[uid]);
+                        }
                     }
                 },
                 Message::ExportGraph { service_uid, response_to } => {
                     if let Some(service) = service_watchers.get(&service_uid) {
                         let graph_string = service.export_graph();
-                        if let Err(_) = response_to.send(graph_string) {
+                        if response_to.send(graph_string).is_err() {
                             log::error!("Failed to send graph export message.");
                         }
                     }
+                },
+                Message::ExportHistory { service_uid, from, to, response_to } => {
+                    if let Some(service) = service_watchers.get(&service_uid) {
+                        let history = service.export_history(from, to);
+                        if response_to.send(history).is_err() {
+                            log::error!("Failed to send history export message.");
+                        }
+                    }
+                },
+                Message::ExportSnapshot { service_uid, at, response_to } => {
+                    if let Some(service) = service_watchers.get(&service_uid) {
+                        let snapshot = service.export_snapshot(at);
+                        if response_to.send(snapshot).is_err() {
+                            log::error!("Failed to send snapshot export message.");
+                        }
+                    }
+                },
+                Message::ApplyOverride { service_uid, over, response_to } => {
+                    if let Some(service) = service_watchers.get_mut(&service_uid) {
+                        info!("Applying override {:?} for service {service_uid}", over);
+                        let result = service.apply_override(over);
+                        if response_to.send(result).is_err() {
+                            log::error!("Failed to send override result message.");
+                        }
+                    }
+                },
+                Message::ExportOverrides { service_uid, response_to } => {
+                    if let Some(service) = service_watchers.get(&service_uid) {
+                        if response_to.send(service.export_overrides()).is_err() {
+                            log::error!("Failed to send overrides export message.");
+                        }
+                    }
+                },
+                Message::ExportDryRun { service_uid, response_to } => {
+                    if let Some(service) = service_watchers.get(&service_uid) {
+                        if response_to.send(service.export_dry_run()).is_err() {
+                            log::error!("Failed to send dry-run export message.");
+                        }
+                    }
+                },
+                Message::ExportEndpoints { service_uid, pod_name, response_to } => {
+                    if let Some(service) = service_watchers.get(&service_uid) {
+                        if response_to.send(service.export_endpoints(&pod_name)).is_err() {
+                            log::error!("Failed to send endpoints export message.");
+                        }
+                    }
+                },
+                Message::NodeChanged { node, info } => {
+                    let changed = match info {
+                        Some(info) => nodes.insert(node.clone(), info.clone()).as_ref() != Some(&info),
+                        None => nodes.remove(&node).is_some()
+                    };
+                    if changed {
+                        debug!("Node {node} changed");
+                        for service in service_watchers.values_mut() {
+                            service.set_nodes(&nodes);
+                        }
+                    }
                 }
             };
 
@@ -74,34 +226,34 @@ pub fn run<T: Policy>(client: Client) -> mpsc::Sender<Message> {
     
     let msg_sender = sender.clone();
     tokio::spawn(async move {
-        graph_export_server(msg_sender).await;
+        export_server::graph_export_server(msg_sender, metrics).await;
     });
 
     sender
 }
 
-async fn graph_export_server(sender: mpsc::Sender<Message>) {
-
-    let mut server = tide::new();
-    server.at("/:path").get(move |request: tide::Request<()>| {
-
-        let sender = sender.clone();
-        async move {
-        
-            let uri = request.url().path_segments()
-                .and_then(|url| url.last())
-                .context("Invalid URL").unwrap().to_string();
-            
-            let uuid = Uuid::from_str(&uri)?;
-            let (s, r) = oneshot::channel::<String>();
-            sender.send(Message::ExportGraph {
-                service_uid: uuid,
-                response_to: s
-            }).await?;
-
-            Ok(r.await?)
+/// Publishes again the endpoints of the `pods` left in a single service,
+/// so they get back the legacy annotation.
+fn republish_single<T: Policy>(service_watchers: &mut HashMap<Uuid, ServiceWatcher<T>>, pod_services: &PodServices, pods: &[Uuid]) {
+    for pod in pods {
+        let services = pod_services.services(*pod);
+        if services.len() != 1 {
+            continue;
         }
-    });
-    server.listen("0.0.0.0:9091").await.expect("HTTP server ended.");
+        if let Some(service) = services.first().and_then(|uid| service_watchers.get_mut(uid)) {
+            service.republish(*pod);
+        }
+    }
+}
+
+/// Feeds a file written with EDGE_CONTROLLER_RECORD_FILE to a loop
+/// started with `Backend::Local`.
+pub async fn replay(path: &Path, speed: f64, sender: mpsc::Sender<Message>) -> anyhow::Result<()> {
+    recording::replay(path, speed, sender).await
+}
 
+/// Sends the services and pods of an inventory file to a loop started
+/// with `Backend::Local`, polling the file every `interval`.
+pub async fn watch_inventory(path: PathBuf, interval: Duration, sender: mpsc::Sender<Message>) {
+    inventory::watch_inventory(path, interval, sender).await
 }
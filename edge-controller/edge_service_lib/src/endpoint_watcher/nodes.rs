@@ -0,0 +1,75 @@
+use super::Message;
+
+use std::collections::BTreeMap;
+use futures::TryStreamExt;
+use k8s_openapi::api::core::v1::Node;
+use kube::runtime::watcher;
+use kube::{Api, Client, ResourceExt};
+use log::{error, info};
+use serde::{Deserialize, Serialize};
+use tokio::sync::mpsc;
+
+/// Pod or node annotation that takes pods out of the endpoints of the
+/// other pods of the service. Pods in maintenance keep their own edges.
+pub const MAINTENANCE_ANNOT: &str = "edgeservices.prueba.ucm.es/maintenance";
+pub const ZONE_LABEL: &str = "topology.kubernetes.io/zone";
+
+/// What the controller needs to know about a node.
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct NodeInfo {
+    #[serde(default)]
+    pub labels: BTreeMap<String, String>,
+    #[serde(default)]
+    pub maintenance: bool
+}
+
+impl NodeInfo {
+
+    fn from_node(node: &Node) -> Self {
+        Self {
+            labels: node.labels().clone(),
+            maintenance: is_in_maintenance(node.annotations())
+        }
+    }
+
+    pub fn zone(&self) -> Option<&str> {
+        self.labels.get(ZONE_LABEL).map(String::as_str)
+    }
+}
+
+pub fn is_in_maintenance(annotations: &BTreeMap<String, String>) -> bool {
+    annotations.get(MAINTENANCE_ANNOT).is_some_and(|value| value.trim() == "true")
+}
+
+/// Watches the nodes of the cluster and sends a `NodeChanged` message
+/// every time a node is seen or deleted.
+pub fn start_node_watcher(client: Client, sender: mpsc::Sender<Message>) {
+
+    let api = Api::<Node>::all(client);
+    tokio::spawn(async move {
+
+        info!("Starting watcher for nodes");
+        let sender = &sender;
+        let result = watcher(api, watcher::Config::default())
+            .try_for_each(|event| async move {
+                let nodes = match event {
+                    watcher::Event::Applied(node) => vec![(node.name_any(), Some(NodeInfo::from_node(&node)))],
+                    watcher::Event::Deleted(node) => vec![(node.name_any(), None)],
+                    watcher::Event::Restarted(nodes) => nodes.iter()
+                        .map(|node| (node.name_any(), Some(NodeInfo::from_node(node))))
+                        .collect()
+                };
+                for (node, info) in nodes {
+                    sender.send(Message::NodeChanged { node, info })
+                        .await
+                        .expect("Failed to send message");
+                }
+                Ok(())
+            })
+            .await;
+
+        if let Err(e) = result {
+            error!("Watcher for nodes ended with error {e}");
+        }
+    });
+}
@@ -0,0 +1,184 @@
+use super::events::{ServiceEvents, REASON_PATCH_FAILED};
+
+use std::collections::{BTreeSet, HashMap};
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+use std::time::Instant;
+use k8s_openapi::api::core::v1::Pod;
+use kube::api::{Patch, PatchParams};
+use kube::{Api, ResourceExt};
+use log::{error, info};
+use serde_json::json;
+use crate::metrics::{ControllerMetrics, PATCH_FAILURE, PATCH_SUCCESS};
+use uuid::Uuid;
+
+pub const ANNOT_NAME: &str = "edgeservices.prueba.ucm.es/endpoints";
+
+/// Annotation with the endpoints of `service_uid`. A pod selected by
+/// several services has one per service.
+pub fn endpoints_annotation(service_uid: Uuid) -> String {
+    format!("{ANNOT_NAME}.{service_uid}")
+}
+
+/// Services whose graph has each pod, shared by the watchers of every
+/// service. Only pods of a single service get the legacy annotation.
+#[derive(Clone, Debug, Default)]
+pub struct PodServices(Arc<Mutex<HashMap<Uuid, BTreeSet<Uuid>>>>);
+
+impl PodServices {
+
+    pub fn join(&self, pod: Uuid, service_uid: Uuid) {
+        self.0.lock().unwrap().entry(pod).or_default().insert(service_uid);
+    }
+
+    pub fn leave(&self, pod: Uuid, service_uid: Uuid) {
+        let mut services = self.0.lock().unwrap();
+        if let Some(pod_services) = services.get_mut(&pod) {
+            pod_services.remove(&service_uid);
+            if pod_services.is_empty() {
+                services.remove(&pod);
+            }
+        }
+    }
+
+    pub fn services(&self, pod: Uuid) -> BTreeSet<Uuid> {
+        self.0.lock().unwrap().get(&pod).cloned().unwrap_or_default()
+    }
+}
+
+/// Where the endpoints computed for each pod are published. Besides
+/// this, the last endpoints of every pod are served on
+/// /:service/endpoints/:pod.
+#[derive(Debug)]
+pub enum Notifier {
+    /// Pod annotation `endpoints.<service uid>`, read by the proxy. Pods
+    /// of a single service also get the legacy `endpoints` annotation,
+    /// for the proxies from before the per-service annotations. It is
+    /// removed from pods of several services.
+    Annotations(Arc<Api<Pod>>),
+    /// One `<service uid>/<pod name>.json` file per pod in the directory.
+    Files(PathBuf),
+    /// Only the HTTP endpoint.
+    Http
+}
+
+impl Notifier {
+
+    /// `shared` is true if the pod is in the graph of other services too.
+    pub fn notify(
+        &self,
+        pod: &Pod,
+        endpoints: String,
+        shared: bool,
+        metrics: &Arc<ControllerMetrics>,
+        events: &ServiceEvents,
+        service_uid: Uuid)
+    {
+        match self {
+            Notifier::Annotations(api) => {
+                let api = Arc::clone(api);
+                let mut annotations = serde_json::Map::new();
+                // Mientras haya proxies sin actualizar se mantiene la anotación
+                // antigua. En un pod de varios servicios no se sabría de cuál es,
+                // así que se borra.
+                let legacy = match shared {
+                    true => serde_json::Value::Null,
+                    false => endpoints.clone().into()
+                };
+                annotations.insert(ANNOT_NAME.to_string(), legacy);
+                annotations.insert(endpoints_annotation(service_uid), endpoints.into());
+                set_annotations(pod, api, annotations, Arc::clone(metrics), events.clone(), service_uid);
+            },
+            Notifier::Files(dir) => {
+                let path = dir.join(service_uid.to_string()).join(format!("{}.json", pod.name_any()));
+                write_endpoints_file(path, endpoints)
+            },
+            Notifier::Http => ()
+        }
+    }
+}
+
+fn write_endpoints_file(path: PathBuf, endpoints: String) {
+    tokio::spawn(async move {
+        if let Some(dir) = path.parent() {
+            if let Err(e) = tokio::fs::create_dir_all(dir).await {
+                error!("Failed to create endpoints directory: {e}");
+                return;
+            }
+        }
+
+        // Se escribe a un fichero temporal y se renombra para que
+        // nadie lea el fichero a medias.
+        let tmp_path = path.with_extension("json.tmp");
+        let result = match tokio::fs::write(&tmp_path, endpoints).await {
+            Ok(_) => tokio::fs::rename(&tmp_path, &path).await,
+            Err(e) => Err(e)
+        };
+        match result {
+            Ok(_) => info!("Wrote endpoints to {}", path.display()),
+            Err(e) => error!("Failed to write endpoints to {}: {e}", path.display())
+        }
+    });
+}
+
+// Adds or changes annotations of a pod, null removes them. Only those
+// annotations are patched, so the annotations of other services are kept.
+fn set_annotations(
+    pod: &Pod,
+    api: Arc<Api<Pod>>,
+    annotations: serde_json::Map<String, serde_json::Value>,
+    metrics: Arc<ControllerMetrics>,
+    events: ServiceEvents,
+    service_uid: Uuid)
+{
+    let pod_name = pod.name_any();
+    let pod = pod.clone();
+    tokio::spawn(async move {
+        let patch = json!({ "metadata": { "annotations": annotations } });
+
+        let start = Instant::now();
+        let err = api.patch_metadata(
+            &pod_name,
+            &PatchParams::default(),
+            &Patch::Merge(&patch)
+        )
+        .await;
+
+        let service = service_uid.to_string();
+        metrics.patch_latency
+            .with_label_values(&[&service])
+            .observe(start.elapsed().as_secs_f64());
+        let result = match err {
+            Ok(_) => { info!("Pod patch applied succesfully."); PATCH_SUCCESS },
+            Err(e) => {
+                error!("Error en apply: {e}");
+                events.warning(REASON_PATCH_FAILED, format!("Failed to patch endpoints of pod {pod_name}: {e}"), Some(&pod));
+                PATCH_FAILURE
+            }
+        };
+        metrics.patches.with_label_values(&[&service, result]).inc();
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use uuid::Uuid;
+    use super::PodServices;
+
+    #[test]
+    fn pod_services_follow_membership() {
+        let (pod, a, b) = (Uuid::from_u128(1), Uuid::from_u128(0xa), Uuid::from_u128(0xb));
+        let pod_services = PodServices::default();
+        pod_services.join(pod, a);
+        assert_eq!(pod_services.services(pod).len(), 1);
+
+        // Los clones comparten el registro, como los watchers de cada servicio.
+        pod_services.clone().join(pod, b);
+        assert_eq!(pod_services.services(pod).len(), 2);
+
+        pod_services.leave(pod, b);
+        assert_eq!(pod_services.services(pod).into_iter().collect::<Vec<_>>(), [a]);
+        pod_services.leave(pod, a);
+        assert!(pod_services.services(pod).is_empty());
+    }
+}
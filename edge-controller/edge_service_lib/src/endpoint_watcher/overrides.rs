@@ -0,0 +1,139 @@
+use std::collections::BTreeSet;
+use std::env;
+use std::path::{Path, PathBuf};
+use anyhow::Result;
+use serde::{Deserialize, Serialize};
+use tokio::{io::AsyncWriteExt, sync::watch};
+use uuid::Uuid;
+
+/// Directorio donde se guardan los overrides de cada servicio
+/// si no se indica otro con EDGE_CONTROLLER_OVERRIDES_DIR.
+const DEFAULT_OVERRIDES_DIR: &str = "./overrides";
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+pub struct Edge {
+    pub from: Uuid,
+    pub to: Uuid
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum OverrideAction {
+    /// Add the edge once. Policies may remove it afterwards.
+    Add,
+    /// Remove the edge once. Policies may add it again afterwards.
+    Remove,
+    /// Keep the edge whenever both pods are in the service.
+    Pin,
+    /// Never allow the edge.
+    Block,
+    Unpin,
+    Unblock
+}
+
+/// Operator request sent to the write endpoints of the export server.
+#[derive(Clone, Copy, Debug, Serialize, Deserialize)]
+pub struct EdgeOverride {
+    pub action: OverrideAction,
+    #[serde(flatten)]
+    pub edge: Edge
+}
+
+/// Edges pinned or blocked by an operator. Persisted per service
+/// so they survive controller restarts.
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+pub struct EdgeOverrides {
+    pub pinned: BTreeSet<Edge>,
+    pub blocked: BTreeSet<Edge>
+}
+
+impl EdgeOverrides {
+
+    pub fn is_pinned(&self, from: Uuid, to: Uuid) -> bool {
+        self.pinned.contains(&Edge { from, to })
+    }
+
+    pub fn is_blocked(&self, from: Uuid, to: Uuid) -> bool {
+        self.blocked.contains(&Edge { from, to })
+    }
+
+    /// Updates the pinned/blocked sets. Add and Remove are one-shot
+    /// and leave the sets untouched.
+    pub fn apply(&mut self, over: &EdgeOverride) {
+        match over.action {
+            OverrideAction::Pin => {
+                self.blocked.remove(&over.edge);
+                self.pinned.insert(over.edge);
+            },
+            OverrideAction::Block => {
+                self.pinned.remove(&over.edge);
+                self.blocked.insert(over.edge);
+            },
+            OverrideAction::Unpin => { self.pinned.remove(&over.edge); },
+            OverrideAction::Unblock => { self.blocked.remove(&over.edge); },
+            OverrideAction::Add | OverrideAction::Remove => ()
+        }
+    }
+
+    pub fn load(service_uid: Uuid) -> Result<Self> {
+        let path = overrides_path(service_uid);
+        if !path.exists() {
+            return Ok(Self::default());
+        }
+
+        let content = std::fs::read_to_string(path)?;
+        Ok(serde_json::from_str(&content)?)
+    }
+}
+
+/// Persists the overrides of a service. A single task writes them, so
+/// the file always ends with the last overrides saved, and each write
+/// goes to a temporary file that replaces the old one, so a crash never
+/// leaves it half written.
+#[derive(Debug)]
+pub struct OverridesWriter {
+    latest: watch::Sender<Option<EdgeOverrides>>
+}
+
+impl OverridesWriter {
+
+    pub fn start(service_uid: Uuid) -> Self {
+        let (latest, mut receiver) = watch::channel(None);
+        let path = overrides_path(service_uid);
+        tokio::spawn(async move {
+            // Si llegan varios cambios durante una escritura solo se
+            // escribe el último.
+            while receiver.changed().await.is_ok() {
+                let Some(overrides) = receiver.borrow_and_update().clone() else { continue };
+                match write_overrides(&path, &overrides).await {
+                    Ok(_) => log::info!("Saved overrides to {}", path.display()),
+                    Err(e) => log::error!("Failed to save overrides to {}: {e}", path.display())
+                }
+            }
+        });
+        Self { latest }
+    }
+
+    pub fn save(&self, overrides: &EdgeOverrides) {
+        self.latest.send_replace(Some(overrides.clone()));
+    }
+}
+
+async fn write_overrides(path: &Path, overrides: &EdgeOverrides) -> Result<()> {
+    if let Some(dir) = path.parent() {
+        tokio::fs::create_dir_all(dir).await?;
+    }
+    let tmp_path = path.with_extension("json.tmp");
+    let mut file = tokio::fs::File::create(&tmp_path).await?;
+    file.write_all(serde_json::to_string_pretty(overrides)?.as_bytes()).await?;
+    file.sync_all().await?;
+    drop(file);
+    tokio::fs::rename(&tmp_path, path).await?;
+    Ok(())
+}
+
+fn overrides_path(service_uid: Uuid) -> PathBuf {
+    let dir = env::var("EDGE_CONTROLLER_OVERRIDES_DIR")
+        .unwrap_or(DEFAULT_OVERRIDES_DIR.to_string());
+    PathBuf::from(dir).join(format!("{service_uid}.json"))
+}
@@ -0,0 +1,134 @@
+use super::{Message, NodeInfo, ServiceInfo};
+use super::history::now_ms;
+
+use std::path::Path;
+use std::time::Duration;
+use anyhow::{Context, Result};
+use k8s_openapi::api::core::v1::Pod;
+use log::{error, info};
+use serde::{Deserialize, Serialize};
+use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+use tokio::sync::mpsc;
+use uuid::Uuid;
+
+/// Message of the endpoint_watcher loop that changes the graphs.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum RecordedEvent {
+    NewService(ServiceInfo),
+    DeleteService { service_uid: Uuid },
+    PodReady { service_uid: Uuid, pod: Pod },
+    PodUnready { service_uid: Uuid, pod: Pod },
+    NodeChanged { node: String, info: Option<NodeInfo> }
+}
+
+/// Line of a recording file.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct RecordEntry {
+    pub timestamp_ms: u64,
+    #[serde(flatten)]
+    pub event: RecordedEvent
+}
+
+impl RecordedEvent {
+
+    fn from_message(msg: &Message) -> Option<Self> {
+        let event = match msg {
+            Message::NewService(info) => Self::NewService(info.clone()),
+            Message::DeleteService { service_uid } => Self::DeleteService { service_uid: *service_uid },
+            Message::PodReady { service_uid, pod } => Self::PodReady { service_uid: *service_uid, pod: pod.clone() },
+            Message::PodUnready { service_uid, pod } => Self::PodUnready { service_uid: *service_uid, pod: pod.clone() },
+            Message::NodeChanged { node, info } => Self::NodeChanged { node: node.clone(), info: info.clone() },
+            _ => return None
+        };
+        Some(event)
+    }
+
+    fn into_message(self) -> Message {
+        match self {
+            Self::NewService(info) => Message::NewService(info),
+            Self::DeleteService { service_uid } => Message::DeleteService { service_uid },
+            Self::PodReady { service_uid, pod } => Message::PodReady { service_uid, pod },
+            Self::PodUnready { service_uid, pod } => Message::PodUnready { service_uid, pod },
+            Self::NodeChanged { node, info } => Message::NodeChanged { node, info }
+        }
+    }
+}
+
+/// Appends the messages processed by the endpoint_watcher loop to a
+/// file, as JSON lines. Writes happen in a separate task.
+#[derive(Debug)]
+pub struct Recorder {
+    sender: mpsc::UnboundedSender<RecordEntry>
+}
+
+impl Recorder {
+
+    pub async fn new(path: &Path) -> Result<Self> {
+
+        let mut file = tokio::fs::OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(path)
+            .await
+            .with_context(|| format!("Failed to open recording file {}", path.display()))?;
+
+        info!("Recording pod events to {}", path.display());
+        let (sender, mut receiver) = mpsc::unbounded_channel::<RecordEntry>();
+        tokio::spawn(async move {
+            while let Some(entry) = receiver.recv().await {
+                let mut line = serde_json::to_vec(&entry).unwrap();
+                line.push(b'\n');
+                if let Err(e) = file.write_all(&line).await {
+                    error!("Failed to write recording: {e}");
+                }
+            }
+        });
+
+        Ok(Self { sender })
+    }
+
+    pub fn record(&self, msg: &Message) {
+        if let Some(event) = RecordedEvent::from_message(msg) {
+            let entry = RecordEntry { timestamp_ms: now_ms(), event };
+            if self.sender.send(entry).is_err() {
+                error!("Recording task ended, event not recorded.");
+            }
+        }
+    }
+}
+
+/// Sends the events of a recording to the endpoint_watcher loop, keeping
+/// the original spacing divided by `speed`. A speed of 0 sends them
+/// without waiting.
+pub async fn replay(path: &Path, speed: f64, sender: mpsc::Sender<Message>) -> Result<()> {
+
+    let file = tokio::fs::File::open(path)
+        .await
+        .with_context(|| format!("Failed to open recording file {}", path.display()))?;
+
+    info!("Replaying {} at speed {speed}", path.display());
+    let mut lines = BufReader::new(file).lines();
+    let mut previous: Option<u64> = None;
+    let mut number = 0;
+    while let Some(line) = lines.next_line().await? {
+        number += 1;
+        if line.trim().is_empty() {
+            continue;
+        }
+
+        let entry: RecordEntry = serde_json::from_str(&line)
+            .with_context(|| format!("Invalid recording entry on line {number}"))?;
+
+        if let Some(previous) = previous.filter(|_| speed > 0.0) {
+            let elapsed = entry.timestamp_ms.saturating_sub(previous);
+            tokio::time::sleep(Duration::from_millis(elapsed).div_f64(speed)).await;
+        }
+        previous = Some(entry.timestamp_ms);
+
+        sender.send(entry.event.into_message()).await?;
+    }
+
+    info!("Replay of {} finished after {number} lines", path.display());
+    Ok(())
+}
@@ -0,0 +1,430 @@
+use super::nodes::{is_in_maintenance, NodeInfo};
+use super::history::{policy_name, GraphHistory, PodEvent, PodEventKind, Trigger, CONTROLLER_CAUSE, OVERRIDE_CAUSE};
+use super::overrides::{EdgeOverride, EdgeOverrides, OverrideAction};
+
+use std::collections::{BTreeMap, BTreeSet};
+use std::collections::btree_map::Entry;
+use anyhow::{anyhow, Context, Result};
+use k8s_openapi::api::core::v1::Pod;
+use kube::ResourceExt;
+use petgraph::Direction;
+use petgraph::dot::{Config, Dot};
+use petgraph::graphmap::DiGraphMap;
+use petgraph::visit::EdgeRef;
+use serde::{Deserialize, Serialize};
+use serde_json::Value as JsonValue;
+use crate::policy::{EdgeWeights, GraphChange, GraphWrapper, PodGraph, PodMap, Policy, PolicyConfig, PolicyWarning};
+use uuid::Uuid;
+
+const HW_ANNOT: &str = "edgeservices.prueba.ucm.es/hw_info";
+/// JSON list with the models that the proxy of the pod can serve.
+const MODELS_ANNOT: &str = "edgeservices.prueba.ucm.es/models";
+/// Container whose first port is the proxy port of the pod.
+pub(crate) const PROXY_CONTAINER: &str = "triton-proxy";
+pub(crate) const DEFAULT_PROXY_PORT: u16 = 9999;
+
+/// Endpoint published to the proxies, with what they need to choose
+/// it without asking the API server.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct Neighbor {
+    pub uuid: Uuid,
+    pub name: String,
+    pub ip: String,
+    #[serde(default = "default_proxy_port")]
+    pub port: u16,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub node_name: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub zone: Option<String>,
+    /// Contents of the hw_info annotation of the pod.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub hw_info: Option<JsonValue>,
+    #[serde(default)]
+    pub models: Vec<String>,
+    /// The pod is in maintenance or terminating.
+    #[serde(default)]
+    pub draining: bool,
+    /// Weight set by the policy for the edge to this neighbour.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub weight: Option<f64>
+}
+
+fn default_proxy_port() -> u16 { DEFAULT_PROXY_PORT }
+
+/// Result of applying an event to the graph of a service.
+#[derive(Debug, Default)]
+pub struct GraphUpdate {
+    /// Pods whose endpoints must be published again, in order.
+    pub notify: Vec<Uuid>,
+    pub warnings: Vec<PolicyWarning>
+}
+
+/// Kind of each edge in the exported graph.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum ExportedEdge {
+    Normal,
+    Pinned,
+    Blocked
+}
+
+/// Graph of a service and the policy that builds it. Knows nothing
+/// about Kubernetes: pods come in, and the pods whose endpoints
+/// changed come out.
+#[derive(Debug)]
+pub struct ServiceGraph<T: Policy> {
+    pods: PodMap,
+    pod_graph: PodGraph,
+    weights: EdgeWeights,
+    policy: T,
+    history: GraphHistory,
+    overrides: EdgeOverrides,
+    /// Nodes of the cluster, by name.
+    nodes: BTreeMap<String, NodeInfo>
+}
+
+impl<T: Policy> ServiceGraph<T> {
+
+    pub fn new(policy: T, overrides: EdgeOverrides) -> Self {
+        Self {
+            pods: PodMap::new(),
+            pod_graph: DiGraphMap::new(),
+            weights: EdgeWeights::new(),
+            policy,
+            history: GraphHistory::new(),
+            overrides,
+            nodes: BTreeMap::new()
+        }
+    }
+
+    pub fn pods(&self) -> &PodMap {
+        &self.pods
+    }
+
+    pub fn graph(&self) -> &PodGraph {
+        &self.pod_graph
+    }
+
+    pub fn overrides(&self) -> &EdgeOverrides {
+        &self.overrides
+    }
+
+    pub fn configure(&mut self, config: &PolicyConfig) -> Result<()> {
+        self.policy.configure(config)
+    }
+
+    /// Adds a new pod or replaces an existing one with updated values.
+    /// Returns error if UID is not valid.
+    pub fn add_pod(&mut self, pod: Pod) -> Result<(PodEventKind, GraphUpdate)> {
+
+        let uid = pod_uid(&pod)?;
+        let mut update = GraphUpdate::default();
+        let kind = if let Entry::Vacant(entry) = self.pods.entry(uid) {
+            let event = Trigger::Pod(PodEvent { kind: PodEventKind::Added, pod: uid, pod_name: pod.name_any() });
+            entry.insert(pod);
+            self.pod_graph.add_node(uid);
+            self.history.record(&event, CONTROLLER_CAUSE, vec![GraphChange::NodeAdded { node: uid }]);
+            update.notify = self.apply_pins(uid, &event);
+
+            let added = self.run_policy(&event, &mut update.warnings, |policy, graph, pods| policy.pod_added(graph, pods, uid));
+            update.notify.extend(added);
+            update.notify.push(uid);
+            PodEventKind::Added
+        }
+        else {
+            let event = Trigger::Pod(PodEvent { kind: PodEventKind::Updated, pod: uid, pod_name: pod.name_any() });
+            let previous = self.descriptor(uid, None);
+            self.pods.insert(uid, pod);
+            if self.descriptor(uid, None) != previous {
+                update.notify = self.incoming(uid);
+                update.notify.push(uid);
+            }
+            let affected = self.run_policy(&event, &mut update.warnings, |policy, graph, pods| policy.pod_updated(graph, pods, uid));
+            update.notify.extend(affected);
+            PodEventKind::Updated
+        };
+
+        Ok((kind, update))
+    }
+
+    /// Returns None if the pod was not in the service, error if UID is not valid.
+    pub fn remove_pod(&mut self, pod: &Pod) -> Result<Option<GraphUpdate>> {
+
+        let uid = pod_uid(pod)?;
+        if self.pods.remove(&uid).is_none() {
+            return Ok(None);
+        }
+
+        let incoming = self.incoming(uid);
+
+        // Remove the node and notify all pods who had connections to it.
+        let event = Trigger::Pod(PodEvent { kind: PodEventKind::Removed, pod: uid, pod_name: pod.name_any() });
+        let mut changes: Vec<GraphChange> = incoming.iter()
+            .map(|from| GraphChange::EdgeRemoved { from: *from, to: uid })
+            .collect();
+        changes.extend(self.pod_graph
+            .neighbors_directed(uid, Direction::Outgoing)
+            .map(|to| GraphChange::EdgeRemoved { from: uid, to })
+        );
+        changes.push(GraphChange::NodeRemoved { node: uid });
+        self.history.record(&event, CONTROLLER_CAUSE, changes);
+        self.pod_graph.remove_node(uid);
+        self.weights.retain(|(from, to), _| *from != uid && *to != uid);
+
+        let mut update = GraphUpdate { notify: incoming.clone(), warnings: Vec::new() };
+        let affected = self.run_policy(&event, &mut update.warnings, |policy, graph, pods| policy.pod_removed(graph, pods, uid, &incoming));
+        update.notify.extend(affected);
+        Ok(Some(update))
+    }
+
+    fn incoming(&self, pod: Uuid) -> Vec<Uuid> {
+        self.pod_graph
+            .edges_directed(pod, Direction::Incoming)
+            .map(|edge| edge.0)
+            .collect()
+    }
+
+    fn node(&self, pod: &Pod) -> Option<&NodeInfo> {
+        let node_name = pod.spec.as_ref()?.node_name.as_ref()?;
+        self.nodes.get(node_name)
+    }
+
+    /// True if the pod or its node are annotated for maintenance.
+    pub fn in_maintenance(&self, pod: Uuid) -> bool {
+        let Some(pod) = self.pods.get(&pod) else { return false };
+        is_in_maintenance(pod.annotations())
+            || self.node(pod).is_some_and(|node| node.maintenance)
+    }
+
+    /// Names of the pods in maintenance.
+    pub fn maintenance(&self) -> BTreeSet<String> {
+        self.pods.iter()
+            .filter(|(uid, _)| self.in_maintenance(**uid))
+            .map(|(_, pod)| pod.name_any())
+            .collect()
+    }
+
+    /// Replaces the nodes of the cluster. The pods whose descriptor
+    /// changed (e.g. their node entered maintenance) are notified, and
+    /// so are the pods with edges to them. The policy sees the pods
+    /// whose node changed as updated.
+    pub fn set_nodes(&mut self, nodes: BTreeMap<String, NodeInfo>) -> GraphUpdate {
+
+        let before: BTreeMap<Uuid, (Option<Neighbor>, Option<NodeInfo>)> = self.pods.iter()
+            .map(|(uid, pod)| (*uid, (self.descriptor(*uid, None), self.node(pod).cloned())))
+            .collect();
+        self.nodes = nodes;
+
+        let mut update = GraphUpdate::default();
+        let mut moved = Vec::new();
+        for (pod, (descriptor, node)) in before {
+            if self.pods.get(&pod).and_then(|pod| self.node(pod)) != node.as_ref() {
+                moved.push(pod);
+            }
+            if self.descriptor(pod, None) == descriptor {
+                continue;
+            }
+            for uid in self.incoming(pod).into_iter().chain([pod]) {
+                if !update.notify.contains(&uid) {
+                    update.notify.push(uid);
+                }
+            }
+        }
+        let reevaluated = self.reevaluate(&moved);
+        update.notify.extend(reevaluated.notify);
+        update.warnings.extend(reevaluated.warnings);
+        update
+    }
+
+    /// Runs `pod_updated` for each of `pods`, e.g. after the policy was
+    /// reconfigured or the nodes of the pods changed.
+    pub fn reevaluate(&mut self, pods: &[Uuid]) -> GraphUpdate {
+
+        let mut update = GraphUpdate::default();
+        for uid in pods {
+            let Some(pod) = self.pods.get(uid) else { continue };
+            let event = Trigger::Pod(PodEvent { kind: PodEventKind::Updated, pod: *uid, pod_name: pod.name_any() });
+            let affected = self.run_policy(&event, &mut update.warnings, |policy, graph, pods| policy.pod_updated(graph, pods, *uid));
+            update.notify.extend(affected);
+        }
+        update
+    }
+
+    /// Runs a policy callback, recording its changes in the history.
+    fn run_policy<F>(&mut self, event: &Trigger, warnings: &mut Vec<PolicyWarning>, callback: F) -> Vec<Uuid>
+    where F: FnOnce(&mut T, &mut GraphWrapper, &PodMap) -> Vec<Uuid>
+    {
+        let mut wrapper = GraphWrapper::new(&mut self.pod_graph, &mut self.weights, &self.overrides, &self.nodes);
+        let affected = callback(&mut self.policy, &mut wrapper, &self.pods);
+        let (changes, reported) = wrapper.into_parts();
+        self.history.record(event, policy_name::<T>(), changes);
+        warnings.extend(reported);
+        affected
+    }
+
+    /// Adds the pinned edges between `pod` and the pods already in the
+    /// service. Returns the pods whose endpoints changed.
+    fn apply_pins(&mut self, pod: Uuid, event: &Trigger) -> Vec<Uuid> {
+
+        let mut changes = Vec::new();
+        for edge in self.overrides.pinned.iter() {
+            if (edge.from == pod || edge.to == pod)
+                && self.pod_graph.contains_node(edge.from)
+                && self.pod_graph.contains_node(edge.to)
+                && self.pod_graph.add_edge(edge.from, edge.to, ()).is_none()
+            {
+                changes.push(GraphChange::EdgeAdded { from: edge.from, to: edge.to });
+            }
+        }
+
+        let affected = changes.iter()
+            .filter_map(|change| match change {
+                GraphChange::EdgeAdded { from, .. } => Some(*from),
+                _ => None
+            })
+            .collect();
+        self.history.record(event, OVERRIDE_CAUSE, changes);
+        affected
+    }
+
+    /// Applies an operator override to the graph and the pinned/blocked edges.
+    pub fn apply_override(&mut self, over: EdgeOverride) -> Result<GraphUpdate> {
+
+        let (from, to) = (over.edge.from, over.edge.to);
+        let both_present = self.pod_graph.contains_node(from) && self.pod_graph.contains_node(to);
+        let change = match over.action {
+            OverrideAction::Add if self.overrides.is_blocked(from, to) => {
+                return Err(anyhow!("Edge {from} -> {to} is blocked"));
+            },
+            OverrideAction::Remove if self.overrides.is_pinned(from, to) => {
+                return Err(anyhow!("Edge {from} -> {to} is pinned"));
+            },
+            OverrideAction::Add | OverrideAction::Remove if !both_present => {
+                return Err(anyhow!("Pods {from} and {to} are not both in the service"));
+            },
+            OverrideAction::Add | OverrideAction::Pin if both_present => {
+                self.pod_graph.add_edge(from, to, ())
+                    .is_none()
+                    .then_some(GraphChange::EdgeAdded { from, to })
+            },
+            OverrideAction::Remove | OverrideAction::Block if both_present => {
+                self.weights.remove(&(from, to));
+                self.pod_graph.remove_edge(from, to)
+                    .map(|_| GraphChange::EdgeRemoved { from, to })
+            },
+            _ => None
+        };
+
+        self.overrides.apply(&over);
+        let mut update = GraphUpdate::default();
+        if let Some(change) = change {
+            self.history.record(&Trigger::Override(over), OVERRIDE_CAUSE, vec![change]);
+            update.notify.push(from);
+        }
+        Ok(update)
+    }
+
+    /// Endpoints published to `pod`: its outgoing neighbours not in
+    /// maintenance and itself.
+    pub fn neighbors(&self, pod: Uuid) -> Option<Vec<Neighbor>> {
+
+        let mut neighbors: Vec<Neighbor> = self.pod_graph.neighbors_directed(pod, Direction::Outgoing)
+            .filter(|uid| !self.in_maintenance(*uid))
+            .flat_map(|uid| self.descriptor(uid, self.weights.get(&(pod, uid)).copied()))
+            .collect();
+
+        // Añadirse a si mismo como vecino.
+        neighbors.push(self.descriptor(pod, self.weights.get(&(pod, pod)).copied())?);
+        Some(neighbors)
+    }
+
+    /// How `pod` is published to the pods with edges to it.
+    fn descriptor(&self, uid: Uuid, weight: Option<f64>) -> Option<Neighbor> {
+
+        let pod = self.pods.get(&uid)?;
+        let node = self.node(pod);
+        let annotations = pod.annotations();
+        Some(Neighbor {
+            uuid: uid,
+            name: pod.name_any(),
+            ip: pod.status.as_ref()?.pod_ip.clone()?,
+            port: proxy_port(pod),
+            node_name: pod.spec.as_ref().and_then(|spec| spec.node_name.clone()),
+            zone: node.and_then(|node| node.zone()).map(str::to_string),
+            hw_info: annotations.get(HW_ANNOT).and_then(|hw_info| serde_json::from_str(hw_info).ok()),
+            models: annotations.get(MODELS_ANNOT)
+                .and_then(|models| serde_json::from_str(models).ok())
+                .unwrap_or_default(),
+            draining: self.in_maintenance(uid) || pod.metadata.deletion_timestamp.is_some(),
+            weight
+        })
+    }
+
+    pub fn export_overrides(&self) -> String {
+        serde_json::to_string_pretty(&self.overrides).unwrap()
+    }
+
+    /// DOT graph of the service. Pinned edges are drawn in bold blue,
+    /// blocked edges between pods in the service in dashed red and pods
+    /// in maintenance filled in orange.
+    pub fn export_graph(&self) -> String {
+
+        let mut graph: DiGraphMap<Uuid, ExportedEdge> = DiGraphMap::new();
+        for node in self.pod_graph.nodes() {
+            graph.add_node(node);
+        }
+        for (from, to, _) in self.pod_graph.all_edges() {
+            let kind = if self.overrides.is_pinned(from, to) { ExportedEdge::Pinned } else { ExportedEdge::Normal };
+            graph.add_edge(from, to, kind);
+        }
+        for edge in self.overrides.blocked.iter() {
+            if graph.contains_node(edge.from) && graph.contains_node(edge.to) {
+                graph.add_edge(edge.from, edge.to, ExportedEdge::Blocked);
+            }
+        }
+
+        let edge_attrs = |_, edge: (Uuid, Uuid, &ExportedEdge)| match edge.weight() {
+            ExportedEdge::Normal => String::new(),
+            ExportedEdge::Pinned => "color = \"blue\" style = \"bold\"".to_string(),
+            ExportedEdge::Blocked => "color = \"red\" style = \"dashed\"".to_string()
+        };
+        let node_attrs = |_, (uid, _): (Uuid, &Uuid)| match self.in_maintenance(uid) {
+            true => "style = \"filled\" fillcolor = \"orange\"".to_string(),
+            false => String::new()
+        };
+        let dot = Dot::with_attr_getters(&graph, &[Config::EdgeNoLabel], &edge_attrs, &node_attrs);
+        format!("{:?}", dot)
+    }
+
+    /// JSON list of the graph changes recorded between `from` and `to` (ms since epoch).
+    pub fn export_history(&self, from: Option<u64>, to: Option<u64>) -> String {
+        let entries = self.history.range(from, to);
+        serde_json::to_string_pretty(&entries).unwrap()
+    }
+
+    /// The graph as it was at instant `at` (ms since epoch), if still in the history.
+    pub fn export_snapshot(&self, at: u64) -> Option<String> {
+        self.history.snapshot_at(at)
+            .map(|graph| graph_to_dot(&graph))
+    }
+}
+
+pub fn graph_to_dot(graph: &PodGraph) -> String {
+    let dot = Dot::with_config(graph, &[Config::EdgeNoLabel]);
+    format!("{:?}", dot)
+}
+
+fn proxy_port(pod: &Pod) -> u16 {
+    pod.spec.as_ref()
+        .and_then(|spec| spec.containers.iter().find(|container| container.name == PROXY_CONTAINER))
+        .and_then(|container| container.ports.as_ref()?.first())
+        .and_then(|port| u16::try_from(port.container_port).ok())
+        .unwrap_or(DEFAULT_PROXY_PORT)
+}
+
+pub fn pod_uid(pod: &Pod) -> Result<Uuid> {
+    let uid = Uuid::parse_str(pod.metadata
+        .uid.as_ref()
+        .context("Pod missing UID")?
+    )?;
+    Ok(uid)
+}
@@ -1,46 +1,69 @@
-use super::Message;
+use super::{Backend, Message, NodeInfo, ServiceInfo};
+use super::events::{ServiceEvents, REASON_POD_JOINED, REASON_POD_LEFT, REASON_POLICY_CONFIG_INVALID};
+use super::history::PodEventKind;
+use super::notifier::{endpoints_annotation, Notifier, PodServices};
+use super::overrides::{Edge, EdgeOverride, EdgeOverrides, OverrideAction, OverridesWriter};
+use super::service_graph::{pod_uid, GraphUpdate, Neighbor, ServiceGraph};
 
-use std::collections::BTreeMap;
-use std::str::FromStr;
+use std::collections::{BTreeMap, BTreeSet};
 use std::sync::Arc;
-use anyhow::{Context, Result};
+use anyhow::Result;
 use futures::TryStreamExt;
 use k8s_openapi::api::core::v1::Pod;
-use k8s_openapi::Metadata;
-use kube::api::{ObjectMeta, PartialObjectMetaExt, Patch, PatchParams};
 use kube::runtime::{watcher, WatchStreamExt};
+use kube::api::{Patch, PatchParams};
 use kube::{Api, Client, ResourceExt};
 use log::{error, info};
-use petgraph::Direction;
-use petgraph::dot::{Config, Dot};
 use serde::Serialize;
+use serde_json::json;
 use tokio::{sync::mpsc, task::JoinHandle};
-use petgraph::graphmap::DiGraphMap;
-use crate::policy::{GraphWrapper, Policy};
+use crate::controller::EdgeService;
+use crate::metrics::ControllerMetrics;
+use crate::policy::{Policy, PolicyConfig};
 use uuid::Uuid;
 
 const LABEL_NAME: &str = "edgeservices.prueba.ucm.es";
-const ANNOT_NAME: &str = "edgeservices.prueba.ucm.es/endpoints";
-
-#[derive(Clone, Debug, Serialize)]
-pub struct Neighbor {
-    uuid: Uuid,
-    name: String,
-    ip: String,
-}
 
 #[derive(Debug)]
 pub struct ServiceWatcher<T: Policy> {
-    pod_graph: DiGraphMap<Uuid, ()>,
-    pods: BTreeMap<Uuid, Pod>,
-    api: Arc<Api<Pod>>,
-    watcher_handle: JoinHandle<Result<(), watcher::Error>>  ,
-    policy: T
+    service_uid: Uuid,
+    graph: ServiceGraph<T>,
+    overrides_writer: OverridesWriter,
+    notifier: Notifier,
+    pod_services: PodServices,
+    /// None if pods are not watched through Kubernetes.
+    watcher_handle: Option<JoinHandle<Result<(), watcher::Error>>>,
+    metrics: Arc<ControllerMetrics>,
+    events: ServiceEvents,
+    policy_config: PolicyConfig,
+    /// Si es true, se calcula el grafo pero no se parchean los pods.
+    dry_run: bool,
+    /// Endpoints annotations withheld while in dry-run, by pod.
+    pending: BTreeMap<Uuid, String>,
+    /// Last endpoints published to each pod.
+    published: BTreeMap<Uuid, String>,
+    /// EdgeService whose status shows the pods in maintenance, if any.
+    status: Option<(Api<EdgeService>, String)>,
+    /// Pods in maintenance last written to the status.
+    maintenance: BTreeSet<String>
+}
+
+#[derive(Debug, Serialize)]
+struct DryRunDiff<'a> {
+    dry_run: bool,
+    added: Vec<Edge>,
+    removed: Vec<Edge>,
+    annotations: &'a BTreeMap<Uuid, String>
 }
 
 impl<T: Policy> Drop for ServiceWatcher<T> {
     fn drop(&mut self) {
-        self.watcher_handle.abort();
+        if let Some(handle) = &self.watcher_handle {
+            handle.abort();
+        }
+        for uid in self.graph.pods().keys() {
+            self.pod_services.leave(*uid, self.service_uid);
+        }
         info!("Stopped watcher for deleted service.");
     }
 }
@@ -49,113 +72,273 @@ type MsgSender = mpsc::Sender<Message>;
 impl<T: Policy> ServiceWatcher<T> {
 
     pub async fn new(
-        service_uid: Uuid,
-        client: Client, 
+        info: ServiceInfo,
+        backend: &Backend, 
         msg_sender: MsgSender, 
-        namespace: &str, 
-        selector: String) -> Self 
+        pod_services: PodServices,
+        metrics: Arc<ControllerMetrics>) -> Self 
     {
-        let watcher_handle = start_watcher(service_uid, client.clone(), namespace, selector, msg_sender);
+        let service_uid = info.uid;
+        let status = match (backend, &info.reference.name) {
+            (Backend::Kubernetes(client), Some(name)) => Some((Api::namespaced(client.clone(), &info.namespace), name.clone())),
+            _ => None
+        };
+        let (notifier, watcher_handle, events) = match backend {
+            Backend::Kubernetes(client) => {
+                let handle = start_watcher(service_uid, client.clone(), &info.namespace, info.selector, msg_sender);
+                let api = Api::namespaced(client.clone(), &info.namespace);
+                (Notifier::Annotations(Arc::new(api)), Some(handle), ServiceEvents::new(client.clone(), info.reference))
+            },
+            Backend::Local { endpoints_dir: Some(dir) } => (Notifier::Files(dir.clone()), None, ServiceEvents::disabled()),
+            Backend::Local { endpoints_dir: None } => (Notifier::Http, None, ServiceEvents::disabled())
+        };
+        let overrides = EdgeOverrides::load(service_uid).unwrap_or_else(|e| {
+            error!("Failed to load overrides for service {service_uid}: {e}");
+            EdgeOverrides::default()
+        });
+
+        let policy_config = PolicyConfig { service_uid, script: info.script, rules: info.rules };
+        let mut graph = ServiceGraph::new(T::default().await, overrides);
+        if let Err(e) = graph.configure(&policy_config) {
+            error!("Failed to configure policy for service {service_uid}: {e:#}");
+            events.warning(REASON_POLICY_CONFIG_INVALID, format!("{e:#}"), None);
+        }
+
         Self {
-            pod_graph: DiGraphMap::new(),
-            pods: BTreeMap::new(),
-            api: Arc::new(Api::namespaced(client, namespace)),
+            service_uid,
+            graph,
+            overrides_writer: OverridesWriter::start(service_uid),
+            policy_config,
+            notifier,
+            pod_services,
             watcher_handle,
-            policy: T::default().await
+            metrics,
+            events,
+            dry_run: info.dry_run,
+            pending: BTreeMap::new(),
+            published: BTreeMap::new(),
+            status,
+            maintenance: BTreeSet::new()
         }
     }
 
     /// Returns error if UID is not valid.
     pub fn add_pod(&mut self, pod: Pod) -> Result<()> {
 
-        let uid = Uuid::parse_str(&pod.metadata()
-            .uid.as_ref()
-            .context("Pod missing UID")?
-        )?;
-
-        let affected = if !self.pods.contains_key(&uid) {
-            self.pods.insert(uid, pod);
-            self.pod_graph.add_node(uid);
-            let mut wrapper = GraphWrapper::new(&mut self.pod_graph);
-            let mut affected = self.policy.pod_added(&mut wrapper, &self.pods, uid);
-            affected.push(uid);
-            affected
+        let uid = pod_uid(&pod)?;
+        let (kind, update) = self.graph.add_pod(pod)?;
+        self.count_event(kind);
+        if let (PodEventKind::Added, Some(pod)) = (kind, self.graph.pods().get(&uid)) {
+            self.pod_services.join(uid, self.service_uid);
+            self.events.normal(REASON_POD_JOINED, format!("Pod {} joined the graph", pod.name_any()), Some(pod));
+            // Tras reiniciar el controlador, lo publicado es lo que ya tiene el pod.
+            if let (Notifier::Annotations(_), Some(endpoints)) = (&self.notifier, pod.annotations().get(&endpoints_annotation(self.service_uid))) {
+                self.published.entry(uid).or_insert_with(|| endpoints.clone());
+            }
         }
-        else {
-            // Replace pod with updated values.
-            self.pods.insert(uid, pod);
-            let mut wrapper = GraphWrapper::new(&mut self.pod_graph);
-            self.policy.pod_updated(&mut wrapper, &self.pods, uid)
-        };
-        
-        self.notify_pods(&affected);
+
+        self.apply_update(update);
         Ok(())
     }
 
     /// Returns error if UID is not valid.
     pub fn remove_pod(&mut self, pod: Pod) -> Result<()> {
-        
-        let uid = Uuid::parse_str(pod.metadata()
-            .uid.as_ref()
-            .context("Pod missing UID")?
-        )?;
-
-        if self.pods.contains_key(&uid) {
-            self.pods.remove(&uid);
-            let incoming: Vec<Uuid> = self.pod_graph
-                .edges_directed(uid, Direction::Incoming)
-                .map(|edge| edge.0)
-                .collect();
-            
-            // Remove the node and notify all pods who had connections to it. 
-            self.pod_graph.remove_node(uid);
-            self.notify_pods(&incoming); 
-            
-            let mut wrapper = GraphWrapper::new(&mut self.pod_graph);
-            let affected = self.policy.pod_removed(&mut wrapper, &self.pods, uid, &incoming);
-            self.notify_pods(&affected);
+
+        if let Some(update) = self.graph.remove_pod(&pod)? {
+            if let Ok(uid) = pod_uid(&pod) {
+                self.pending.remove(&uid);
+                self.published.remove(&uid);
+                self.pod_services.leave(uid, self.service_uid);
+            }
+            self.count_event(PodEventKind::Removed);
+            self.events.normal(REASON_POD_LEFT, format!("Pod {} left the graph", pod.name_any()), Some(&pod));
+            self.apply_update(update);
         }
 
         Ok(())
     }
 
-    fn notify_pods(&self, pods: &[Uuid]) {
-        for pod in pods {
-            self.notify_pod(&self.pods.get(&pod).unwrap());
+    /// Publishes the warnings reported by the policy and the endpoints
+    /// of the affected pods.
+    fn apply_update(&mut self, update: GraphUpdate) {
+
+        for warning in update.warnings {
+            let pod = warning.pod.and_then(|uid| self.graph.pods().get(&uid));
+            self.events.warning(&warning.reason, warning.message, pod);
+        }
+        self.notify_pods(&update.notify);
+        self.update_graph_metrics();
+        self.update_maintenance_status();
+    }
+
+    /// Publishes the endpoints of `pod` again, e.g. when it leaves the
+    /// other services and gets back the legacy annotation.
+    pub fn republish(&mut self, pod: Uuid) {
+        self.notify_pods(&[pod]);
+    }
+
+    /// Pods in the graph of the service.
+    pub fn pod_uids(&self) -> Vec<Uuid> {
+        self.graph.pods().keys().copied().collect()
+    }
+
+    /// Applies the current nodes of the cluster to the graph.
+    pub fn set_nodes(&mut self, nodes: &BTreeMap<String, NodeInfo>) {
+        let update = self.graph.set_nodes(nodes.clone());
+        self.apply_update(update);
+    }
+
+    /// Writes the pods in maintenance to the EdgeService status when they change.
+    fn update_maintenance_status(&mut self) {
+
+        let maintenance = self.graph.maintenance();
+        if maintenance == self.maintenance {
+            return;
+        }
+
+        info!("Pods in maintenance for service {}: {:?}", self.service_uid, maintenance);
+        self.maintenance = maintenance;
+        let Some((api, name)) = self.status.clone() else { return };
+        let patch = json!({ "status": { "maintenance": self.maintenance } });
+        tokio::spawn(async move {
+            if let Err(e) = api.patch_status(&name, &PatchParams::default(), &Patch::Merge(&patch)).await {
+                error!("Failed to update status of service {name}: {e}");
+            }
+        });
+    }
+
+    /// Applies an operator override and persists the pinned/blocked edges.
+    pub fn apply_override(&mut self, over: EdgeOverride) -> Result<()> {
+
+        let persistent = !matches!(over.action, OverrideAction::Add | OverrideAction::Remove);
+        let update = self.graph.apply_override(over)?;
+        if persistent {
+            self.overrides_writer.save(self.graph.overrides());
+        }
+
+        self.apply_update(update);
+        Ok(())
+    }
+
+    pub fn export_overrides(&self) -> String {
+        self.graph.export_overrides()
+    }
+
+    fn count_event(&self, kind: PodEventKind) {
+        self.metrics.pod_events
+            .with_label_values(&[&self.service_uid.to_string(), kind.as_str()])
+            .inc();
+    }
+
+    fn update_graph_metrics(&self) {
+        let service = self.service_uid.to_string();
+        self.metrics.pods.with_label_values(&[&service]).set(self.graph.pods().len() as i64);
+        self.metrics.edges.with_label_values(&[&service]).set(self.graph.graph().edge_count() as i64);
+    }
+
+    fn notify_pods(&mut self, pods: &[Uuid]) {
+        for uid in pods {
+            let Some(pod) = self.graph.pods().get(uid) else { continue };
+            let Some(neighbors) = self.graph.neighbors(*uid) else { continue };
+            let neighbor_string = serde_json::to_string_pretty(&neighbors).unwrap();
+            if self.dry_run {
+                info!("[dry-run] Endpoints for pod {}: {neighbor_string}", pod.name_any());
+                self.pending.insert(*uid, neighbor_string);
+            }
+            else {
+                let shared = self.pod_services.services(*uid).len() > 1;
+                self.notifier.notify(pod, neighbor_string.clone(), shared, &self.metrics, &self.events, self.service_uid);
+                self.published.insert(*uid, neighbor_string);
+            }
+        }
+    }
+
+    /// Applies a new spec of the service.
+    pub fn update(&mut self, info: &ServiceInfo) {
+
+        let config = PolicyConfig { service_uid: self.service_uid, script: info.script.clone(), rules: info.rules.clone() };
+        if config != self.policy_config {
+            info!("Reconfiguring policy for service {}", self.service_uid);
+            match self.graph.configure(&config) {
+                Ok(_) => {
+                    // El grafo se recalcula con la nueva configuración.
+                    let pods: Vec<Uuid> = self.graph.pods().keys().copied().collect();
+                    let update = self.graph.reevaluate(&pods);
+                    self.apply_update(update);
+                },
+                Err(e) => {
+                    error!("Failed to configure policy for service {}: {e:#}", self.service_uid);
+                    self.events.warning(REASON_POLICY_CONFIG_INVALID, format!("{e:#}"), None);
+                }
+            }
+            self.policy_config = config;
         }
+        self.set_dry_run(info.dry_run);
     }
 
-    fn notify_pod(&self, pod: &Pod) {
+    /// Enables or disables dry-run. When leaving dry-run, the computed
+    /// endpoints are applied to every pod.
+    fn set_dry_run(&mut self, dry_run: bool) {
         
-        let pod_uuid = Uuid::from_str(&pod.metadata.uid.as_ref().unwrap()).unwrap();
-        let mut neighbors: Vec<Neighbor> = self.pod_graph.neighbors_directed(pod_uuid, Direction::Outgoing)
-            .flat_map(|uid| {
-
-                let pod = self.pods.get(&uid).unwrap();
-                let uuid = Uuid::parse_str(&pod.metadata.uid.as_ref()?).ok()?;
-                let ip = pod.status.as_ref()?.pod_ip.clone()?;
-                Some(Neighbor {
-                    name: pod.name_any(),
-                    uuid,
-                    ip,
-                })
+        if self.dry_run == dry_run {
+            return;
+        }
+
+        info!("Dry-run for service {} set to {dry_run}", self.service_uid);
+        self.dry_run = dry_run;
+        self.pending.clear();
+        if !dry_run {
+            let pods: Vec<Uuid> = self.graph.pods().keys().copied().collect();
+            self.notify_pods(&pods);
+        }
+    }
+
+    /// Differences between the computed graph and the endpoints last
+    /// published to the pods, plus the annotations that dry-run withheld.
+    pub fn export_dry_run(&self) -> String {
+
+        // Lo publicado sirve con cualquier notificador, no solo con anotaciones.
+        let applied: BTreeSet<Edge> = self.published.iter()
+            .flat_map(|(uid, endpoints)| {
+                let neighbors: Vec<Neighbor> = serde_json::from_str(endpoints).unwrap_or_default();
+
+                neighbors.into_iter()
+                    .filter(|neighbor| neighbor.uuid != *uid)
+                    .map(|neighbor| Edge { from: *uid, to: neighbor.uuid })
+                    .collect::<Vec<_>>()
             })
             .collect();
-        
-        // Añadirse a si mismo como vecino.
-        neighbors.push(Neighbor {
-            uuid: pod_uuid,
-            name: pod.name_any(),
-            ip: pod.status.as_ref().unwrap().pod_ip.clone().unwrap()
-        });
-        let neighbor_string = serde_json::to_string_pretty(&neighbors).unwrap(); 
-        let api = Arc::clone(&self.api);
-        set_annotation(pod, api, ANNOT_NAME, neighbor_string);
+
+        let computed: BTreeSet<Edge> = self.graph.graph().all_edges()
+            .map(|(from, to, _)| Edge { from, to })
+            .collect();
+
+        let diff = DryRunDiff {
+            dry_run: self.dry_run,
+            added: computed.difference(&applied).copied().collect(),
+            removed: applied.difference(&computed).copied().collect(),
+            annotations: &self.pending
+        };
+        serde_json::to_string_pretty(&diff).unwrap()
+    }
+
+    /// Last endpoints published to the pod with name `pod_name`.
+    pub fn export_endpoints(&self, pod_name: &str) -> Option<String> {
+        let (uid, _) = self.graph.pods().iter()
+            .find(|(_, pod)| pod.name_any() == pod_name)?;
+        self.published.get(uid).cloned()
     }
 
     pub fn export_graph(&self) -> String {
-        let dot = Dot::with_config(&self.pod_graph, &[Config::EdgeNoLabel]);
-        format!("{:?}", dot)
+        self.graph.export_graph()
+    }
+
+    pub fn export_history(&self, from: Option<u64>, to: Option<u64>) -> String {
+        self.graph.export_history(from, to)
+    }
+
+    pub fn export_snapshot(&self, at: u64) -> Option<String> {
+        self.graph.export_snapshot(at)
     }
 }
 
@@ -211,29 +394,80 @@ fn pod_readiness(pod: &Pod) -> Option<bool> {
     Some(readiness)
 }
 
-// Adds or changes an annotation for a pod
-fn set_annotation(pod: &Pod, api: Arc<Api<Pod>>, annotation_name: &str, annotation_value: String) {
-    let pod_name = pod.name_any();
-    let mut annotations = pod.annotations().clone();
-    let annotation_name = annotation_name.to_string();
-    tokio::spawn(async move {
-        annotations.insert(annotation_name, annotation_value);
-
-        let meta = ObjectMeta {
-            annotations: Some(annotations),
-            ..Default::default()
-        }.into_request_partial::<Pod>();
-
-        let err = api.patch_metadata(
-            &pod_name,
-            &PatchParams::apply("tservice-controller"),
-            &Patch::Apply(meta)
-        )
-        .await;
-
-        match err {
-            Ok(_) => info!("Pod patch applied succesfully."),
-            Err(e) => error!("Error en apply: {e}")
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+    use k8s_openapi::api::core::v1::ObjectReference;
+    use serde_json::Value as JsonValue;
+    use tokio::sync::mpsc;
+    use uuid::Uuid;
+    use crate::metrics::ControllerMetrics;
+    use crate::policy::{AsyncDefault, GraphWrapper, PodMap, Policy};
+    use crate::simulator::TraceEvent;
+    use super::{Backend, PodServices, ServiceInfo, ServiceWatcher};
+
+    /// Conecta cada pod nuevo con todos los demás.
+    #[derive(Debug)]
+    struct Mesh;
+
+    impl AsyncDefault for Mesh {
+        async fn default() -> Self { Mesh }
+    }
+
+    impl Policy for Mesh {
+        fn pod_added(&mut self, graph: &mut GraphWrapper, _pods: &PodMap, pod: Uuid) -> Vec<Uuid> {
+            let others: Vec<Uuid> = graph.nodes().filter(|node| *node != pod).collect();
+            for other in others.iter() {
+                graph.add_edge(pod, *other);
+                graph.add_edge(*other, pod);
+            }
+            others
+        }
+        fn pod_removed(&mut self, _graph: &mut GraphWrapper, _pods: &PodMap, _pod: Uuid, _affected: &[Uuid]) -> Vec<Uuid> { Vec::new() }
+        fn pod_updated(&mut self, _graph: &mut GraphWrapper, _pods: &PodMap, _pod: Uuid) -> Vec<Uuid> { Vec::new() }
+    }
+
+    fn info(dry_run: bool) -> ServiceInfo {
+        ServiceInfo {
+            uid: Uuid::from_u128(0x5e),
+            reference: ObjectReference::default(),
+            namespace: "default".to_string(),
+            selector: "test".to_string(),
+            dry_run,
+            script: None,
+            rules: None
         }
-    });
+    }
+
+    fn pod(uid: u128) -> TraceEvent {
+        serde_json::from_value(serde_json::json!({
+            "uid": Uuid::from_u128(uid), "name": format!("pod-{uid}"), "ip": format!("10.0.0.{uid}"), "ready": true
+        }))
+        .unwrap()
+    }
+
+    fn diff(watcher: &ServiceWatcher<Mesh>) -> (usize, usize) {
+        let diff: JsonValue = serde_json::from_str(&watcher.export_dry_run()).unwrap();
+        (diff["added"].as_array().unwrap().len(), diff["removed"].as_array().unwrap().len())
+    }
+
+    #[tokio::test]
+    async fn dry_run_diff_uses_published_endpoints() {
+        let (sender, _receiver) = mpsc::channel(1);
+        let metrics = Arc::new(ControllerMetrics::new().unwrap());
+        let backend = Backend::Local { endpoints_dir: None };
+        let mut watcher = ServiceWatcher::<Mesh>::new(info(true), &backend, sender, PodServices::default(), metrics).await;
+
+        watcher.add_pod(pod(1).to_pod()).unwrap();
+        watcher.add_pod(pod(2).to_pod()).unwrap();
+        assert_eq!(diff(&watcher), (2, 0));
+
+        // Sin anotaciones (solo HTTP) lo publicado cuenta como aplicado.
+        watcher.update(&info(false));
+        assert_eq!(diff(&watcher), (0, 0));
+
+        watcher.update(&info(true));
+        watcher.remove_pod(pod(2).to_pod()).unwrap();
+        assert_eq!(diff(&watcher), (0, 1));
+    }
 }
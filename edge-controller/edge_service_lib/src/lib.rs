@@ -1,12 +1,42 @@
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::Duration;
+use endpoint_watcher::Backend;
 use kube::Client;
+use metrics::ControllerMetrics;
 use policy::Policy;
 
+pub mod admission;
 mod controller;
 mod endpoint_watcher;
+mod metrics;
 pub mod policy;
+pub mod simulator;
 
 pub fn run<T: Policy>(client: Client) {
 
-    let msg_sender = endpoint_watcher::run::<T>(client.clone());
-    controller::run(client.clone(), msg_sender.clone());
+    let metrics = Arc::new(ControllerMetrics::new().expect("Failed to register controller metrics."));
+    let msg_sender = endpoint_watcher::run::<T>(Backend::Kubernetes(client.clone()), Arc::clone(&metrics));
+    controller::run(client.clone(), msg_sender.clone(), metrics);
+}
+
+/// Reproduces a recording made with EDGE_CONTROLLER_RECORD_FILE without
+/// a cluster. The graphs stay available on the export server once the
+/// replay ends.
+pub async fn replay<T: Policy>(path: &Path, speed: f64) -> anyhow::Result<()> {
+
+    let metrics = Arc::new(ControllerMetrics::new()?);
+    let msg_sender = endpoint_watcher::run::<T>(Backend::Local { endpoints_dir: None }, metrics);
+    endpoint_watcher::replay(path, speed, msg_sender).await
+}
+
+/// Runs the controller without Kubernetes: services and pods come from
+/// the `inventory` file, and endpoints are written as files to
+/// `endpoints_dir` (if any) and served on /:service/endpoints/:pod.
+pub async fn run_local<T: Policy>(inventory: PathBuf, endpoints_dir: Option<PathBuf>, interval: Duration) -> anyhow::Result<()> {
+
+    let metrics = Arc::new(ControllerMetrics::new()?);
+    let msg_sender = endpoint_watcher::run::<T>(Backend::Local { endpoints_dir }, metrics);
+    endpoint_watcher::watch_inventory(inventory, interval, msg_sender).await;
+    Ok(())
 }
@@ -0,0 +1,129 @@
+use prometheus::{
+    Encoder, HistogramOpts, HistogramVec, IntCounterVec, IntGauge, IntGaugeVec, Opts, Registry, TextEncoder
+};
+use crate::endpoint_watcher::PodEventKind;
+
+const NAMESPACE: &str = "edge_controller";
+/// Values of the `result` label of the annotation patches.
+pub const PATCH_SUCCESS: &str = "success";
+pub const PATCH_FAILURE: &str = "failure";
+
+/// Operational metrics of the controller, served on /metrics.
+/// Every metric except the channel depth is labelled with the
+/// UID of the EdgeService it refers to.
+#[derive(Debug)]
+pub struct ControllerMetrics {
+    registry: Registry,
+    pub pods: IntGaugeVec,
+    pub edges: IntGaugeVec,
+    pub pod_events: IntCounterVec,
+    pub patches: IntCounterVec,
+    pub patch_latency: HistogramVec,
+    pub reconcile_errors: IntCounterVec,
+    pub channel_depth: IntGauge
+}
+
+impl ControllerMetrics {
+
+    pub fn new() -> prometheus::Result<Self> {
+
+        let registry = Registry::new();
+        let pods = IntGaugeVec::new(
+            Opts::new("pods", "Pods tracked in the graph of the service.").namespace(NAMESPACE),
+            &["service"]
+        )?;
+        let edges = IntGaugeVec::new(
+            Opts::new("edges", "Edges in the graph of the service.").namespace(NAMESPACE),
+            &["service"]
+        )?;
+        let pod_events = IntCounterVec::new(
+            Opts::new("pod_events_total", "Pod events processed, by type.").namespace(NAMESPACE),
+            &["service", "event"]
+        )?;
+        let patches = IntCounterVec::new(
+            Opts::new("annotation_patches_total", "Endpoint annotation patches, by result.").namespace(NAMESPACE),
+            &["service", "result"]
+        )?;
+        let patch_latency = HistogramVec::new(
+            HistogramOpts::new("annotation_patch_seconds", "Latency of endpoint annotation patches.").namespace(NAMESPACE),
+            &["service"]
+        )?;
+        let reconcile_errors = IntCounterVec::new(
+            Opts::new("reconcile_errors_total", "Errors returned by the EdgeService reconciler.").namespace(NAMESPACE),
+            &["service"]
+        )?;
+        let channel_depth = IntGauge::with_opts(
+            Opts::new("channel_depth", "Messages waiting in the endpoint_watcher channel.").namespace(NAMESPACE)
+        )?;
+
+        registry.register(Box::new(pods.clone()))?;
+        registry.register(Box::new(edges.clone()))?;
+        registry.register(Box::new(pod_events.clone()))?;
+        registry.register(Box::new(patches.clone()))?;
+        registry.register(Box::new(patch_latency.clone()))?;
+        registry.register(Box::new(reconcile_errors.clone()))?;
+        registry.register(Box::new(channel_depth.clone()))?;
+
+        Ok(Self {
+            registry,
+            pods,
+            edges,
+            pod_events,
+            patches,
+            patch_latency,
+            reconcile_errors,
+            channel_depth
+        })
+    }
+
+    /// Drops the per-service series once a service is deleted.
+    pub fn remove_service(&self, service: &str) {
+        let _ = self.pods.remove_label_values(&[service]);
+        let _ = self.edges.remove_label_values(&[service]);
+        let _ = self.patch_latency.remove_label_values(&[service]);
+        let _ = self.reconcile_errors.remove_label_values(&[service]);
+        for event in PodEventKind::ALL {
+            let _ = self.pod_events.remove_label_values(&[service, event.as_str()]);
+        }
+        for result in [PATCH_SUCCESS, PATCH_FAILURE] {
+            let _ = self.patches.remove_label_values(&[service, result]);
+        }
+    }
+
+    /// Metrics in the Prometheus text format.
+    pub fn encode(&self) -> String {
+        let mut buffer = Vec::new();
+        if let Err(e) = TextEncoder::new().encode(&self.registry.gather(), &mut buffer) {
+            log::error!("Failed to encode metrics: {e}");
+        }
+        String::from_utf8(buffer).unwrap_or_default()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::endpoint_watcher::PodEventKind;
+    use super::*;
+
+    #[test]
+    fn remove_service_drops_every_series() {
+        let metrics = ControllerMetrics::new().unwrap();
+        for service in ["deleted", "kept"] {
+            metrics.pods.with_label_values(&[service]).set(2);
+            metrics.edges.with_label_values(&[service]).set(1);
+            metrics.patch_latency.with_label_values(&[service]).observe(0.1);
+            metrics.reconcile_errors.with_label_values(&[service]).inc();
+            for event in PodEventKind::ALL {
+                metrics.pod_events.with_label_values(&[service, event.as_str()]).inc();
+            }
+            for result in [PATCH_SUCCESS, PATCH_FAILURE] {
+                metrics.patches.with_label_values(&[service, result]).inc();
+            }
+        }
+
+        metrics.remove_service("deleted");
+        let encoded = metrics.encode();
+        assert!(!encoded.contains("service=\"deleted\""), "{encoded}");
+        assert!(encoded.contains("service=\"kept\""));
+    }
+}
@@ -1,24 +1,95 @@
-use std::collections::BTreeMap;
+use std::collections::{BTreeMap, BTreeSet, VecDeque};
 use k8s_openapi::api::core::v1::Pod;
 use petgraph::{
     Directed, Direction,
-    graphmap::{DiGraphMap, EdgesDirected, Nodes},
+    algo::tarjan_scc,
+    graphmap::{DiGraphMap, EdgesDirected, NeighborsDirected, Nodes},
+    visit::Bfs,
 };
+use serde::Serialize;
 use uuid::Uuid;
+use crate::endpoint_watcher::EdgeOverrides;
+pub use crate::controller::{ConnectRule, PodMatch, TopologyRules};
+pub use crate::endpoint_watcher::NodeInfo;
 
 pub type PodMap = BTreeMap<Uuid, Pod>;
 pub type PodGraph = DiGraphMap<Uuid, ()>;
+/// Weight published with each edge, for policies that set them.
+pub type EdgeWeights = BTreeMap<(Uuid, Uuid), f64>;
+
+/// Cambio aplicado sobre el grafo de un servicio.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize)]
+#[serde(tag = "change", rename_all = "snake_case")]
+pub enum GraphChange {
+    NodeAdded { node: Uuid },
+    NodeRemoved { node: Uuid },
+    EdgeAdded { from: Uuid, to: Uuid },
+    EdgeRemoved { from: Uuid, to: Uuid },
+}
+
+/// Problem found by a policy (e.g. a pod with unparseable `hw_info`).
+/// The controller publishes it as a Warning event on the EdgeService.
+#[derive(Clone, Debug, Serialize)]
+pub struct PolicyWarning {
+    pub pod: Option<Uuid>,
+    pub reason: String,
+    pub message: String
+}
+
+/// Configuración de la política tomada del EdgeService.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct PolicyConfig {
+    pub service_uid: Uuid,
+    /// Script named in the spec, for policies that run scripts.
+    pub script: Option<String>,
+    /// Topology rules of the spec, for the rules policy.
+    pub rules: Option<TopologyRules>
+}
+
+/// View of a service graph handed to policies. Edges blocked
+/// or pinned by an operator cannot be added or removed through it.
 pub struct GraphWrapper<'a> {
-    _graph: &'a mut PodGraph
+    _graph: &'a mut PodGraph,
+    weights: &'a mut EdgeWeights,
+    overrides: &'a EdgeOverrides,
+    /// Nodes of the cluster, by name.
+    nodes: &'a BTreeMap<String, NodeInfo>,
+    changes: Vec<GraphChange>,
+    warnings: Vec<PolicyWarning>
 }
 impl<'a> GraphWrapper<'a> {
 
-    pub fn new(graph: &'a mut PodGraph) -> Self {
+    pub(crate) fn new(
+        graph: &'a mut PodGraph,
+        weights: &'a mut EdgeWeights,
+        overrides: &'a EdgeOverrides,
+        nodes: &'a BTreeMap<String, NodeInfo>) -> Self
+    {
         Self {
-            _graph: graph
+            _graph: graph,
+            weights,
+            overrides,
+            nodes,
+            changes: Vec::new(),
+            warnings: Vec::new()
         }
     }
 
+    /// Edges added or removed through this wrapper, in order, and
+    /// the warnings reported by the policy.
+    pub(crate) fn into_parts(self) -> (Vec<GraphChange>, Vec<PolicyWarning>) {
+        (self.changes, self.warnings)
+    }
+
+    /// Reports a problem to be published as a Kubernetes Event.
+    pub fn report_warning(&mut self, pod: Option<Uuid>, reason: &str, message: String) {
+        self.warnings.push(PolicyWarning {
+            pod,
+            reason: reason.to_string(),
+            message
+        });
+    }
+
     pub fn node_count(&self) -> usize {
         self._graph.node_count()
     }
@@ -31,6 +102,12 @@ impl<'a> GraphWrapper<'a> {
         self._graph.nodes()
     }
 
+    /// Node the pod is scheduled on, if the controller knows it.
+    pub fn pod_node(&self, pod: &Pod) -> Option<&NodeInfo> {
+        let node_name = pod.spec.as_ref()?.node_name.as_ref()?;
+        self.nodes.get(node_name)
+    }
+
     pub fn edges_directed(&self, node: Uuid, dir: Direction) -> EdgesDirected<'_, Uuid, (), Directed> {
         self._graph.edges_directed(node, dir)
     }
@@ -39,12 +116,157 @@ impl<'a> GraphWrapper<'a> {
         self._graph.contains_edge(a, b)
     }
 
+    /// True if an operator pinned the edge, so it cannot be removed.
+    pub fn is_pinned(&self, from: Uuid, to: Uuid) -> bool {
+        self.overrides.is_pinned(from, to)
+    }
+
+    /// True if an operator blocked the edge, so it cannot be added.
+    pub fn is_blocked(&self, from: Uuid, to: Uuid) -> bool {
+        self.overrides.is_blocked(from, to)
+    }
+
     pub fn add_edge(&mut self, from: Uuid, to: Uuid) {
-        self._graph.add_edge(from, to, ());
+        if self.overrides.is_blocked(from, to) {
+            log::warn!("Edge {from} -> {to} is blocked, ignoring.");
+            return;
+        }
+        if self._graph.add_edge(from, to, ()).is_none() {
+            self.changes.push(GraphChange::EdgeAdded { from, to });
+        }
     }
 
     pub fn remove_edge(&mut self, from: Uuid, to: Uuid) -> Option<()> {
-        self._graph.remove_edge(from, to)
+        if self.overrides.is_pinned(from, to) {
+            log::warn!("Edge {from} -> {to} is pinned, ignoring.");
+            return None;
+        }
+        let removed = self._graph.remove_edge(from, to);
+        if removed.is_some() {
+            self.weights.remove(&(from, to));
+            self.changes.push(GraphChange::EdgeRemoved { from, to });
+        }
+        removed
+    }
+
+    pub fn edge_weight(&self, from: Uuid, to: Uuid) -> Option<f64> {
+        self.weights.get(&(from, to)).copied()
+    }
+
+    /// Sets the weight published with an existing edge, None to clear it.
+    /// The weight of `(pod, pod)` is published with the pod itself, as
+    /// the share of requests it keeps. Returns true if the weight changed.
+    pub fn set_edge_weight(&mut self, from: Uuid, to: Uuid, weight: Option<f64>) -> bool {
+        let exists = if from == to { self._graph.contains_node(from) } else { self._graph.contains_edge(from, to) };
+        if !exists {
+            return false;
+        }
+
+        let previous = match weight {
+            Some(weight) => self.weights.insert((from, to), weight),
+            None => self.weights.remove(&(from, to))
+        };
+        previous != weight
+    }
+
+    pub fn neighbors_directed(&self, node: Uuid, dir: Direction) -> NeighborsDirected<'_, Uuid, Directed> {
+        self._graph.neighbors_directed(node, dir)
+    }
+
+    pub fn in_degree(&self, node: Uuid) -> usize {
+        self._graph.neighbors_directed(node, Direction::Incoming).count()
+    }
+
+    pub fn out_degree(&self, node: Uuid) -> usize {
+        self._graph.neighbors_directed(node, Direction::Outgoing).count()
+    }
+
+    /// Nodes reachable from `from` following outgoing edges, `from` included.
+    pub fn reachable(&self, from: Uuid) -> BTreeSet<Uuid> {
+        let mut reachable = BTreeSet::new();
+        if !self._graph.contains_node(from) {
+            return reachable;
+        }
+
+        let mut bfs = Bfs::new(&*self._graph, from);
+        while let Some(node) = bfs.next(&*self._graph) {
+            reachable.insert(node);
+        }
+        reachable
+    }
+
+    /// Path with the fewest hops from `from` to `to`, both included.
+    pub fn shortest_path(&self, from: Uuid, to: Uuid) -> Option<Vec<Uuid>> {
+
+        if !self._graph.contains_node(from) || !self._graph.contains_node(to) {
+            return None;
+        }
+
+        let mut previous: BTreeMap<Uuid, Uuid> = BTreeMap::new();
+        let mut queue = VecDeque::from([from]);
+        while let Some(node) = queue.pop_front() {
+            if node == to {
+                let mut path = vec![to];
+                while let Some(prev) = previous.get(path.last().unwrap()) {
+                    path.push(*prev);
+                }
+                path.reverse();
+                return Some(path);
+            }
+
+            for next in self._graph.neighbors_directed(node, Direction::Outgoing) {
+                if next != from && !previous.contains_key(&next) {
+                    previous.insert(next, node);
+                    queue.push_back(next);
+                }
+            }
+        }
+        None
+    }
+
+    /// Strongly connected components, in reverse topological order.
+    pub fn strongly_connected_components(&self) -> Vec<Vec<Uuid>> {
+        tarjan_scc(&*self._graph)
+    }
+
+    /// Makes `targets` the only outgoing neighbours of `node`, keeping
+    /// pinned edges and skipping blocked ones. Returns true if the
+    /// endpoints of `node` changed.
+    pub fn set_outgoing(&mut self, node: Uuid, targets: &[Uuid]) -> bool {
+
+        let changes = self.changes.len();
+        let targets: BTreeSet<Uuid> = targets.iter()
+            .copied()
+            .filter(|target| *target != node && self._graph.contains_node(*target))
+            .collect();
+        let current: Vec<Uuid> = self._graph.neighbors_directed(node, Direction::Outgoing).collect();
+
+        for to in current.into_iter().filter(|to| !targets.contains(to)) {
+            self.remove_edge(node, to);
+        }
+        for to in targets {
+            self.add_edge(node, to);
+        }
+        self.changes.len() != changes
+    }
+
+    /// Removes every edge of `node` that is not pinned. Returns the pods
+    /// whose endpoints changed.
+    pub fn clear_node_edges(&mut self, node: Uuid) -> Vec<Uuid> {
+
+        let incoming: Vec<Uuid> = self._graph.neighbors_directed(node, Direction::Incoming).collect();
+        let outgoing: Vec<Uuid> = self._graph.neighbors_directed(node, Direction::Outgoing).collect();
+
+        let mut affected: Vec<Uuid> = incoming.into_iter()
+            .filter(|from| self.remove_edge(*from, node).is_some())
+            .collect();
+        let removed_outgoing = outgoing.into_iter()
+            .filter(|to| self.remove_edge(node, *to).is_some())
+            .count();
+        if removed_outgoing > 0 && !affected.contains(&node) {
+            affected.push(node);
+        }
+        affected
     }
 }
 
@@ -56,7 +278,150 @@ pub trait Policy: AsyncDefault + std::fmt::Debug + Send {
     fn pod_added(&mut self, graph: &mut GraphWrapper, pods: &PodMap, pod: Uuid) -> Vec<Uuid>;
     fn pod_removed(&mut self, graph: &mut GraphWrapper, pods: &PodMap, pod: Uuid, affected: &[Uuid]) -> Vec<Uuid>;
     fn pod_updated(&mut self, graph: &mut GraphWrapper, pods: &PodMap, pod: Uuid) -> Vec<Uuid>;
+
+    /// Called when the service is created and whenever its spec changes.
+    /// An error is published as a Warning event on the service.
+    fn configure(&mut self, _config: &PolicyConfig) -> anyhow::Result<()> {
+        Ok(())
+    }
 }
 
 
 
+#[cfg(test)]
+mod tests {
+    use crate::endpoint_watcher::Edge;
+    use super::*;
+
+    const POD_A: Uuid = Uuid::from_u128(0xa);
+    const POD_B: Uuid = Uuid::from_u128(0xb);
+    const POD_C: Uuid = Uuid::from_u128(0xc);
+    const POD_D: Uuid = Uuid::from_u128(0xd);
+    const UNKNOWN: Uuid = Uuid::from_u128(0xff);
+
+    fn graph(edges: &[(Uuid, Uuid)]) -> PodGraph {
+        let mut graph = PodGraph::from_edges(edges);
+        for node in [POD_A, POD_B, POD_C, POD_D] {
+            graph.add_node(node);
+        }
+        graph
+    }
+
+    fn overrides(pinned: &[(Uuid, Uuid)], blocked: &[(Uuid, Uuid)]) -> EdgeOverrides {
+        let edges = |edges: &[(Uuid, Uuid)]| edges.iter().map(|(from, to)| Edge { from: *from, to: *to }).collect();
+        EdgeOverrides { pinned: edges(pinned), blocked: edges(blocked) }
+    }
+
+    fn outgoing(wrapper: &GraphWrapper, node: Uuid) -> BTreeSet<Uuid> {
+        wrapper.neighbors_directed(node, Direction::Outgoing).collect()
+    }
+
+    #[test]
+    fn reachable_follows_outgoing_edges() {
+        let mut graph = graph(&[(POD_A, POD_B), (POD_B, POD_C), (POD_D, POD_A)]);
+        let (mut weights, overrides, nodes) = (EdgeWeights::new(), EdgeOverrides::default(), BTreeMap::new());
+        let wrapper = GraphWrapper::new(&mut graph, &mut weights, &overrides, &nodes);
+
+        assert_eq!(wrapper.reachable(POD_A), BTreeSet::from([POD_A, POD_B, POD_C]));
+        assert_eq!(wrapper.reachable(POD_C), BTreeSet::from([POD_C]));
+        assert!(wrapper.reachable(UNKNOWN).is_empty());
+    }
+
+    #[test]
+    fn shortest_path_takes_fewest_hops() {
+        let mut graph = graph(&[(POD_A, POD_B), (POD_B, POD_C), (POD_C, POD_D), (POD_A, POD_C)]);
+        let (mut weights, overrides, nodes) = (EdgeWeights::new(), EdgeOverrides::default(), BTreeMap::new());
+        let wrapper = GraphWrapper::new(&mut graph, &mut weights, &overrides, &nodes);
+
+        assert_eq!(wrapper.shortest_path(POD_A, POD_D), Some(vec![POD_A, POD_C, POD_D]));
+        assert_eq!(wrapper.shortest_path(POD_A, POD_A), Some(vec![POD_A]));
+        assert_eq!(wrapper.shortest_path(POD_D, POD_A), None);
+        assert_eq!(wrapper.shortest_path(POD_A, UNKNOWN), None);
+    }
+
+    #[test]
+    fn strongly_connected_components_group_cycles() {
+        let mut graph = graph(&[(POD_A, POD_B), (POD_B, POD_A), (POD_C, POD_A), (POD_C, POD_D), (POD_D, POD_C)]);
+        let (mut weights, overrides, nodes) = (EdgeWeights::new(), EdgeOverrides::default(), BTreeMap::new());
+        let wrapper = GraphWrapper::new(&mut graph, &mut weights, &overrides, &nodes);
+
+        let mut components = wrapper.strongly_connected_components();
+        components.iter_mut().for_each(|component| component.sort());
+        // Orden topológico inverso: {A, B} no llega a {C, D}.
+        assert_eq!(components, [vec![POD_A, POD_B], vec![POD_C, POD_D]]);
+    }
+
+    #[test]
+    fn degrees_count_neighbours() {
+        let mut graph = graph(&[(POD_A, POD_B), (POD_A, POD_C), (POD_C, POD_B)]);
+        let (mut weights, overrides, nodes) = (EdgeWeights::new(), EdgeOverrides::default(), BTreeMap::new());
+        let wrapper = GraphWrapper::new(&mut graph, &mut weights, &overrides, &nodes);
+
+        assert_eq!((wrapper.in_degree(POD_A), wrapper.out_degree(POD_A)), (0, 2));
+        assert_eq!((wrapper.in_degree(POD_B), wrapper.out_degree(POD_B)), (2, 0));
+        assert_eq!((wrapper.in_degree(POD_D), wrapper.out_degree(POD_D)), (0, 0));
+    }
+
+    #[test]
+    fn set_outgoing_keeps_pinned_and_skips_blocked() {
+        let mut graph = graph(&[(POD_A, POD_B), (POD_A, POD_C)]);
+        let (mut weights, nodes) = (EdgeWeights::new(), BTreeMap::new());
+        let overrides = overrides(&[(POD_A, POD_B)], &[(POD_A, POD_D)]);
+        let mut wrapper = GraphWrapper::new(&mut graph, &mut weights, &overrides, &nodes);
+
+        // El propio pod y los que no están en el grafo se ignoran.
+        assert!(wrapper.set_outgoing(POD_A, &[POD_A, POD_D, UNKNOWN]));
+        assert_eq!(outgoing(&wrapper, POD_A), BTreeSet::from([POD_B]));
+        assert!(!wrapper.set_outgoing(POD_A, &[POD_B]));
+
+        let (changes, _) = wrapper.into_parts();
+        assert_eq!(changes, [GraphChange::EdgeRemoved { from: POD_A, to: POD_C }]);
+    }
+
+    #[test]
+    fn clear_node_edges_keeps_pinned() {
+        let mut graph = graph(&[(POD_A, POD_B), (POD_C, POD_B), (POD_B, POD_D), (POD_B, POD_A)]);
+        let (mut weights, nodes) = (EdgeWeights::new(), BTreeMap::new());
+        let overrides = overrides(&[(POD_C, POD_B)], &[]);
+        let mut wrapper = GraphWrapper::new(&mut graph, &mut weights, &overrides, &nodes);
+
+        assert_eq!(wrapper.clear_node_edges(POD_B), [POD_A, POD_B]);
+        assert!(wrapper.contains_edge(POD_C, POD_B));
+        assert_eq!(wrapper.out_degree(POD_B), 0);
+        // Solo queda la arista fijada, que no cambia los endpoints de nadie.
+        assert!(wrapper.clear_node_edges(POD_B).is_empty());
+    }
+
+    #[test]
+    fn overrides_win_over_add_and_remove() {
+        let mut graph = graph(&[(POD_A, POD_B)]);
+        let (mut weights, nodes) = (EdgeWeights::new(), BTreeMap::new());
+        let overrides = overrides(&[(POD_A, POD_B)], &[(POD_B, POD_A)]);
+        let mut wrapper = GraphWrapper::new(&mut graph, &mut weights, &overrides, &nodes);
+
+        wrapper.add_edge(POD_B, POD_A);
+        assert_eq!(wrapper.remove_edge(POD_A, POD_B), None);
+        assert!(wrapper.contains_edge(POD_A, POD_B) && !wrapper.contains_edge(POD_B, POD_A));
+        assert!(wrapper.into_parts().0.is_empty());
+    }
+
+    #[test]
+    fn set_edge_weight_needs_the_edge() {
+        let mut graph = graph(&[(POD_A, POD_B), (POD_A, POD_C)]);
+        let (mut weights, overrides, nodes) = (EdgeWeights::new(), EdgeOverrides::default(), BTreeMap::new());
+        let mut wrapper = GraphWrapper::new(&mut graph, &mut weights, &overrides, &nodes);
+
+        assert!(wrapper.set_edge_weight(POD_A, POD_B, Some(0.5)));
+        assert!(!wrapper.set_edge_weight(POD_A, POD_B, Some(0.5)));
+        assert!(!wrapper.set_edge_weight(POD_B, POD_A, Some(0.5)));
+        // El peso del propio pod se guarda aunque no tenga arista a sí mismo.
+        assert!(wrapper.set_edge_weight(POD_A, POD_A, Some(0.25)));
+        assert!(!wrapper.set_edge_weight(UNKNOWN, UNKNOWN, Some(1.0)));
+        assert!(wrapper.set_edge_weight(POD_A, POD_A, None));
+
+        wrapper.set_edge_weight(POD_A, POD_C, Some(0.5));
+        wrapper.remove_edge(POD_A, POD_B);
+        assert_eq!(wrapper.edge_weight(POD_A, POD_B), None);
+        assert_eq!(wrapper.edge_weight(POD_A, POD_C), Some(0.5));
+    }
+}
@@ -0,0 +1,183 @@
+//! Runs a `Policy` over a trace of pod events, without a cluster.
+//!
+//! The trace is read as JSON lines, one `TraceEvent` per line, and every
+//! event produces one `SimulationStep` line with the resulting graph and
+//! the endpoints that the controller would have published.
+
+use std::collections::BTreeMap;
+use std::io::{BufRead, Write};
+use anyhow::{Context, Result};
+use k8s_openapi::api::core::v1::{Container, ContainerPort, Pod, PodCondition, PodSpec, PodStatus};
+use kube::api::ObjectMeta;
+use serde::{Deserialize, Serialize};
+use uuid::Uuid;
+use crate::endpoint_watcher::{EdgeOverrides, ServiceGraph, PROXY_CONTAINER};
+use crate::policy::{NodeInfo, PodGraph, Policy, PolicyConfig, PolicyWarning};
+
+pub use crate::endpoint_watcher::{Edge, Neighbor, PodEventKind};
+
+/// Pod as seen by the controller at some point of the trace.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct TraceEvent {
+    #[serde(default)]
+    pub timestamp_ms: Option<u64>,
+    pub uid: Uuid,
+    pub name: String,
+    #[serde(default)]
+    pub ip: Option<String>,
+    /// Proxy port, if not the default one.
+    #[serde(default)]
+    pub port: Option<u16>,
+    pub ready: bool,
+    #[serde(default)]
+    pub node_name: Option<String>,
+    #[serde(default)]
+    pub labels: BTreeMap<String, String>,
+    #[serde(default)]
+    pub annotations: BTreeMap<String, String>
+}
+
+impl TraceEvent {
+
+    /// Builds the Pod object that the watcher would have received.
+    pub fn to_pod(&self) -> Pod {
+
+        let ready = PodCondition {
+            type_: "Ready".to_string(),
+            status: if self.ready { "True" } else { "False" }.to_string(),
+            ..Default::default()
+        };
+
+        Pod {
+            metadata: ObjectMeta {
+                uid: Some(self.uid.to_string()),
+                name: Some(self.name.clone()),
+                labels: Some(self.labels.clone()),
+                annotations: Some(self.annotations.clone()),
+                ..Default::default()
+            },
+            spec: Some(PodSpec {
+                node_name: self.node_name.clone(),
+                containers: self.port.iter()
+                    .map(|port| Container {
+                        name: PROXY_CONTAINER.to_string(),
+                        ports: Some(vec![ContainerPort { container_port: *port as i32, ..Default::default() }]),
+                        ..Default::default()
+                    })
+                    .collect(),
+                ..Default::default()
+            }),
+            status: Some(PodStatus {
+                pod_ip: self.ip.clone(),
+                conditions: Some(vec![ready]),
+                ..Default::default()
+            })
+        }
+    }
+}
+
+/// Outcome of one event of the trace.
+#[derive(Clone, Debug, Serialize)]
+pub struct SimulationStep {
+    pub timestamp_ms: Option<u64>,
+    pub pod: Uuid,
+    /// None if the event did not change the service (e.g. an unready
+    /// pod that was never added).
+    pub event: Option<PodEventKind>,
+    /// Graph after the event, in DOT format.
+    pub graph: String,
+    pub edges: Vec<Edge>,
+    /// Endpoints published to each notified pod.
+    pub notified: BTreeMap<Uuid, Vec<Neighbor>>,
+    pub warnings: Vec<PolicyWarning>
+}
+
+/// Equivalent of a ServiceWatcher that takes its pods from a trace.
+#[derive(Debug)]
+pub struct Simulator<T: Policy> {
+    graph: ServiceGraph<T>
+}
+
+impl<T: Policy> Simulator<T> {
+
+    pub fn new(policy: T) -> Self {
+        Self { graph: ServiceGraph::new(policy, EdgeOverrides::default()) }
+    }
+
+    /// Simulator with the policy built as the controller builds it.
+    pub async fn from_default() -> Self {
+        Self::new(T::default().await)
+    }
+
+    /// Configures the policy as the controller does from the EdgeService spec.
+    pub fn configure(&mut self, config: &PolicyConfig) -> Result<()> {
+        self.graph.configure(config)
+    }
+
+    /// Nodes of the cluster, by name, for policies that use their labels.
+    /// Returns the pods whose endpoints changed.
+    pub fn set_nodes(&mut self, nodes: BTreeMap<String, NodeInfo>) -> Vec<Uuid> {
+        self.graph.set_nodes(nodes).notify
+    }
+
+    pub fn graph(&self) -> &PodGraph {
+        self.graph.graph()
+    }
+
+    /// Endpoints that would be published to `pod`.
+    pub fn endpoints(&self, pod: Uuid) -> Option<Vec<Neighbor>> {
+        self.graph.neighbors(pod)
+    }
+
+    /// Applies one event, as the controller does with PodReady/PodUnready.
+    pub fn step(&mut self, event: &TraceEvent) -> Result<SimulationStep> {
+
+        let pod = event.to_pod();
+        let (kind, update) = if event.ready {
+            let (kind, update) = self.graph.add_pod(pod)?;
+            (Some(kind), Some(update))
+        }
+        else {
+            let update = self.graph.remove_pod(&pod)?;
+            (update.as_ref().map(|_| PodEventKind::Removed), update)
+        };
+
+        let update = update.unwrap_or_default();
+        let notified = update.notify.iter()
+            .filter_map(|uid| Some((*uid, self.graph.neighbors(*uid)?)))
+            .collect();
+
+        Ok(SimulationStep {
+            timestamp_ms: event.timestamp_ms,
+            pod: event.uid,
+            event: kind,
+            graph: self.graph.export_graph(),
+            edges: self.graph.graph().all_edges()
+                .map(|(from, to, _)| Edge { from, to })
+                .collect(),
+            notified,
+            warnings: update.warnings
+        })
+    }
+
+    /// Reads a trace as JSON lines and writes a `SimulationStep` line per event.
+    /// Empty lines are skipped.
+    pub fn run<R: BufRead, W: Write>(&mut self, reader: R, mut writer: W) -> Result<()> {
+
+        for (number, line) in reader.lines().enumerate() {
+            let line = line?;
+            if line.trim().is_empty() {
+                continue;
+            }
+
+            let event: TraceEvent = serde_json::from_str(&line)
+                .with_context(|| format!("Invalid trace event on line {}", number + 1))?;
+            let step = self.step(&event)?;
+            serde_json::to_writer(&mut writer, &step)?;
+            writeln!(writer)?;
+        }
+
+        writer.flush()?;
+        Ok(())
+    }
+}
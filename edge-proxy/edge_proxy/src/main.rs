@@ -5,7 +5,7 @@ use std::{env, process::Command};
 use kube::{Client, Config};
 use log::{error, info};
 use anyhow::{anyhow, Context, Result};
-use policies::{MinLatencia, SimpleContext};
+use policies::{MinLatencia, SimpleContext, Weighted};
 use edge_proxy_lib::metrics::Metric;
 use uuid::Uuid;
 
@@ -39,18 +39,37 @@ async fn main() -> Result<()> {
     }
 
     let (pod_namespace, pod_name, pod_uuid) = get_env_vars()?;
-    let policy = MinLatencia::new(CSV_MODELOS)?;
     let metrics = get_target_metrics();
+    let models = policies::read_models(CSV_MODELOS)?
+        .into_iter()
+        .map(|model| model.name)
+        .collect();
     match client {
-        Ok(client) => {
-            edge_proxy_lib::main_task::<MinLatencia, SimpleContext>(
-                client,
-                pod_namespace,
-                pod_name,
-                pod_uuid,
-                policy,
-                metrics
-            ).await
+        // La política se elige con EDGE_PROXY_POLICY.
+        Ok(client) => match env::var("EDGE_PROXY_POLICY").as_deref() {
+            Ok("min_latencia") | Err(_) => {
+                edge_proxy_lib::main_task::<MinLatencia, SimpleContext>(
+                    client,
+                    pod_namespace,
+                    pod_name,
+                    pod_uuid,
+                    MinLatencia::new(CSV_MODELOS)?,
+                    metrics,
+                    models
+                ).await
+            },
+            Ok("weighted") => {
+                edge_proxy_lib::main_task::<Weighted, SimpleContext>(
+                    client,
+                    pod_namespace,
+                    pod_name,
+                    pod_uuid,
+                    Weighted::new(CSV_MODELOS)?,
+                    metrics,
+                    models
+                ).await
+            },
+            Ok(other) => Err(anyhow!("Unknown policy {other}, expected min_latencia or weighted"))
         },
         Err(e) => {
             error!("Failed to start Kubernetes api client: {e}");
@@ -1,6 +1,6 @@
 use ringbuffer::RingBuffer;
 use edge_proxy_lib::{
-    policy::{Policy, Request},
+    policy::{Content, Policy, Processed, Request},
     server::{Endpoint, Endpoints}
 };
 use uuid::Uuid;
@@ -43,7 +43,7 @@ impl Policy<SimpleContext> for MinLatencia {
        node_uuid
     }
 
-    async fn process_locally(&self, request: &Request<SimpleContext>) -> Result<Vec<u8>> {
+    async fn process_locally(&self, request: &Request<SimpleContext>, content: Content<'_>) -> Result<Processed> {
         
         let model = request.context.model
             .as_ref()
@@ -58,7 +58,7 @@ impl Policy<SimpleContext> for MinLatencia {
                     .unwrap()
             });
 
-        process_locally(request, model).await
+        process_locally(request, content, model).await
     }
 }
 
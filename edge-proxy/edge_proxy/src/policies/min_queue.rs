@@ -1,7 +1,7 @@
 use itertools::Itertools;
 use ringbuffer::RingBuffer;
 use edge_proxy_lib::{
-    policy::{Policy, Request}, 
+    policy::{Content, Policy, Processed, Request}, 
     server::{Endpoint, Endpoints}
 };
 use uuid::Uuid;
@@ -78,10 +78,10 @@ impl Policy<SimpleContext> for MinQueue {
         target
     }
 
-    async fn process_locally(&self, request: &Request<SimpleContext>) -> Result<Vec<u8>> {
+    async fn process_locally(&self, request: &Request<SimpleContext>, content: Content<'_>) -> Result<Processed> {
         
         let model = self.choose_model(request);
-        process_locally(request, model).await
+        process_locally(request, content, model).await
     }
 
 }
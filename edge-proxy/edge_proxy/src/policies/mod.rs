@@ -4,12 +4,14 @@ mod min_latencia;
 mod min_queue;
 mod requisitos;
 mod rrobin;
+mod weighted;
 
 
 pub use random::Random;
 pub use rrobin::Rrobin;
 pub use min_latencia::MinLatencia;
 pub use requisitos::Requisitos;
+pub use weighted::Weighted;
 
 use std::time::Instant;
 
@@ -21,7 +23,7 @@ use tokio::{
 };
 use edge_proxy_lib::{
     hardware::{get_hardware_info, SystemInfo},
-    policy::{Receiver, Request, RequestContext, Sender},
+    policy::{Content, Processed, Qos, Receiver, Request, RequestContext, Sender},
     server::{Endpoint, Endpoints}
 };
 
@@ -37,6 +39,14 @@ type TritonEndpoint = Endpoint<SimpleContext>;
 type TritonEndpoints = Endpoints<SimpleContext>;
 
 impl RequestContext for SimpleContext {
+    fn from_qos(qos: Qos) -> Self {
+        Self {
+            priority: qos.priority.unwrap_or(0),
+            accuracy: qos.accuracy.unwrap_or(0),
+            model: qos.model
+        }
+    }
+
     async fn receive(reader: &mut Receiver<'_>) -> Result<Self, std::io::Error> {
         
         let priority = reader.r.read_u32().await?;
@@ -139,7 +149,7 @@ pub fn read_models(path: &str) -> Result<Vec<Model>> {
     Ok(models)
 }
 
-async fn process_locally(request: &Request<impl RequestContext>, model: &Model) -> Result<Vec<u8>> {
+async fn process_locally(request: &Request<impl RequestContext>, content: Content<'_>, model: &Model) -> Result<Processed> {
     
     let i1 = Instant::now();
     log::info!("Locally processing request: {}", request.id);
@@ -150,14 +160,15 @@ async fn process_locally(request: &Request<impl RequestContext>, model: &Model)
 
     writer.write_u32(model.name.len() as u32).await?;
     writer.write_all(model.name.as_bytes()).await?;
-    writer.write_all(&request.content).await?;
+    content.copy_to(&mut writer).await?;
     writer.flush().await?;
     drop(writer);
 
     let mut output_buffer = Vec::with_capacity(1024);
     reader.read_to_end(&mut output_buffer).await?;
     log::info!("Tiempo de inferencia: {}ms", i1.elapsed().as_millis());
-    output_buffer.extend_from_slice("Model: ".as_bytes());
-    output_buffer.extend_from_slice(model.name.as_bytes());
-    Ok(output_buffer)
+    Ok(Processed {
+        payload: output_buffer,
+        model: Some(model.name.clone())
+    })
 }
@@ -1,6 +1,6 @@
 use rand::random;
 use edge_proxy_lib::{
-    policy::{Policy, Request, RequestContext},
+    policy::{Content, Policy, Processed, Request, RequestContext},
     server::Endpoints
 };
 use uuid::Uuid;
@@ -30,12 +30,12 @@ impl<R: RequestContext> Policy<R> for Random {
         node_uuid 
     }
 
-    async fn process_locally(&self, request: &Request<R>) -> Result<Vec<u8>> {
+    async fn process_locally(&self, request: &Request<R>, content: Content<'_>) -> Result<Processed> {
 
         let model = self.models.iter()
             .min_by_key(|model| model.perf)
             .unwrap();
 
-        process_locally(request, model).await
+        process_locally(request, content, model).await
     }
 }
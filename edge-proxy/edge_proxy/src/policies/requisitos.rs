@@ -4,7 +4,7 @@ use itertools::Itertools;
 use ringbuffer::RingBuffer;
 use tokio::io::{AsyncRead, AsyncReadExt, AsyncWriteExt};
 use edge_proxy_lib::{
-    policy::{Policy, Receiver, Request, RequestContext, Sender},
+    policy::{Content, Policy, Processed, Receiver, Request, RequestContext, Sender},
     server::{Endpoint, Endpoints}
 };
 use uuid::Uuid;
@@ -49,11 +49,17 @@ impl Policy<SimpleContext> for Requisitos {
             .filter(|(uuid, _)| !request.previous_nodes.contains(uuid))
             .collect_vec();
         
+        // El tiempo que queda hasta el deadline. Los clientes sin deadline
+        // siguen mandando el presupuesto en ms como prioridad.
+        let presupuesto_ms = request.remaining()
+            .map(|remaining| remaining.as_millis().min(u32::MAX as u128) as u32)
+            .unwrap_or(request.context.priority);
+
         // Sacar nodos que han cumplido los requisitos anteriormente.
         let cumplen = nodes.iter()
             .filter(|(_, ep)| {
                 let est_tiempo = est_tiempo_para_acc(ep, request.context.accuracy);
-                est_tiempo.is_some_and(|t| t < request.context.priority)
+                est_tiempo.is_some_and(|t| t < presupuesto_ms)
             });
         
         // Si alguno ha cumplido, enviar al que tenga menos trabajo.
@@ -89,7 +95,7 @@ impl Policy<SimpleContext> for Requisitos {
         *nodes.iter().min_by_key(|(_, ep)| promedio_latencia(ep)).unwrap().0
     }
     
-    async fn process_locally(&self, request: &TritonRequest) -> Result<Vec<u8>> {
+    async fn process_locally(&self, request: &TritonRequest, content: Content<'_>) -> Result<Processed> {
         
         // Coger el modelo más rapido con accuracy >= a la pedida.
         // En caso de que no haya ningun modelo con accuracy suficiente, usar el
@@ -99,6 +105,6 @@ impl Policy<SimpleContext> for Requisitos {
             .min_by_key(|m| m.perf)
             .unwrap_or(self.models.iter().max_by_key(|m| m.accuracy).unwrap());
 
-        process_locally(&request, model).await
+        process_locally(&request, content, model).await
     }
 }
@@ -3,7 +3,7 @@ use std::sync::atomic::{AtomicUsize, Ordering};
 use anyhow::Result;
 use itertools::Itertools;
 use tokio::sync::Mutex;
-use edge_proxy_lib::{policy::{Policy, Request}, server::Endpoints};
+use edge_proxy_lib::{policy::{Content, Policy, Processed, Request}, server::Endpoints};
 use uuid::Uuid;
 use super::{process_locally, read_models, Model, SimpleContext, TritonEndpoints, TritonRequest};
 
@@ -35,12 +35,12 @@ impl Policy<SimpleContext> for Rrobin {
             .unwrap().0
     }
 
-    async fn process_locally(&self, request: &TritonRequest) -> Result<Vec<u8>> {
+    async fn process_locally(&self, request: &TritonRequest, content: Content<'_>) -> Result<Processed> {
 
         let model = self.models.iter()
             .min_by_key(|m| m.perf)
             .unwrap();
 
-        process_locally(&request, model).await
+        process_locally(&request, content, model).await
     }
 }
@@ -0,0 +1,116 @@
+use rand::random;
+use edge_proxy_lib::{
+    policy::{Content, Policy, Processed, Request, RequestContext},
+    server::Endpoints
+};
+use uuid::Uuid;
+use super::{process_locally, read_models, Model};
+use anyhow::Result;
+
+/// Reparte las peticiones según el peso que publica el controlador con
+/// cada endpoint (p.ej. la política Capacity). Los pesos se reparten entre
+/// los candidatos, sin los pods ya visitados. Si ningún candidato tiene
+/// peso, se escoge uno al azar.
+#[derive(Default)]
+pub struct Weighted {
+    models: Vec<Model>
+}
+
+impl Weighted {
+    pub fn new(path: &str) -> Result<Self> {
+        Ok(Self {
+            models: read_models(path)?
+        })
+    }
+}
+
+impl<R: RequestContext> Policy<R> for Weighted {
+
+    async fn choose_target(&self, _request: &Request<R>, endpoints: &Endpoints<R>) -> Uuid {
+        pick(endpoints, random::<f64>())
+    }
+
+    async fn process_locally(&self, request: &Request<R>, content: Content<'_>) -> Result<Processed> {
+
+        let model = self.models.iter()
+            .min_by_key(|model| model.perf)
+            .unwrap();
+
+        process_locally(request, content, model).await
+    }
+}
+
+/// Endpoint que ocupa la posición `point`, en [0, 1), del reparto por pesos.
+/// Los endpoints sin peso o con peso 0 no reciben nada salvo que ninguno lo tenga.
+fn pick<R: RequestContext>(endpoints: &Endpoints<R>, point: f64) -> Uuid {
+
+    let weighted: Vec<(Uuid, f64)> = endpoints.iter()
+        .filter_map(|(uuid, endpoint)| Some((*uuid, endpoint.weight.filter(|weight| *weight > 0.0)?)))
+        .collect();
+    let total: f64 = weighted.iter().map(|(_, weight)| weight).sum();
+    if weighted.is_empty() || !total.is_finite() {
+        let index = ((point * endpoints.len() as f64) as usize).min(endpoints.len() - 1);
+        return *endpoints.keys().nth(index).unwrap();
+    }
+
+    let mut left = point * total;
+    for (uuid, weight) in weighted.iter() {
+        if left < *weight {
+            return *uuid;
+        }
+        left -= weight;
+    }
+    weighted.last().unwrap().0
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+    use ringbuffer::AllocRingBuffer;
+    use edge_proxy_lib::server::{Endpoint, Endpoints};
+    use uuid::Uuid;
+    use crate::policies::SimpleContext;
+    use super::pick;
+
+    fn endpoints(weights: &[Option<f64>]) -> Endpoints<SimpleContext> {
+        weights.iter().enumerate()
+            .map(|(i, weight)| (Uuid::from_u128(i as u128 + 1), Endpoint {
+                name: Arc::from(format!("pod-{i}")),
+                ip: Arc::from(format!("10.0.0.{i}:9000")),
+                node_name: None,
+                zone: None,
+                hw_info: None,
+                models: Vec::new(),
+                draining: false,
+                metrics: None,
+                metrics_queried_at: None,
+                weight: *weight,
+                last_results: AllocRingBuffer::new(5)
+            }))
+            .collect()
+    }
+
+    #[test]
+    fn follows_weights() {
+        let endpoints = endpoints(&[Some(0.25), None, Some(0.75)]);
+        assert_eq!(pick(&endpoints, 0.0), Uuid::from_u128(1));
+        assert_eq!(pick(&endpoints, 0.2), Uuid::from_u128(1));
+        assert_eq!(pick(&endpoints, 0.3), Uuid::from_u128(3));
+        assert_eq!(pick(&endpoints, 0.999), Uuid::from_u128(3));
+    }
+
+    #[test]
+    fn renormalizes_over_candidates() {
+        // El pod con más peso ya se visitó: todo va al que queda.
+        let endpoints = endpoints(&[Some(0.1), Some(0.0)]);
+        assert_eq!(pick(&endpoints, 0.9), Uuid::from_u128(1));
+    }
+
+    #[test]
+    fn uniform_without_weights() {
+        let endpoints = endpoints(&[None, None, None, None]);
+        assert_eq!(pick(&endpoints, 0.0), Uuid::from_u128(1));
+        assert_eq!(pick(&endpoints, 0.5), Uuid::from_u128(3));
+        assert_eq!(pick(&endpoints, 0.99), Uuid::from_u128(4));
+    }
+}
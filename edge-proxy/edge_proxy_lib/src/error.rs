@@ -0,0 +1,63 @@
+use thiserror::Error;
+use uuid::Uuid;
+use crate::protocol::{Response, ResponseStatus};
+
+/// Why a request could not be answered. Sent back to the client as a
+/// `Response` with the pod where it failed.
+#[derive(Error, Debug)]
+pub enum ProxyError {
+    #[error("Timeout expired for request {request}")]
+    Timeout { request: Uuid, pod: Uuid },
+
+    #[error("No endpoints for service {service}")]
+    NoEndpoints { service: Uuid, pod: Uuid },
+
+    #[error("Backend failed: {source:#}")]
+    Backend { pod: Uuid, #[source] source: anyhow::Error },
+
+    #[error("Request rejected: {reason}")]
+    Rejected { pod: Uuid, reason: String },
+
+    #[error("Hop limit of {limit} reached")]
+    HopLimit { pod: Uuid, limit: u32 },
+
+    #[error("Failed to reach {address}: {source:#}")]
+    Unreachable { pod: Uuid, address: String, #[source] source: anyhow::Error }
+}
+
+impl ProxyError {
+
+    pub fn status(&self) -> ResponseStatus {
+        match self {
+            ProxyError::Timeout { .. } => ResponseStatus::Timeout,
+            ProxyError::NoEndpoints { .. } => ResponseStatus::NoEndpoints,
+            ProxyError::Backend { .. } => ResponseStatus::Backend,
+            ProxyError::Rejected { .. } => ResponseStatus::Rejected,
+            ProxyError::HopLimit { .. } => ResponseStatus::HopLimit,
+            ProxyError::Unreachable { .. } => ResponseStatus::Unreachable
+        }
+    }
+
+    /// Pod where the request failed.
+    pub fn pod(&self) -> Uuid {
+        match self {
+            ProxyError::Timeout { pod, .. }
+            | ProxyError::NoEndpoints { pod, .. }
+            | ProxyError::Backend { pod, .. }
+            | ProxyError::Rejected { pod, .. }
+            | ProxyError::HopLimit { pod, .. }
+            | ProxyError::Unreachable { pod, .. } => *pod
+        }
+    }
+
+    pub fn to_response(&self, route: Vec<Uuid>) -> Response {
+        Response {
+            status: self.status(),
+            route,
+            model: None,
+            hops: Vec::new(),
+            failed_at: Some(self.pod()),
+            payload: self.to_string().into_bytes()
+        }
+    }
+}
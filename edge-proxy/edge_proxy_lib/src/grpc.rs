@@ -0,0 +1,246 @@
+//! gRPC ingress with the KServe v2 `GRPCInferenceService`, the API that
+//! Triton serves, so its clients can talk to the mesh.
+//!
+//! Like the HTTP ingress, the first input must be a `BYTES` tensor with the
+//! encoded image, in `raw_input_contents` or in `bytes_contents`. The request
+//! parameters `priority` and `accuracy` set the QoS, `timeout` (in
+//! microseconds, as in Triton) the deadline, which is propagated to the
+//! next hops, and `service` the EdgeService.
+
+use std::{collections::HashMap, net::SocketAddr, str::FromStr, sync::Arc, time::{Duration, Instant}};
+use anyhow::{anyhow, Context, Result};
+use tonic::{
+    metadata::MetadataValue,
+    transport::server::{Router, TcpIncoming},
+    Code, Status
+};
+use uuid::Uuid;
+
+use crate::{
+    error::ProxyError,
+    kserve::{first_bytes_element, BYTES, DEFAULT_OUTPUT, INPUT_NAME, SERVER_NAME},
+    policy::{Content, Policy, Qos, Request, RequestContext},
+    protocol::{Response, ResponseStatus},
+    server::ProxyServer
+};
+
+#[allow(clippy::all)]
+mod inference {
+    tonic::include_proto!("inference");
+}
+
+use inference::{
+    grpc_inference_service_server::{GrpcInferenceService, GrpcInferenceServiceServer},
+    infer_parameter::ParameterChoice,
+    model_infer_request::InferInputTensor,
+    model_infer_response::InferOutputTensor,
+    model_metadata_response::TensorMetadata,
+    InferParameter, ModelInferRequest, ModelInferResponse, ModelMetadataRequest, ModelMetadataResponse,
+    ModelReadyRequest, ModelReadyResponse, ServerLiveRequest, ServerLiveResponse, ServerMetadataRequest,
+    ServerMetadataResponse, ServerReadyRequest, ServerReadyResponse
+};
+
+/// Pod donde falló la petición, en los metadatos del error.
+const FAILED_AT_METADATA: &str = "x-edge-failed-at";
+
+struct Ingress<T, R>
+where T: Policy<R>,
+      R: RequestContext
+{
+    server: Arc<ProxyServer<T, R>>
+}
+
+/// Binds the gRPC server, to be spawned by the proxy.
+pub(crate) fn serve<T, R>(server: Arc<ProxyServer<T, R>>, address: &SocketAddr) -> Result<impl std::future::Future<Output = Result<(), tonic::transport::Error>>>
+where T: Policy<R> + 'static,
+      R: RequestContext + 'static
+{
+    let incoming = TcpIncoming::new(*address, true, None)
+        .map_err(|e| anyhow!(e))
+        .with_context(|| format!("Failed to bind gRPC ingress to {address}"))?;
+    let router: Router = tonic::transport::Server::builder()
+        .add_service(GrpcInferenceServiceServer::new(Ingress { server: Arc::clone(&server) })
+            // Con algo de margen para el resto del mensaje.
+            .max_decoding_message_size((server.max_request_bytes() as usize).saturating_add(64 * 1024)));
+    Ok(router.serve_with_incoming(incoming))
+}
+
+#[tonic::async_trait]
+impl<T, R> GrpcInferenceService for Ingress<T, R>
+where T: Policy<R> + 'static,
+      R: RequestContext + 'static
+{
+    async fn server_live(&self, _request: tonic::Request<ServerLiveRequest>) -> Result<tonic::Response<ServerLiveResponse>, Status> {
+        Ok(tonic::Response::new(ServerLiveResponse { live: true }))
+    }
+
+    async fn server_ready(&self, _request: tonic::Request<ServerReadyRequest>) -> Result<tonic::Response<ServerReadyResponse>, Status> {
+        let ready = self.server.has_endpoints().await;
+        Ok(tonic::Response::new(ServerReadyResponse { ready }))
+    }
+
+    async fn model_ready(&self, request: tonic::Request<ModelReadyRequest>) -> Result<tonic::Response<ModelReadyResponse>, Status> {
+        let ready = self.server.available_models().await.contains(&request.get_ref().name);
+        Ok(tonic::Response::new(ModelReadyResponse { ready }))
+    }
+
+    async fn server_metadata(&self, _request: tonic::Request<ServerMetadataRequest>) -> Result<tonic::Response<ServerMetadataResponse>, Status> {
+        Ok(tonic::Response::new(ServerMetadataResponse {
+            name: SERVER_NAME.to_string(),
+            version: env!("CARGO_PKG_VERSION").to_string(),
+            extensions: Vec::new()
+        }))
+    }
+
+    async fn model_metadata(&self, request: tonic::Request<ModelMetadataRequest>) -> Result<tonic::Response<ModelMetadataResponse>, Status> {
+        let ModelMetadataRequest { name, version } = request.into_inner();
+        if !self.server.available_models().await.contains(&name) {
+            return Err(Status::not_found(format!("Unknown model {name}")));
+        }
+        let tensor = |name: &str| TensorMetadata { name: name.to_string(), datatype: BYTES.to_string(), shape: vec![1] };
+        Ok(tonic::Response::new(ModelMetadataResponse {
+            name,
+            versions: Some(version).into_iter().filter(|version| !version.is_empty()).collect(),
+            platform: SERVER_NAME.to_string(),
+            inputs: vec![tensor(INPUT_NAME)],
+            outputs: vec![tensor(DEFAULT_OUTPUT)]
+        }))
+    }
+
+    /// Routes the request through the policy, like the requests from the
+    /// binary protocol.
+    async fn model_infer(&self, request: tonic::Request<ModelInferRequest>) -> Result<tonic::Response<ModelInferResponse>, Status> {
+
+        let received = Instant::now();
+        let self_uuid = self.server.uuid();
+        let infer = request.into_inner();
+        let (qos, service, deadline, content) = parse_infer(&infer).map_err(|e| {
+            status(ProxyError::Rejected { pod: self_uuid, reason: format!("{e:#}") }.to_response(vec![self_uuid]))
+        })?;
+
+        let id = Uuid::from_str(&infer.id).unwrap_or_else(|_| Uuid::new_v4());
+        let mut request = Request {
+            id,
+            service,
+            jumps: 0,
+            context: R::from_qos(Qos { model: Some(infer.model_name.clone()), ..qos }),
+            content_len: content.len() as u64,
+            previous_nodes: Vec::new(),
+            deadline: deadline.map(|deadline| received + deadline)
+        };
+        log::info!("Received gRPC request: {:?}", request);
+
+        let result = self.server.route_request(&mut request, Content::from(content), received).await;
+        let route = vec![self_uuid];
+        let response = match result {
+            Ok(reply) => reply.into_response(route),
+            Err(e) => {
+                log::error!("gRPC request {id} failed: {e}");
+                e.to_response(route)
+            }
+        };
+        if response.status != ResponseStatus::Ok {
+            return Err(status(response));
+        }
+
+        let output = infer.outputs.first().map(|output| output.name.as_str()).unwrap_or(DEFAULT_OUTPUT);
+        let route: Vec<String> = response.route.iter().map(Uuid::to_string).collect();
+        let mut parameters = HashMap::from([
+            ("edge_route".to_string(), string_param(route.join("->")))
+        ]);
+        if let Some(model) = response.model {
+            parameters.insert("edge_model".to_string(), string_param(model));
+        }
+        for (i, hop) in response.hops.iter().enumerate() {
+            parameters.insert(format!("edge_hop_{i}_pod"), string_param(hop.pod.to_string()));
+            parameters.insert(format!("edge_hop_{i}_queue_us"), int_param(hop.queue));
+            parameters.insert(format!("edge_hop_{i}_inference_us"), int_param(hop.inference));
+            parameters.insert(format!("edge_hop_{i}_network_us"), int_param(hop.network));
+        }
+
+        // Igual que Triton, los BYTES en binario llevan delante su longitud.
+        let mut raw_output = Vec::with_capacity(response.payload.len() + 4);
+        raw_output.extend_from_slice(&(response.payload.len() as u32).to_le_bytes());
+        raw_output.extend_from_slice(&response.payload);
+        Ok(tonic::Response::new(ModelInferResponse {
+            model_name: infer.model_name,
+            model_version: infer.model_version,
+            id: infer.id,
+            parameters,
+            outputs: vec![InferOutputTensor {
+                name: output.to_string(),
+                datatype: BYTES.to_string(),
+                shape: vec![1],
+                ..Default::default()
+            }],
+            raw_output_contents: vec![raw_output]
+        }))
+    }
+}
+
+fn parse_infer(infer: &ModelInferRequest) -> Result<(Qos, Uuid, Option<Duration>, Vec<u8>)> {
+
+    let input: &InferInputTensor = infer.inputs.first().context("Infer request without inputs")?;
+    if input.datatype != BYTES {
+        return Err(anyhow!("Input {} must be BYTES with an encoded image, found {}", input.name, input.datatype));
+    }
+    let content = match infer.raw_input_contents.first() {
+        Some(raw) => first_bytes_element(raw)?.to_vec(),
+        None => input.contents.as_ref()
+            .and_then(|contents| contents.bytes_contents.first())
+            .context("Input without data")?
+            .clone()
+    };
+
+    let int = |name: &str| -> Result<Option<i64>> {
+        match infer.parameters.get(name).and_then(|param| param.parameter_choice.as_ref()) {
+            None => Ok(None),
+            Some(ParameterChoice::Int64Param(value)) => Ok(Some(*value)),
+            Some(ParameterChoice::Uint64Param(value)) => Ok(Some(i64::try_from(*value)?)),
+            Some(other) => Err(anyhow!("Invalid parameter {name}: {other:?}"))
+        }
+    };
+    let qos = Qos {
+        priority: int("priority")?.map(u32::try_from).transpose().context("Invalid priority")?,
+        accuracy: int("accuracy")?.map(u32::try_from).transpose().context("Invalid accuracy")?,
+        model: None
+    };
+    // Triton usa 0 para "sin timeout".
+    let deadline = int("timeout")?
+        .filter(|us| *us != 0)
+        .map(|us| u64::try_from(us).map(Duration::from_micros))
+        .transpose()
+        .context("Invalid timeout")?;
+    let service = match infer.parameters.get("service").and_then(|param| param.parameter_choice.as_ref()) {
+        None => Uuid::nil(),
+        Some(ParameterChoice::StringParam(service)) => Uuid::from_str(service).context("Invalid service")?,
+        Some(other) => return Err(anyhow!("Invalid parameter service: {other:?}"))
+    };
+    Ok((qos, service, deadline, content))
+}
+
+/// Error of a failed `Response`, with the pod where it failed.
+fn status(response: Response) -> Status {
+    let code = match response.status {
+        ResponseStatus::Ok => Code::Ok,
+        ResponseStatus::Rejected => Code::InvalidArgument,
+        ResponseStatus::NoEndpoints | ResponseStatus::Unreachable => Code::Unavailable,
+        ResponseStatus::Timeout => Code::DeadlineExceeded,
+        ResponseStatus::HopLimit => Code::ResourceExhausted,
+        ResponseStatus::Backend => Code::Internal
+    };
+    let mut status = Status::new(code, String::from_utf8_lossy(&response.payload));
+    if let Some(pod) = response.failed_at {
+        let pod: MetadataValue<_> = pod.to_string().parse().expect("Uuid is valid metadata");
+        status.metadata_mut().insert(FAILED_AT_METADATA, pod);
+    }
+    status
+}
+
+fn string_param(value: String) -> InferParameter {
+    InferParameter { parameter_choice: Some(ParameterChoice::StringParam(value)) }
+}
+
+fn int_param(time: Duration) -> InferParameter {
+    InferParameter { parameter_choice: Some(ParameterChoice::Int64Param(time.as_micros() as i64)) }
+}
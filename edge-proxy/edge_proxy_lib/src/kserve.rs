@@ -0,0 +1,352 @@
+//! HTTP/JSON ingress following the KServe v2 inference protocol, so Triton
+//! clients can send requests to the mesh without the binary protocol.
+//!
+//! The first input of an infer request must be a `BYTES` tensor holding the
+//! encoded image, either with the binary tensor extension or base64 in the
+//! JSON `data`. The answer of the backend is returned as a `BYTES` output
+//! with the binary tensor extension, since it is rarely valid UTF-8.
+//! QoS is read from the `X-Edge-Priority` and `X-Edge-Accuracy` headers and
+//! the service from `X-Edge-Service`. The `timeout` request parameter, in
+//! microseconds as in Triton, sets the deadline. Bodies larger than the
+//! maximum request size of the proxy are rejected.
+//!
+//! Besides the KServe endpoints, `GET /edge/pool` returns the usage of the
+//! connections to the neighbours.
+
+use std::{collections::BTreeSet, convert::Infallible, future::Future, net::SocketAddr, str::FromStr, sync::Arc, time::{Duration, Instant}};
+use anyhow::{anyhow, Context, Result};
+use base64::{engine::general_purpose::STANDARD as BASE64, Engine};
+use hyper::{
+    body::HttpBody,
+    header::CONTENT_TYPE,
+    http::HeaderMap,
+    service::{make_service_fn, service_fn},
+    Body, Method, Request as HttpRequest, Response as HttpResponse, Server, StatusCode
+};
+use serde::Deserialize;
+use serde_json::{json, Map, Value as JsonValue};
+use uuid::Uuid;
+
+use crate::{
+    error::ProxyError,
+    policy::{Content, Policy, Qos, Request, RequestContext},
+    protocol::{Response, ResponseStatus},
+    server::ProxyServer
+};
+
+/// Longitud del JSON cuando se usa la extensión binaria de Triton.
+const HEADER_LENGTH: &str = "inference-header-content-length";
+const PRIORITY_HEADER: &str = "x-edge-priority";
+const ACCURACY_HEADER: &str = "x-edge-accuracy";
+const SERVICE_HEADER: &str = "x-edge-service";
+/// Pod donde falló la petición, en las respuestas de error.
+const FAILED_AT_HEADER: &str = "x-edge-failed-at";
+
+pub(crate) const SERVER_NAME: &str = "edge-proxy";
+/// Tensores de entrada y salida que publica el proxy.
+pub(crate) const INPUT_NAME: &str = "image";
+pub(crate) const DEFAULT_OUTPUT: &str = "output";
+pub(crate) const BYTES: &str = "BYTES";
+
+#[derive(Debug, Deserialize)]
+struct InferRequest {
+    id: Option<String>,
+    inputs: Vec<InferInput>,
+    #[serde(default)]
+    outputs: Vec<RequestedOutput>,
+    #[serde(default)]
+    parameters: Map<String, JsonValue>
+}
+
+#[derive(Debug, Deserialize)]
+struct InferInput {
+    name: String,
+    datatype: String,
+    data: Option<JsonValue>,
+    #[serde(default)]
+    parameters: Map<String, JsonValue>
+}
+
+#[derive(Debug, Deserialize)]
+struct RequestedOutput {
+    name: String
+}
+
+/// Builds the HTTP server, to be spawned by the proxy.
+pub(crate) fn serve<T, R>(server: Arc<ProxyServer<T, R>>, address: &SocketAddr) -> Result<impl Future<Output = hyper::Result<()>>>
+where T: Policy<R> + 'static,
+      R: RequestContext + 'static
+{
+    let make_service = make_service_fn(move |_| {
+        let server = Arc::clone(&server);
+        async move {
+            Ok::<_, Infallible>(service_fn(move |request| handle(Arc::clone(&server), request)))
+        }
+    });
+    let http = Server::try_bind(address)
+        .with_context(|| format!("Failed to bind HTTP ingress to {address}"))?
+        .serve(make_service);
+    Ok(http)
+}
+
+async fn handle<T, R>(server: Arc<ProxyServer<T, R>>, request: HttpRequest<Body>) -> Result<HttpResponse<Body>, Infallible>
+where T: Policy<R> + 'static,
+      R: RequestContext + 'static
+{
+    let path = request.uri().path().to_string();
+    let segments: Vec<&str> = path.trim_matches('/').split('/').collect();
+    let response = match (request.method(), segments.as_slice()) {
+        (&Method::GET, ["v2", "health", "live"]) => status(StatusCode::OK),
+        (&Method::GET, ["v2", "health", "ready"]) => match server.has_endpoints().await {
+            true => status(StatusCode::OK),
+            false => status(StatusCode::SERVICE_UNAVAILABLE)
+        },
+        (&Method::GET, ["v2"]) => json_response(StatusCode::OK, json!({
+            "name": SERVER_NAME,
+            "version": env!("CARGO_PKG_VERSION"),
+            "extensions": ["binary_tensor_data"]
+        })),
+        (&Method::GET, ["edge", "pool"]) => json_response(StatusCode::OK, json!(server.pool_stats().await)),
+        (method, ["v2", "models", model, rest @ ..]) => {
+            let (version, action) = match rest {
+                ["versions", version, action @ ..] => (Some(version.to_string()), action),
+                action => (None, action)
+            };
+            let model = model.to_string();
+            match (method, action) {
+                (&Method::GET, []) => model_metadata(&server.available_models().await, model, version),
+                (&Method::GET, ["ready"]) => match server.available_models().await.contains(&model) {
+                    true => status(StatusCode::OK),
+                    false => status(StatusCode::SERVICE_UNAVAILABLE)
+                },
+                (&Method::POST, ["infer"]) => infer(&server, model, version, request).await,
+                _ => error_response(StatusCode::NOT_FOUND, format!("Unknown endpoint {path}"))
+            }
+        },
+        _ => error_response(StatusCode::NOT_FOUND, format!("Unknown endpoint {path}"))
+    };
+    Ok(response)
+}
+
+fn model_metadata(models: &BTreeSet<String>, model: String, version: Option<String>) -> HttpResponse<Body> {
+    if !models.contains(&model) {
+        return error_response(StatusCode::NOT_FOUND, format!("Unknown model {model}"));
+    }
+    json_response(StatusCode::OK, json!({
+        "name": model,
+        "versions": version.into_iter().collect::<Vec<_>>(),
+        "platform": SERVER_NAME,
+        "inputs": [{ "name": INPUT_NAME, "datatype": BYTES, "shape": [1] }],
+        "outputs": [{ "name": DEFAULT_OUTPUT, "datatype": BYTES, "shape": [1] }]
+    }))
+}
+
+/// Routes the request through the policy, like the requests from the
+/// binary protocol.
+async fn infer<T, R>(server: &ProxyServer<T, R>, model: String, version: Option<String>, http_request: HttpRequest<Body>) -> HttpResponse<Body>
+where T: Policy<R> + 'static,
+      R: RequestContext + 'static
+{
+    let received = Instant::now();
+    let (parts, body) = http_request.into_parts();
+    let rejected = |e: anyhow::Error| ProxyError::Rejected { pod: server.uuid(), reason: format!("{e:#}") };
+
+    let parsed = match read_body(body, server.max_request_bytes()).await {
+        Ok(body) => parse_infer(&parts.headers, &body),
+        Err(e) => Err(e)
+    };
+    let (infer, qos, service, deadline, content) = match parsed {
+        Ok(parsed) => parsed,
+        Err(e) => return render(rejected(e).to_response(vec![server.uuid()]), &model, version, None, DEFAULT_OUTPUT)
+    };
+
+    let id = infer.id.as_deref()
+        .and_then(|id| Uuid::from_str(id).ok())
+        .unwrap_or_else(Uuid::new_v4);
+    let output = infer.outputs.first().map(|output| output.name.as_str()).unwrap_or(DEFAULT_OUTPUT);
+    let mut request = Request {
+        id,
+        service,
+        jumps: 0,
+        context: R::from_qos(Qos { model: Some(model.clone()), ..qos }),
+        content_len: content.len() as u64,
+        previous_nodes: Vec::new(),
+        deadline: deadline.map(|deadline| received + deadline)
+    };
+    log::info!("Received KServe request: {:?}", request);
+
+    let route = vec![server.uuid()];
+    let response = match server.route_request(&mut request, Content::from(content), received).await {
+        Ok(reply) => reply.into_response(route),
+        Err(e) => {
+            log::error!("KServe request {id} failed: {e}");
+            e.to_response(route)
+        }
+    };
+    render(response, &model, version, infer.id.as_deref(), output)
+}
+
+/// Reads the body, failing as soon as it goes over `max_len`.
+async fn read_body(mut body: Body, max_len: u64) -> Result<Vec<u8>> {
+    let too_large = || anyhow!("Body is larger than the limit of {max_len} bytes");
+    if body.size_hint().lower() > max_len {
+        return Err(too_large());
+    }
+    let mut bytes = Vec::with_capacity(body.size_hint().lower() as usize);
+    while let Some(chunk) = body.data().await {
+        let chunk = chunk.context("Failed to read body")?;
+        if (bytes.len() + chunk.len()) as u64 > max_len {
+            return Err(too_large());
+        }
+        bytes.extend_from_slice(&chunk);
+    }
+    Ok(bytes)
+}
+
+/// Request, QoS, service, time until the deadline and content.
+type ParsedInfer = (InferRequest, Qos, Uuid, Option<Duration>, Vec<u8>);
+
+fn parse_infer(headers: &HeaderMap, body: &[u8]) -> Result<ParsedInfer> {
+
+    let (json, binary) = match header::<usize>(headers, HEADER_LENGTH)? {
+        Some(length) if length <= body.len() => body.split_at(length),
+        Some(length) => return Err(anyhow!("{HEADER_LENGTH} {length} is larger than the body")),
+        None => (body, &[][..])
+    };
+    let infer: InferRequest = serde_json::from_slice(json).context("Invalid infer request")?;
+    let input = infer.inputs.first().context("Infer request without inputs")?;
+    if input.datatype != BYTES {
+        return Err(anyhow!("Input {} must be BYTES with an encoded image, found {}", input.name, input.datatype));
+    }
+
+    let content = match input.parameters.get("binary_data_size") {
+        Some(size) => {
+            let size = size.as_u64().context("Invalid binary_data_size")? as usize;
+            let data = binary.get(..size).context("Binary data is shorter than binary_data_size")?;
+            first_bytes_element(data)?.to_vec()
+        },
+        None => {
+            let mut data = input.data.as_ref().context("Input without data")?;
+            // Los tensores pueden venir como listas anidadas.
+            while let Some(first) = data.as_array().and_then(|array| array.first()) {
+                data = first;
+            }
+            let encoded = data.as_str().context("BYTES data must be a base64 string")?;
+            BASE64.decode(encoded).context("Invalid base64 data")?
+        }
+    };
+
+    let qos = Qos {
+        priority: header(headers, PRIORITY_HEADER)?,
+        accuracy: header(headers, ACCURACY_HEADER)?,
+        model: None
+    };
+    let service = header(headers, SERVICE_HEADER)?.unwrap_or(Uuid::nil());
+    // Como en Triton, en microsegundos y 0 es sin timeout.
+    let deadline = match infer.parameters.get("timeout") {
+        Some(timeout) => Some(timeout.as_u64().context("Invalid timeout")?)
+            .filter(|us| *us != 0)
+            .map(Duration::from_micros),
+        None => None
+    };
+    Ok((infer, qos, service, deadline, content))
+}
+
+/// First element of a serialized `BYTES` tensor, where each element has
+/// its length in front as a little endian u32.
+pub(crate) fn first_bytes_element(data: &[u8]) -> Result<&[u8]> {
+    let length = u32::from_le_bytes(data.get(..4).context("Empty BYTES tensor")?.try_into()?) as usize;
+    data.get(4..4 + length).context("Truncated BYTES element")
+}
+
+fn header<F: FromStr>(headers: &HeaderMap, name: &str) -> Result<Option<F>>
+where F::Err: std::error::Error + Send + Sync + 'static
+{
+    headers.get(name)
+        .map(|value| -> Result<F> { Ok(value.to_str()?.trim().parse::<F>()?) })
+        .transpose()
+        .with_context(|| format!("Invalid header {name}"))
+}
+
+fn render(response: Response, model: &str, version: Option<String>, id: Option<&str>, output: &str) -> HttpResponse<Body> {
+
+    if response.status != ResponseStatus::Ok {
+        let message = String::from_utf8(response.payload)
+            .unwrap_or_else(|e| format!("Error of {} bytes that is not text", e.as_bytes().len()));
+        let mut http = error_response(http_status(response.status), message);
+        if let Some(pod) = response.failed_at {
+            http.headers_mut().insert(FAILED_AT_HEADER, pod.to_string().parse().expect("Uuid is a valid header"));
+        }
+        return http;
+    }
+
+    let hops: Vec<JsonValue> = response.hops.iter()
+        .map(|hop| json!({
+            "pod": hop.pod,
+            "queue_us": hop.queue.as_micros() as u64,
+            "inference_us": hop.inference.as_micros() as u64,
+            "network_us": hop.network.as_micros() as u64
+        }))
+        .collect();
+    // La salida va como BYTES serializado: longitud en little endian y datos.
+    let mut binary = Vec::with_capacity(response.payload.len() + 4);
+    binary.extend_from_slice(&(response.payload.len() as u32).to_le_bytes());
+    binary.extend_from_slice(&response.payload);
+    let mut body = json!({
+        "model_name": model,
+        "parameters": {
+            "edge_model": response.model,
+            "edge_route": response.route,
+            "edge_hops": hops
+        },
+        "outputs": [{
+            "name": output,
+            "datatype": BYTES,
+            "shape": [1],
+            "parameters": { "binary_data_size": binary.len() }
+        }]
+    });
+    if let Some(version) = version {
+        body["model_version"] = json!(version);
+    }
+    if let Some(id) = id {
+        body["id"] = json!(id);
+    }
+
+    let mut bytes = body.to_string().into_bytes();
+    let json_len = bytes.len();
+    bytes.extend_from_slice(&binary);
+    let mut http = HttpResponse::new(Body::from(bytes));
+    let headers = http.headers_mut();
+    headers.insert(CONTENT_TYPE, "application/octet-stream".parse().expect("Valid content type"));
+    headers.insert(HEADER_LENGTH, json_len.into());
+    http
+}
+
+fn http_status(status: ResponseStatus) -> StatusCode {
+    match status {
+        ResponseStatus::Ok => StatusCode::OK,
+        ResponseStatus::Rejected => StatusCode::BAD_REQUEST,
+        ResponseStatus::NoEndpoints => StatusCode::SERVICE_UNAVAILABLE,
+        ResponseStatus::Timeout => StatusCode::GATEWAY_TIMEOUT,
+        ResponseStatus::HopLimit => StatusCode::LOOP_DETECTED,
+        ResponseStatus::Backend | ResponseStatus::Unreachable => StatusCode::BAD_GATEWAY
+    }
+}
+
+fn status(status: StatusCode) -> HttpResponse<Body> {
+    let mut response = HttpResponse::new(Body::empty());
+    *response.status_mut() = status;
+    response
+}
+
+fn error_response(status: StatusCode, message: String) -> HttpResponse<Body> {
+    json_response(status, json!({ "error": message }))
+}
+
+fn json_response(status: StatusCode, body: JsonValue) -> HttpResponse<Body> {
+    let mut response = HttpResponse::new(Body::from(body.to_string()));
+    *response.status_mut() = status;
+    response.headers_mut().insert(CONTENT_TYPE, "application/json".parse().expect("Valid content type"));
+    response
+}
@@ -1,7 +1,12 @@
 mod watcher;
+pub mod error;
+mod grpc;
+mod kserve;
 pub mod metrics;
 pub mod server;
 pub mod policy;
+pub mod pool;
+pub mod protocol;
 pub mod hardware;
 
 use std::sync::Arc;
@@ -23,11 +28,13 @@ use crate::{
 const METRICS_ANNOT: &str = "edgeservices.prueba.ucm.es/triton_metrics";
 const HW_ANNOT: &str =      "edgeservices.prueba.ucm.es/hw_info";
 const ENDPS_ANNOT: &str =   "edgeservices.prueba.ucm.es/endpoints";
+const MODELS_ANNOT: &str =  "edgeservices.prueba.ucm.es/models";
 
 type MsgSender<'a> = mpsc::Sender<Message<'a>>;
 #[derive(Debug)]
 enum Message<'a> {
-    EndpointsChanged(JsonValue),
+    /// Endpoints of a service. Uuid::nil() for the legacy annotation.
+    EndpointsChanged { service: Uuid, endpoints: JsonValue },
     AnnotationUpdate(Vec<(&'a str, String)>),
     NeighborAnnotRequest { neighbor_name: Arc<str>, annot_name: String, respond_to: oneshot::Sender<Option<JsonValue>>}
 }
@@ -38,7 +45,8 @@ pub async fn main_task<P, R> (
     pod_name: String,
     pod_uuid: Uuid,
     policy: P,
-    metrics: Vec<Metric>
+    metrics: Vec<Metric>,
+    models: Vec<String>
 ) -> Result<()>
 where P: Policy<R> + 'static,
       R: RequestContext + 'static
@@ -64,15 +72,19 @@ where P: Policy<R> + 'static,
 
     let _metrics_client = PrometheusClient::new("http://localhost:9090", metrics, sender.clone())?;
 
-    // Update the server with the probed hardware.
-    watcher.add_annot(vec![(HW_ANNOT, hw_info)]).await;
+    // Update the server with the probed hardware and the models it serves,
+    // the controller publishes them to the other pods.
+    watcher.add_annot(vec![
+        (HW_ANNOT, hw_info.to_string()),
+        (MODELS_ANNOT, serde_json::to_string(&models)?)
+    ]).await;
     loop {
         
         let message = receiver.recv().await.context("Message channel closed.")?;
         log::debug!("New message received: {:?}", message);
         match message {
-            Message::EndpointsChanged(endpoints) => {
-                proxy_server.update_endpoints(endpoints);
+            Message::EndpointsChanged { service, endpoints } => {
+                proxy_server.update_endpoints(service, endpoints);
             },
             Message::AnnotationUpdate(annots) => {
                 watcher.add_annot(annots).await;
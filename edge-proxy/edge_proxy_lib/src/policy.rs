@@ -1,29 +1,41 @@
-use std::fmt::Debug;
+use std::{fmt::Debug, io, time::{Duration, Instant}};
 //use serde::{Deserialize, Serialize};
 use tokio::{
-    io::{BufReader, BufWriter},
-    net::TcpStream
+    io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, BufReader, BufWriter},
+    sync::mpsc
 };
 use uuid::Uuid;
 use crate::server::Endpoints;
 use anyhow::Result;
 
+/// Writer for the context of a request, a connection or a buffer.
 pub struct Sender<'a> {
-    pub s: BufWriter<&'a mut TcpStream>
+    pub s: BufWriter<&'a mut (dyn AsyncWrite + Unpin + Send + Sync)>
 }
 
 // TODO: safety
 unsafe impl<'a> Sync for Sender<'a> {}
 
+/// Reader for the context of a request, a connection or a buffer.
 pub struct Receiver<'a> {
-    pub r: BufReader<&'a mut TcpStream>
+    pub r: BufReader<&'a mut (dyn AsyncRead + Unpin + Send + Sync)>
 }
 
 // TODO: safety
 unsafe impl<'a> Sync for Receiver<'a> {}
 
+/// Quality of service asked by a client that does not speak the binary
+/// protocol, e.g. from the headers of a KServe request.
+#[derive(Debug, Clone, Default)]
+pub struct Qos {
+    pub priority: Option<u32>,
+    pub accuracy: Option<u32>,
+    pub model: Option<String>
+}
+
 pub trait RequestContext: Send + Debug + Clone  + Sync {
 
+    fn from_qos(qos: Qos) -> Self;
     fn receive(reader: &mut Receiver) -> impl std::future::Future<Output = Result<Self, std::io::Error>> + Send + Sync;
     fn send(sender: &mut Sender, req: &Self) -> impl std::future::Future<Output = Result<(), std::io::Error>> + Send + Sync;
 }
@@ -31,24 +43,139 @@ pub trait RequestContext: Send + Debug + Clone  + Sync {
 //#[derive(Clone, Serialize, Deserialize)]
 pub struct Request<R: RequestContext> {
     pub id: Uuid,
+    /// EdgeService whose endpoints are used to route the request.
+    pub service: Uuid,
     pub jumps: u32,
     pub context: R,
-    pub content: Vec<u8>,
-    pub previous_nodes: Vec<Uuid>
+    /// Size of the content, which is not read until the request is
+    /// processed or forwarded.
+    pub content_len: u64,
+    pub previous_nodes: Vec<Uuid>,
+    /// When the client stops waiting for the answer. Each hop sends the
+    /// time left, so the time spent in this pod counts against it.
+    pub deadline: Option<Instant>
+}
+
+impl<R: RequestContext> Request<R> {
+
+    /// Time left until the deadline, None if the request has none.
+    pub fn remaining(&self) -> Option<Duration> {
+        self.deadline.map(|deadline| deadline.saturating_duration_since(Instant::now()))
+    }
+
+    pub fn is_expired(&self) -> bool {
+        self.remaining().is_some_and(|remaining| remaining.is_zero())
+    }
 }
 
 impl<R: RequestContext> Debug for Request<R> {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        write!(f, "Request {{ id: {}, jumps: {}, context: {:?}, im_size: {} }}",
+        write!(f, "Request {{ id: {}, service: {}, jumps: {}, context: {:?}, im_size: {}, remaining: {:?} }}",
             self.id,
+            self.service,
             self.jumps,
             self.context,
-            self.content.len()
+            self.content_len,
+            self.remaining()
         )
     }
 }
 
+/// Result of processing a request in this pod.
+#[derive(Debug, Clone)]
+pub struct Processed {
+    pub payload: Vec<u8>,
+    /// Model that processed the request.
+    pub model: Option<String>
+}
+
+/// Size of the chunks in which the content is read.
+const CHUNK_SIZE: usize = 64 * 1024;
+
+/// Content of a request. It is read from the client while it is sent to
+/// the next hop or to the backend, so it can only be used once.
+pub struct Content<'a> {
+    len: u64,
+    /// Bytes not read yet.
+    remaining: u64,
+    source: Source<'a>
+}
+
+enum Source<'a> {
+    Buffered(Vec<u8>),
+    /// The rest of the client connection.
+    Reader(&'a mut (dyn AsyncRead + Unpin + Send + Sync)),
+    /// Chunks from a multiplexed connection.
+    Chunks(mpsc::Receiver<Vec<u8>>)
+}
+
+impl<'a> Content<'a> {
+
+    pub(crate) fn reader(reader: &'a mut (dyn AsyncRead + Unpin + Send + Sync), len: u64) -> Self {
+        Self { len, remaining: len, source: Source::Reader(reader) }
+    }
+
+    pub(crate) fn chunks(chunks: mpsc::Receiver<Vec<u8>>, len: u64) -> Self {
+        Self { len, remaining: len, source: Source::Chunks(chunks) }
+    }
+
+    pub fn len(&self) -> u64 {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// Next piece of the content, None once it has all been read.
+    pub async fn next_chunk(&mut self) -> io::Result<Option<Vec<u8>>> {
+        if self.remaining == 0 {
+            return Ok(None);
+        }
+        let chunk = match &mut self.source {
+            Source::Buffered(content) => std::mem::take(content),
+            Source::Reader(reader) => {
+                let mut chunk = vec![0; self.remaining.min(CHUNK_SIZE as u64) as usize];
+                reader.read_exact(&mut chunk).await?;
+                chunk
+            },
+            Source::Chunks(chunks) => chunks.recv().await.unwrap_or_default()
+        };
+        if chunk.is_empty() || chunk.len() as u64 > self.remaining {
+            return Err(io::Error::new(io::ErrorKind::UnexpectedEof, format!("Content ended after {} of {} bytes", self.len - self.remaining, self.len)));
+        }
+        self.remaining -= chunk.len() as u64;
+        Ok(Some(chunk))
+    }
+
+    /// Writes the content to `writer` without holding it all in memory.
+    pub async fn copy_to<W: AsyncWrite + Unpin + ?Sized>(mut self, writer: &mut W) -> io::Result<()> {
+        while let Some(chunk) = self.next_chunk().await? {
+            writer.write_all(&chunk).await?;
+        }
+        Ok(())
+    }
+
+    /// Reads the whole content, for backends that need it in memory.
+    pub async fn read_all(mut self) -> io::Result<Vec<u8>> {
+        let mut content = Vec::with_capacity(self.len as usize);
+        while let Some(chunk) = self.next_chunk().await? {
+            content.extend_from_slice(&chunk);
+        }
+        Ok(content)
+    }
+}
+
+impl From<Vec<u8>> for Content<'_> {
+    fn from(content: Vec<u8>) -> Self {
+        let len = content.len() as u64;
+        Self { len, remaining: len, source: Source::Buffered(content) }
+    }
+}
+
 pub trait Policy<R: RequestContext>: Send + Sync {
+    /// `endpoints` are the candidates: this pod and the ones the request
+    /// has not visited yet.
     fn choose_target(&self, request: &Request<R>, endpoints: &Endpoints<R>) -> impl std::future::Future<Output = Uuid> + std::marker::Send;
-    fn process_locally(&self, request: &Request<R>) -> impl std::future::Future<Output = Result<Vec<u8>>> + std::marker::Send;
+    fn process_locally(&self, request: &Request<R>, content: Content<'_>) -> impl std::future::Future<Output = Result<Processed>> + std::marker::Send;
 }
@@ -0,0 +1,292 @@
+//! Persistent connections to the neighbours.
+//!
+//! With peers that support `MULTIPLEXED` there is one connection per
+//! neighbour, shared by every request forwarded to it. Requests and
+//! answers go in frames `[tag u64][len u64][body]`. The tag is chosen by
+//! the sender and is unique in the connection: the request id is not
+//! enough, a request that loops may be forwarded twice to the same pod
+//! while the first one is still waiting. Older peers get a connection per
+//! request, as before.
+
+use std::{
+    net::SocketAddr,
+    sync::{atomic::{AtomicBool, AtomicU64, Ordering}, Arc}
+};
+use anyhow::{anyhow, Context, Result};
+use dashmap::DashMap;
+use serde::Serialize;
+use tokio::{
+    io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, BufWriter},
+    net::tcp::OwnedWriteHalf,
+    sync::{oneshot, Mutex, MutexGuard}
+};
+
+use crate::{
+    policy::{Content, Request, RequestContext},
+    protocol::{PeerProtocols, Protocol},
+    server::{encode_request_head, request_len, write_request}
+};
+
+/// Connections to the neighbours, one per address.
+#[derive(Debug)]
+pub struct PeerPool {
+    protocols: PeerProtocols,
+    peers: DashMap<SocketAddr, Arc<Peer>>,
+    /// Tamaño máximo de una respuesta, para no reservar memoria por una
+    /// longitud corrupta.
+    max_response: u64
+}
+
+#[derive(Debug, Default)]
+struct Peer {
+    /// Shared connection, None until the first request or if the peer
+    /// does not support multiplexing.
+    connection: Mutex<Option<Arc<Connection>>>,
+    multiplexed: AtomicBool,
+    connections: AtomicU64,
+    requests: AtomicU64,
+    failures: AtomicU64
+}
+
+/// Usage of the connections to a neighbour.
+#[derive(Debug, Clone, Serialize)]
+pub struct PeerStats {
+    pub address: SocketAddr,
+    pub multiplexed: bool,
+    pub connected: bool,
+    /// Requests waiting for an answer on the shared connection.
+    pub in_flight: usize,
+    pub requests: u64,
+    /// Connections opened, one per request for peers without multiplexing.
+    pub connections: u64,
+    pub failures: u64
+}
+
+impl PeerPool {
+
+    pub fn new(max_response: u64) -> Self {
+        Self { protocols: PeerProtocols::default(), peers: DashMap::new(), max_response }
+    }
+
+    /// Sends `request` to `addr` and returns the answer with the protocol
+    /// it is in. The content is streamed to peers without multiplexing and
+    /// read whole before it is written to a shared connection.
+    pub async fn send<R: RequestContext>(&self, addr: SocketAddr, request: &Request<R>, content: Content<'_>) -> Result<(Vec<u8>, Protocol)> {
+
+        let peer = Arc::clone(self.peers.entry(addr).or_default().value());
+        peer.requests.fetch_add(1, Ordering::Relaxed);
+        let result = self.send_to(&peer, addr, request, content).await;
+        if result.is_err() {
+            peer.failures.fetch_add(1, Ordering::Relaxed);
+        }
+        result
+    }
+
+    async fn send_to<R: RequestContext>(&self, peer: &Peer, addr: SocketAddr, request: &Request<R>, content: Content<'_>) -> Result<(Vec<u8>, Protocol)> {
+
+        let mut connection = peer.connection.lock().await;
+        if let Some(shared) = connection.as_ref().filter(|shared| !shared.is_closed()) {
+            let shared = Arc::clone(shared);
+            drop(connection);
+            return Ok((shared.send(request, content).await?, shared.protocol));
+        }
+
+        let (mut stream, protocol) = self.protocols.connect(addr).await?;
+        peer.connections.fetch_add(1, Ordering::Relaxed);
+        peer.multiplexed.store(protocol.multiplexed(), Ordering::Relaxed);
+        if protocol.multiplexed() {
+            let shared = Connection::start(addr, stream, protocol, self.max_response);
+            *connection = Some(Arc::clone(&shared));
+            drop(connection);
+            return Ok((shared.send(request, content).await?, protocol));
+        }
+        *connection = None;
+        drop(connection);
+
+        // Sin multiplexar el final de la respuesta es el cierre de la conexión.
+        let head = encode_request_head(request, protocol).await?;
+        write_request(&mut stream, &head, request, content, protocol).await?;
+        let mut response = Vec::new();
+        (&mut stream).take(self.max_response + 1).read_to_end(&mut response).await?;
+        if response.len() as u64 > self.max_response {
+            return Err(anyhow!("Answer from {addr} is larger than the limit of {} bytes", self.max_response));
+        }
+        Ok((response, protocol))
+    }
+
+    pub async fn stats(&self) -> Vec<PeerStats> {
+        let peers: Vec<(SocketAddr, Arc<Peer>)> = self.peers.iter()
+            .map(|peer| (*peer.key(), Arc::clone(peer.value())))
+            .collect();
+        let mut stats = Vec::with_capacity(peers.len());
+        for (address, peer) in peers {
+            let connection = peer.connection.lock().await.clone().filter(|connection| !connection.is_closed());
+            stats.push(PeerStats {
+                address,
+                multiplexed: peer.multiplexed.load(Ordering::Relaxed),
+                connected: connection.is_some(),
+                in_flight: connection.map(|connection| connection.pending.len()).unwrap_or(0),
+                requests: peer.requests.load(Ordering::Relaxed),
+                connections: peer.connections.load(Ordering::Relaxed),
+                failures: peer.failures.load(Ordering::Relaxed)
+            });
+        }
+        stats
+    }
+}
+
+/// Multiplexed connection to a neighbour.
+#[derive(Debug)]
+struct Connection {
+    protocol: Protocol,
+    /// None once a frame was left unfinished.
+    writer: Mutex<Option<BufWriter<OwnedWriteHalf>>>,
+    next_tag: AtomicU64,
+    pending: Arc<DashMap<u64, oneshot::Sender<Vec<u8>>>>,
+    closed: Arc<AtomicBool>
+}
+
+impl Connection {
+
+    /// Starts the task that hands the answers to the waiting requests.
+    fn start(addr: SocketAddr, stream: tokio::net::TcpStream, protocol: Protocol, max_response: u64) -> Arc<Self> {
+
+        let (mut reader, writer) = stream.into_split();
+        let pending: Arc<DashMap<u64, oneshot::Sender<Vec<u8>>>> = Arc::default();
+        let closed: Arc<AtomicBool> = Arc::default();
+        log::info!("Opened multiplexed connection to {addr}");
+
+        let (task_pending, task_closed) = (Arc::clone(&pending), Arc::clone(&closed));
+        tokio::spawn(async move {
+            let result = loop {
+                match read_frame(&mut reader, max_response).await {
+                    Ok(Some((tag, frame))) => match task_pending.remove(&tag) {
+                        Some((_, waiting)) => { let _ = waiting.send(frame); },
+                        None => log::warn!("Answer from {addr} for unknown tag {tag}")
+                    },
+                    Ok(None) => break Ok(()),
+                    Err(e) => break Err(e)
+                }
+            };
+            match result {
+                Ok(_) => log::info!("Multiplexed connection to {addr} closed"),
+                Err(e) => log::warn!("Multiplexed connection to {addr} failed: {e:#}")
+            }
+            // Las peticiones pendientes fallan al soltar sus canales.
+            task_closed.store(true, Ordering::SeqCst);
+            task_pending.clear();
+        });
+
+        Arc::new(Self {
+            protocol,
+            writer: Mutex::new(Some(BufWriter::new(writer))),
+            next_tag: AtomicU64::new(0),
+            pending,
+            closed
+        })
+    }
+
+    fn is_closed(&self) -> bool {
+        self.closed.load(Ordering::SeqCst)
+    }
+
+    async fn send<R: RequestContext>(self: &Arc<Self>, request: &Request<R>, content: Content<'_>) -> Result<Vec<u8>> {
+
+        let head = encode_request_head(request, self.protocol).await?;
+        let len = request_len(&head, request, content.len(), self.protocol);
+
+        let tag = self.next_tag.fetch_add(1, Ordering::Relaxed);
+        let (sender, answer) = oneshot::channel();
+        self.pending.insert(tag, sender);
+        // Si se cancela la petición (timeout) no se queda en pending.
+        let _pending = PendingGuard { pending: &self.pending, tag };
+        if self.is_closed() {
+            return Err(anyhow!("Connection closed"));
+        }
+
+        // El frame se prepara entero antes de tomar el lock, para que un
+        // cliente lento no retenga al resto de peticiones a este vecino. Su
+        // tamaño está acotado por el máximo de las peticiones.
+        let mut frame = Vec::with_capacity(16 + len as usize);
+        frame.write_u64(tag).await?;
+        frame.write_u64(len).await?;
+        write_request(&mut frame, &head, request, content, self.protocol).await?;
+
+        // La escritura sigue aunque se cancele la petición: un frame cortado
+        // cerraría la conexión a todas las que la comparten.
+        let connection = Arc::clone(self);
+        tokio::spawn(async move { connection.write_frame(&frame).await })
+            .await
+            .context("Frame writer failed")??;
+        answer.await.context("Connection closed before the answer")
+    }
+
+    async fn write_frame(&self, frame: &[u8]) -> Result<()> {
+        let mut guard = FrameGuard { writer: self.writer.lock().await, closed: &self.closed, finished: false };
+        let writer = guard.writer.as_mut().context("Connection closed")?;
+        writer.write_all(frame).await?;
+        writer.flush().await?;
+        guard.finished = true;
+        Ok(())
+    }
+}
+
+struct PendingGuard<'a> {
+    pending: &'a DashMap<u64, oneshot::Sender<Vec<u8>>>,
+    tag: u64
+}
+
+impl Drop for PendingGuard<'_> {
+    fn drop(&mut self) {
+        self.pending.remove(&self.tag);
+    }
+}
+
+/// Closes the connection if the frame could not be written whole, so no
+/// other frame is appended to a truncated one.
+struct FrameGuard<'a> {
+    writer: MutexGuard<'a, Option<BufWriter<OwnedWriteHalf>>>,
+    closed: &'a AtomicBool,
+    finished: bool
+}
+
+impl Drop for FrameGuard<'_> {
+    fn drop(&mut self) {
+        if !self.finished {
+            self.closed.store(true, Ordering::SeqCst);
+            // Soltar la mitad de escritura cierra ese sentido de la conexión.
+            self.writer.take();
+        }
+    }
+}
+
+/// Reads the tag and length of a frame, None if the peer closed the
+/// connection between frames.
+pub(crate) async fn read_frame_head<R: AsyncRead + Unpin + ?Sized>(reader: &mut R) -> Result<Option<(u64, u64)>> {
+    let tag = match reader.read_u64().await {
+        Ok(tag) => tag,
+        Err(e) if e.kind() == std::io::ErrorKind::UnexpectedEof => return Ok(None),
+        Err(e) => return Err(e.into())
+    };
+    Ok(Some((tag, reader.read_u64().await?)))
+}
+
+/// Reads a whole frame of at most `max_len` bytes.
+pub(crate) async fn read_frame<R: AsyncRead + Unpin + ?Sized>(reader: &mut R, max_len: u64) -> Result<Option<(u64, Vec<u8>)>> {
+    let Some((tag, len)) = read_frame_head(reader).await? else {
+        return Ok(None);
+    };
+    if len > max_len {
+        return Err(anyhow!("Frame of {len} bytes is larger than the limit of {max_len} bytes"));
+    }
+    let mut body = vec![0; len as usize];
+    reader.read_exact(&mut body).await?;
+    Ok(Some((tag, body)))
+}
+
+pub(crate) async fn write_frame<W: AsyncWrite + Unpin + ?Sized>(writer: &mut W, tag: u64, body: &[u8]) -> std::io::Result<()> {
+    writer.write_u64(tag).await?;
+    writer.write_u64(body.len() as u64).await?;
+    writer.write_all(body).await?;
+    writer.flush().await
+}
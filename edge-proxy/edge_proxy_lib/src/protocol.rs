@@ -0,0 +1,381 @@
+//! Versioned wire protocol between proxies and clients.
+//!
+//! A framed connection starts with a hello from the client:
+//! `[magic "EDGP"][version u16][capabilities u32]`. The proxy answers
+//! with a hello holding the version and capabilities both ends support,
+//! and then the request follows:
+//! `[request id 16B][service uuid 16B][jumps u32][context len u32][context]
+//! [content len u64][content][previous uuids]`. With `HEADER_FIRST` the
+//! previous uuids go before the content length instead, and the proxy
+//! chooses the target with the header and streams the content to it.
+//! With `DEADLINE` the context is followed by the time left until the
+//! deadline, `[remaining us u64]`, 0 if the request has none. Clocks of
+//! different nodes are not compared, each hop restarts the count when
+//! the request arrives.
+//!
+//! With `STRUCTURED_RESPONSE` the answer is a `Response` frame:
+//! `[header len u32][header][payload len u64][payload]`, where the header
+//! holds the status, the route, the model and the timings of each hop.
+//! Otherwise the answer is the payload followed by the model and the
+//! route as text.
+//!
+//! With `MULTIPLEXED` the connection is kept open and carries several
+//! requests at once. Each request and each answer goes in a frame
+//! `[tag u64][len u64][request or response]`, and answers are matched to
+//! requests by the tag, see `pool`.
+//!
+//! Connections that do not start with the magic use the legacy layout,
+//! `[request id 16B][jumps u32][context][content len u64][content]
+//! [previous uuids]`, without the service, which is the only one of the
+//! pod, so clients and proxies from before the handshake keep working.
+
+use std::{
+    net::SocketAddr,
+    time::{Duration, Instant}
+};
+use anyhow::{anyhow, Context, Result};
+use dashmap::DashMap;
+use uuid::Uuid;
+use tokio::{
+    io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt},
+    net::{TcpSocket, TcpStream},
+    time::timeout
+};
+
+pub const MAGIC: [u8; 4] = *b"EDGP";
+pub const VERSION: u16 = 1;
+
+/// Tiempo de espera de la respuesta al hello. Un proxy antiguo
+/// no contesta, se queda esperando el resto de la petición.
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_millis(500);
+
+/// Tiempo tras el que se vuelve a intentar el handshake con un
+/// vecino antiguo, por si se ha actualizado.
+const LEGACY_RECHECK: Duration = Duration::from_secs(60);
+
+/// Optional features of the protocol, as bits. A feature is used on a
+/// connection only if both ends advertise it.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Capabilities(pub u32);
+
+impl Capabilities {
+    pub const NONE: Self = Self(0);
+    /// Answers are `Response` frames instead of text.
+    pub const STRUCTURED_RESPONSE: Self = Self(1 << 0);
+    /// Several requests share the connection, in tagged frames. Only used
+    /// together with `STRUCTURED_RESPONSE`.
+    pub const MULTIPLEXED: Self = Self(1 << 1);
+    /// The previous uuids go before the content, so the request can be
+    /// routed before its content arrives.
+    pub const HEADER_FIRST: Self = Self(1 << 2);
+    /// The header carries the time left until the deadline.
+    pub const DEADLINE: Self = Self(1 << 3);
+
+    /// Capabilities of this proxy.
+    pub const SUPPORTED: Self = Self(Self::STRUCTURED_RESPONSE.0 | Self::MULTIPLEXED.0 | Self::HEADER_FIRST.0 | Self::DEADLINE.0);
+
+    pub fn contains(self, other: Self) -> bool {
+        self.0 & other.0 == other.0
+    }
+
+    pub fn intersection(self, other: Self) -> Self {
+        Self(self.0 & other.0)
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Hello {
+    pub version: u16,
+    pub capabilities: Capabilities
+}
+
+impl Hello {
+
+    /// Hello advertising what this proxy supports.
+    pub fn ours() -> Self {
+        Self { version: VERSION, capabilities: Capabilities::SUPPORTED }
+    }
+
+    /// What both ends support, sent back to the peer.
+    pub fn negotiate(&self, peer: &Hello) -> Hello {
+        Self {
+            version: self.version.min(peer.version),
+            capabilities: self.capabilities.intersection(peer.capabilities)
+        }
+    }
+
+    pub async fn write<W: AsyncWrite + Unpin + ?Sized>(&self, writer: &mut W) -> std::io::Result<()> {
+        let mut frame = Vec::with_capacity(10);
+        frame.extend_from_slice(&MAGIC);
+        frame.extend_from_slice(&self.version.to_be_bytes());
+        frame.extend_from_slice(&self.capabilities.0.to_be_bytes());
+        writer.write_all(&frame).await?;
+        writer.flush().await
+    }
+
+    pub async fn read<R: AsyncRead + Unpin + ?Sized>(reader: &mut R) -> Result<Self> {
+        let mut magic = [0_u8; 4];
+        reader.read_exact(&mut magic).await?;
+        if magic != MAGIC {
+            return Err(anyhow!("Invalid magic {magic:?}"));
+        }
+        Ok(Self::read_after_magic(reader).await?)
+    }
+
+    /// Reads a hello whose magic was already consumed.
+    pub async fn read_after_magic<R: AsyncRead + Unpin + ?Sized>(reader: &mut R) -> std::io::Result<Self> {
+        let version = reader.read_u16().await?;
+        let capabilities = Capabilities(reader.read_u32().await?);
+        Ok(Self { version, capabilities })
+    }
+}
+
+/// Layout of the requests on a connection.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Protocol {
+    Legacy,
+    Framed(Hello)
+}
+
+impl Protocol {
+    pub fn capabilities(&self) -> Capabilities {
+        match self {
+            Protocol::Legacy => Capabilities::NONE,
+            Protocol::Framed(hello) => hello.capabilities
+        }
+    }
+
+    /// The connection carries several requests, see `pool`.
+    pub fn multiplexed(&self) -> bool {
+        let capabilities = self.capabilities();
+        capabilities.contains(Capabilities::MULTIPLEXED) && capabilities.contains(Capabilities::STRUCTURED_RESPONSE)
+    }
+}
+
+/// Outcome of a request, the first field of a `Response` header. On
+/// failure the payload holds the error message.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[repr(u16)]
+pub enum ResponseStatus {
+    Ok = 0,
+    /// Local processing failed.
+    Backend = 1,
+    /// A hop did not answer in time.
+    Timeout = 2,
+    /// The pod has no endpoints for the requested service.
+    NoEndpoints = 3,
+    /// The request is invalid.
+    Rejected = 4,
+    /// The request went through too many pods.
+    HopLimit = 5,
+    /// The next hop could not be reached.
+    Unreachable = 6
+}
+
+impl ResponseStatus {
+    fn from_u16(status: u16) -> Self {
+        match status {
+            0 => Self::Ok,
+            2 => Self::Timeout,
+            3 => Self::NoEndpoints,
+            4 => Self::Rejected,
+            5 => Self::HopLimit,
+            6 => Self::Unreachable,
+            _ => Self::Backend
+        }
+    }
+}
+
+/// Time spent by a request in a pod.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct HopTiming {
+    pub pod: Uuid,
+    /// From the request being read until it was processed or forwarded.
+    pub queue: Duration,
+    /// Local processing, zero in the pods that forwarded the request.
+    pub inference: Duration,
+    /// Waiting for the next hop, minus the time the next hops report.
+    pub network: Duration
+}
+
+impl HopTiming {
+    pub fn total(&self) -> Duration {
+        self.queue + self.inference + self.network
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Response {
+    pub status: ResponseStatus,
+    /// Pods the request went through, the one that answered last.
+    pub route: Vec<Uuid>,
+    /// Model that processed the request.
+    pub model: Option<String>,
+    /// Timings of each hop, in the order of `route`.
+    pub hops: Vec<HopTiming>,
+    /// Pod where the request failed.
+    pub failed_at: Option<Uuid>,
+    pub payload: Vec<u8>
+}
+
+impl Response {
+
+    /// Time reported by all the hops.
+    pub fn total_time(&self) -> Duration {
+        self.hops.iter().map(HopTiming::total).sum()
+    }
+
+    pub fn encode(&self) -> Vec<u8> {
+
+        let mut header = Vec::new();
+        header.extend_from_slice(&(self.status as u16).to_be_bytes());
+        header.extend_from_slice(&(self.route.len() as u32).to_be_bytes());
+        for pod in self.route.iter() {
+            header.extend_from_slice(pod.as_bytes());
+        }
+        let model = self.model.as_deref().unwrap_or_default();
+        header.extend_from_slice(&(model.len() as u32).to_be_bytes());
+        header.extend_from_slice(model.as_bytes());
+        header.extend_from_slice(&(self.hops.len() as u32).to_be_bytes());
+        for hop in self.hops.iter() {
+            header.extend_from_slice(hop.pod.as_bytes());
+            for time in [hop.queue, hop.inference, hop.network] {
+                header.extend_from_slice(&(time.as_micros() as u64).to_be_bytes());
+            }
+        }
+        header.extend_from_slice(self.failed_at.unwrap_or_default().as_bytes());
+
+        let mut frame = Vec::with_capacity(header.len() + self.payload.len() + 12);
+        frame.extend_from_slice(&(header.len() as u32).to_be_bytes());
+        frame.extend_from_slice(&header);
+        frame.extend_from_slice(&(self.payload.len() as u64).to_be_bytes());
+        frame.extend_from_slice(&self.payload);
+        frame
+    }
+
+    /// Reads a frame. Header fields added by newer versions are ignored.
+    pub fn decode(frame: &[u8]) -> Result<Self> {
+
+        let mut input = frame;
+        let header_len = take_u32(&mut input)? as usize;
+        let mut header = take(&mut input, header_len)?;
+
+        let status = ResponseStatus::from_u16(u16::from_be_bytes(take(&mut header, 2)?.try_into()?));
+        let route = (0..take_u32(&mut header)?)
+            .map(|_| Ok(Uuid::from_slice(take(&mut header, 16)?)?))
+            .collect::<Result<Vec<_>>>()?;
+        let model_len = take_u32(&mut header)? as usize;
+        let model = String::from_utf8(take(&mut header, model_len)?.to_vec())?;
+        let hops = (0..take_u32(&mut header)?)
+            .map(|_| {
+                let pod = Uuid::from_slice(take(&mut header, 16)?)?;
+                let mut time = || -> Result<Duration> {
+                    Ok(Duration::from_micros(u64::from_be_bytes(take(&mut header, 8)?.try_into()?)))
+                };
+                Ok(HopTiming { pod, queue: time()?, inference: time()?, network: time()? })
+            })
+            .collect::<Result<Vec<_>>>()?;
+        // Las respuestas de la versión anterior no lo llevan.
+        let failed_at = match header.len() {
+            0 => None,
+            _ => Some(Uuid::from_slice(take(&mut header, 16)?)?).filter(|pod| !pod.is_nil())
+        };
+
+        let payload_len = u64::from_be_bytes(take(&mut input, 8)?.try_into()?) as usize;
+        let payload = take(&mut input, payload_len)?.to_vec();
+        Ok(Self {
+            status,
+            route,
+            model: (!model.is_empty()).then_some(model),
+            hops,
+            failed_at,
+            payload
+        })
+    }
+
+    /// The answer as clients without `STRUCTURED_RESPONSE` expect it.
+    /// Errors are a single line of text.
+    pub fn to_legacy(&self) -> Vec<u8> {
+        if self.status != ResponseStatus::Ok {
+            let pod = self.failed_at.map(|pod| pod.to_string()).unwrap_or_default();
+            return format!("Error ({:?}) at {pod}: {}\n", self.status, String::from_utf8_lossy(&self.payload)).into_bytes();
+        }
+        let mut output = self.payload.clone();
+        if let Some(model) = &self.model {
+            output.extend_from_slice("Model: ".as_bytes());
+            output.extend_from_slice(model.as_bytes());
+        }
+        let route: Vec<String> = self.route.iter().map(Uuid::to_string).collect();
+        output.extend_from_slice(format!("\nRoute: {}\n", route.join("->")).as_bytes());
+        output
+    }
+}
+
+fn take<'a>(input: &mut &'a [u8], len: usize) -> Result<&'a [u8]> {
+    if input.len() < len {
+        return Err(anyhow!("Truncated response frame"));
+    }
+    let (taken, rest) = input.split_at(len);
+    *input = rest;
+    Ok(taken)
+}
+
+fn take_u32(input: &mut &[u8]) -> Result<u32> {
+    let bytes = take(input, 4).context("Missing field")?;
+    Ok(u32::from_be_bytes(bytes.try_into()?))
+}
+
+/// Protocol spoken by each neighbour, learnt from the handshake.
+#[derive(Debug, Default)]
+pub struct PeerProtocols {
+    peers: DashMap<SocketAddr, PeerProtocol>
+}
+
+#[derive(Clone, Copy, Debug)]
+enum PeerProtocol {
+    Framed(Hello),
+    Legacy { since: Instant }
+}
+
+impl PeerProtocols {
+
+    /// Opens a connection to `addr` and agrees on a protocol. Peers that
+    /// do not answer the hello are reached with the legacy layout.
+    pub async fn connect(&self, addr: SocketAddr) -> Result<(TcpStream, Protocol)> {
+
+        let known = self.peers.get(&addr).map(|peer| *peer);
+        if let Some(PeerProtocol::Legacy { since }) = known {
+            if since.elapsed() < LEGACY_RECHECK {
+                return Ok((connect(addr).await?, Protocol::Legacy));
+            }
+        }
+
+        let mut stream = connect(addr).await?;
+        Hello::ours().write(&mut stream).await?;
+        match timeout(HANDSHAKE_TIMEOUT, Hello::read(&mut stream)).await {
+            Ok(Ok(hello)) => {
+                if !matches!(known, Some(PeerProtocol::Framed(known)) if known == hello) {
+                    log::info!("Peer {addr} speaks protocol version {} with capabilities {:#x}", hello.version, hello.capabilities.0);
+                }
+                self.peers.insert(addr, PeerProtocol::Framed(hello));
+                Ok((stream, Protocol::Framed(hello)))
+            },
+            result => {
+                if let Ok(Err(e)) = result {
+                    log::warn!("Handshake with {addr} failed: {e}");
+                }
+                log::info!("Peer {addr} does not answer the handshake, using the legacy protocol");
+                self.peers.insert(addr, PeerProtocol::Legacy { since: Instant::now() });
+                Ok((connect(addr).await?, Protocol::Legacy))
+            }
+        }
+    }
+}
+
+async fn connect(addr: SocketAddr) -> std::io::Result<TcpStream> {
+    let socket = match addr {
+        SocketAddr::V4(_) => TcpSocket::new_v4()?,
+        SocketAddr::V6(_) => TcpSocket::new_v6()?
+    };
+    socket.connect(addr).await
+}
@@ -1,19 +1,24 @@
 use log::info;
 use ringbuffer::{AllocRingBuffer, RingBuffer};
 use uuid::Uuid;use serde_json::Value as JsonValue;
-use anyhow::{Context, Result};
+use anyhow::{anyhow, Context, Result};
 use std::{
-    collections::BTreeMap, env, net::SocketAddr, str::FromStr, sync::Arc, time::{Duration, Instant}
+    borrow::Cow, collections::{BTreeMap, BTreeSet}, env, net::SocketAddr, str::FromStr, sync::Arc, time::{Duration, Instant}
 };
 use tokio::{
-    io::{AsyncReadExt, AsyncWriteExt, BufReader, BufWriter},
-    net::{TcpListener, TcpSocket, TcpStream},
-    sync::{oneshot, RwLock, Semaphore},
+    io::{AsyncReadExt, AsyncWrite, AsyncWriteExt, BufReader, BufWriter},
+    net::{TcpListener, TcpStream},
+    sync::{mpsc, oneshot, Mutex, RwLock, Semaphore},
     time::{sleep, timeout}
 };
 
 use crate::{
-    policy::{Policy, Receiver, Request, RequestContext, Sender},
+    policy::{Content, Policy, Receiver, Request, RequestContext, Sender},
+    error::ProxyError,
+    grpc,
+    kserve,
+    pool::{self, PeerPool, PeerStats},
+    protocol::{Capabilities, Hello, HopTiming, Protocol, Response, ResponseStatus, MAGIC},
     Message, MsgSender, HW_ANNOT, METRICS_ANNOT
 };
 
@@ -25,8 +30,32 @@ const QUERY_MAX_ELLAPSED: Duration = Duration::from_secs(10);
 /// simultáneas.
 const MAX_CONCURRENT_METRICS_QUERY: usize = 2;
 
+/// Tamaño máximo por defecto del contenido de una petición.
+const DEFAULT_MAX_REQUEST_BYTES: u64 = 64 * 1024 * 1024;
+
+/// Saltos máximos por defecto de una petición. Al llegar al límite se
+/// procesa en este pod, o falla si el pod no sirve el servicio.
+const DEFAULT_MAX_HOPS: u32 = 8;
+
+/// Trozos del contenido que se pueden encolar para una petición
+/// de una conexión multiplexada.
+const CONTENT_CHUNKS: usize = 4;
+
+/// Puerto de los vecinos si el controlador no lo publica.
+const DEFAULT_PORT: u16 = 9999;
+
+/// Puerto de la entrada HTTP compatible con KServe v2. El 8000 ya lo
+/// usa el Triton del pod.
+const DEFAULT_HTTP_PORT: u16 = 8080;
+
+/// Puerto de la entrada gRPC compatible con KServe v2. El 8001 es el
+/// del Triton del pod.
+const DEFAULT_GRPC_PORT: u16 = 8081;
+
 
 pub type Endpoints<R> = BTreeMap<Uuid, Endpoint<R>>;
+/// Endpoints of every service the pod belongs to.
+pub type ServiceEndpoints<R> = BTreeMap<Uuid, Endpoints<R>>;
 
 #[derive(Debug, Clone)]
 pub struct PreviousResult<R: RequestContext> {
@@ -35,13 +64,23 @@ pub struct PreviousResult<R: RequestContext> {
     pub context: R
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub struct Endpoint<R: RequestContext> {
     pub name: Arc<str>,
+    /// Proxy address, ip:port.
     pub ip: Arc<str>,
+    pub node_name: Option<Arc<str>>,
+    pub zone: Option<Arc<str>>,
     pub hw_info: Option<JsonValue>,
+    /// Models advertised by the pod.
+    pub models: Vec<String>,
+    /// The pod is in maintenance or terminating.
+    pub draining: bool,
     pub metrics: Option<JsonValue>,
     pub metrics_queried_at: Option<Instant>,
+    /// Share of the requests to send to this endpoint, if the
+    /// controller's policy sets one.
+    pub weight: Option<f64>,
     
     /// Infomation about the last query to this endpoint:
     /// - None if the endpoint has never been used.
@@ -56,11 +95,16 @@ where T: Policy<R>,
       R: RequestContext
 {
     self_uuid: Uuid,
-    endpoints: RwLock<Endpoints<R>>,
+    endpoints: RwLock<ServiceEndpoints<R>>,
     query_sem: Semaphore,
     sender: MsgSender<'static>,
     policy: T,
-    request_timeout: Duration
+    request_timeout: Duration,
+    max_request_bytes: u64,
+    max_hops: u32,
+    peers: PeerPool,
+    http_port: u16,
+    grpc_port: u16
 }
 
 impl<T, R> ProxyServer<T, R> 
@@ -81,41 +125,72 @@ where T: Policy<R> + 'static,
 
         log::info!("Timeout found: {timeout_ms} ms");
 
+        let max_request_bytes: u64 = env::var("EDGE_PROXY_MAX_REQUEST_BYTES")
+            .ok()
+            .and_then(|x| x.parse().ok())
+            .unwrap_or(DEFAULT_MAX_REQUEST_BYTES);
+
+        let max_hops: u32 = env::var("EDGE_PROXY_MAX_HOPS")
+            .ok()
+            .and_then(|x| x.parse().ok())
+            .unwrap_or(DEFAULT_MAX_HOPS);
+
+        let http_port: u16 = env::var("EDGE_PROXY_HTTP_PORT")
+            .ok()
+            .and_then(|x| x.parse().ok())
+            .unwrap_or(DEFAULT_HTTP_PORT);
+        let grpc_port: u16 = env::var("EDGE_PROXY_GRPC_PORT")
+            .ok()
+            .and_then(|x| x.parse().ok())
+            .unwrap_or(DEFAULT_GRPC_PORT);
+
         let server = Arc::new(Self {
             self_uuid,
-            endpoints: RwLock::new(Endpoints::default()),
+            endpoints: RwLock::new(ServiceEndpoints::default()),
             query_sem: Semaphore::new(MAX_CONCURRENT_METRICS_QUERY),
             sender,
             policy,
-            request_timeout: Duration::from_millis(timeout_ms)
+            request_timeout: Duration::from_millis(timeout_ms),
+            max_request_bytes,
+            max_hops,
+            peers: PeerPool::new(max_request_bytes),
+            http_port,
+            grpc_port
         });
         
         server.run(listen_address).await?;
         Ok(server)
     }
 
-    pub fn update_endpoints(self: &Arc<Self>, new_endpoints: JsonValue) {
+    /// Replaces the endpoints of `service`. An empty list removes it.
+    pub fn update_endpoints(self: &Arc<Self>, service: Uuid, new_endpoints: JsonValue) {
     
         let server = Arc::clone(self);
         tokio::spawn(async move {
             let parsed = parse_endpoints(new_endpoints);
             let mut write_handle = server.endpoints.write().await;
             match parsed {
+                Ok(endps) if endps.is_empty() => {
+                    log::info!("Removed endpoints of service {service}");
+                    write_handle.remove(&service);
+                }
                 Ok(mut endps) => {
+                    let mut old_endps = write_handle.remove(&service).unwrap_or_default();
                     for (uuid, ep) in endps.iter_mut() {
                         // Save metrics for previous endpoints that are
                         // still valid.
-                        if let Some(old_endp) = write_handle.remove(uuid) {
-                            ep.hw_info = old_endp.hw_info;
+                        if let Some(old_endp) = old_endps.remove(uuid) {
+                            ep.hw_info = ep.hw_info.take().or(old_endp.hw_info);
                             ep.metrics = old_endp.metrics;
                             ep.metrics_queried_at = old_endp.metrics_queried_at;
                             ep.last_results = old_endp.last_results;
                         }
-                        else {
+                        // Controladores antiguos no publican el hw_info.
+                        if ep.hw_info.is_none() {
                             server.query_annot(*uuid, HW_ANNOT, &ep.name);
                         }
                     }
-                    *write_handle = endps;
+                    write_handle.insert(service, endps);
                 }
                 Err(e) => log::error!("Failed to parse new endpoints. {e}")
             };
@@ -125,6 +200,26 @@ where T: Policy<R> + 'static,
     async fn run(self: &Arc<Self>, listen_address: &str) -> Result<()> {
         
         let listener = TcpListener::bind(listen_address).await?;
+        let mut http_address = listener.local_addr()?;
+        http_address.set_port(self.http_port);
+        let http = kserve::serve(Arc::clone(self), &http_address)?;
+        log::info!("KServe v2 HTTP ingress listening on {http_address}");
+        tokio::spawn(async move {
+            if let Err(e) = http.await {
+                log::error!("HTTP ingress stopped: {e}");
+            }
+        });
+
+        let mut grpc_address = http_address;
+        grpc_address.set_port(self.grpc_port);
+        let grpc = grpc::serve(Arc::clone(self), &grpc_address)?;
+        log::info!("KServe v2 gRPC ingress listening on {grpc_address}");
+        tokio::spawn(async move {
+            if let Err(e) = grpc.await {
+                log::error!("gRPC ingress stopped: {e}");
+            }
+        });
+
         let server = Arc::clone(self);
         // Tarea para el servidor proxy.
         tokio::spawn(async move {
@@ -151,9 +246,10 @@ where T: Policy<R> + 'static,
              
             loop {
                 let endpoints = server.endpoints.read().await;
-                for (uuid, ep) in endpoints.iter() {
+                let mut queried = BTreeSet::new();
+                for (uuid, ep) in endpoints.values().flat_map(|endps| endps.iter()) {
                     // Si no hay que seguir esperando...
-                    if !ep.metrics_queried_at.is_some_and(|t| t.elapsed() < QUERY_MAX_ELLAPSED) {
+                    if !ep.metrics_queried_at.is_some_and(|t| t.elapsed() < QUERY_MAX_ELLAPSED) && queried.insert(*uuid) {
                         server.query_annot(*uuid, METRICS_ANNOT, &ep.name);
                     }
                 }
@@ -170,61 +266,280 @@ where T: Policy<R> + 'static,
     async fn handle_request(self: Arc<Self>, mut client_conn: TcpStream, _from_addr: SocketAddr)
         -> Result<()>
     {
-        let mut request = read_request(&mut client_conn).await?;
+        let mut prefix = [0_u8; 4];
+        client_conn.read_exact(&mut prefix).await?;
+        let protocol = if prefix == MAGIC {
+            let hello = Hello::read_after_magic(&mut client_conn).await?;
+            let agreed = Hello::ours().negotiate(&hello);
+            agreed.write(&mut client_conn).await?;
+            Protocol::Framed(agreed)
+        }
+        else {
+            Protocol::Legacy
+        };
+        if protocol.multiplexed() {
+            return self.serve_multiplexed(client_conn, protocol).await;
+        }
+
+        // En el protocolo antiguo los 4 bytes leídos son parte del id.
+        let prefix: &[u8] = match protocol {
+            Protocol::Legacy => &prefix,
+            Protocol::Framed(_) => &[]
+        };
+        let structured = protocol.capabilities().contains(Capabilities::STRUCTURED_RESPONSE);
+        let (client_read, client_write) = client_conn.split();
+        let mut input = prefix.chain(client_read);
+        let mut reader = Receiver { r: BufReader::new(&mut input) };
+        let (mut request, content) = match read_request(&mut reader, protocol, self.max_request_bytes).await {
+            Ok(read) => read,
+            Err(e) => {
+                // Tras el handshake se puede contestar aunque la petición esté mal.
+                if structured {
+                    let error = ProxyError::Rejected { pod: self.self_uuid, reason: format!("{e:#}") };
+                    BufWriter::new(client_write).write_all(&error.to_response(vec![self.self_uuid]).encode()).await?;
+                }
+                return Err(e);
+            }
+        };
+        let received = Instant::now();
         log::info!("Received request: {:?}", request);
+        let mut route = request.previous_nodes.clone();
+        route.push(self.self_uuid);
+
+        let result = self.route_request(&mut request, content, received).await;
+        // Los clientes antiguos reciben el texto de siempre.
+        let (output, error) = match (result, structured) {
+            (Ok(Reply::Structured(response)), true) => (response.encode(), None),
+            (Ok(Reply::Structured(response)), false) => (response.to_legacy(), None),
+            (Ok(reply @ Reply::Legacy { .. }), true) => (reply.into_response(route).encode(), None),
+            (Ok(Reply::Legacy { output, .. }), false) => (output, None),
+            (Err(e), true) => (e.to_response(route).encode(), Some(e)),
+            (Err(e), false) => (e.to_response(route).to_legacy(), Some(e))
+        };
+        let mut writer = BufWriter::new(client_write);
+        writer.write_all(&output).await?;
+        writer.flush().await?;
+        if let Some(e) = error {
+            return Err(e.into());
+        }
+        info!("Conexión terminada.");
+        Ok(())
+    }
+
+    /// Answers the requests of a multiplexed connection until the peer
+    /// closes it. Each request is routed in its own task, which receives
+    /// the content in chunks as it is read. If a request does not take
+    /// its content fast enough the connection stops being read.
+    async fn serve_multiplexed(self: Arc<Self>, client_conn: TcpStream, protocol: Protocol) -> Result<()> {
+
+        let (mut reader, writer) = client_conn.into_split();
+        let writer = Arc::new(Mutex::new(BufWriter::new(writer)));
+        while let Some((tag, len)) = pool::read_frame_head(&mut reader).await? {
+            let received = Instant::now();
+            let mut frame = (&mut reader).take(len);
+            let mut frame_reader = Receiver { r: BufReader::new(&mut frame) };
+
+            match read_request::<R>(&mut frame_reader, protocol, self.max_request_bytes).await {
+                Ok((request, mut content)) => {
+                    let (chunks, receiver) = mpsc::channel(CONTENT_CHUNKS);
+                    let server = Arc::clone(&self);
+                    let writer = Arc::clone(&writer);
+                    let content_len = content.len();
+                    tokio::spawn(async move {
+                        let response = server.answer(request, Content::chunks(receiver, content_len), received).await;
+                        let mut writer = writer.lock().await;
+                        match pool::write_frame(&mut *writer, tag, &response.encode()).await {
+                            Ok(_) => log::info!("Petition processed in {} ms", received.elapsed().as_millis()),
+                            Err(e) => log::error!("Failed to answer multiplexed request: {e}")
+                        }
+                    });
+                    // Si la petición ya no quiere el contenido se descarta.
+                    let mut chunks = Some(chunks);
+                    while let Some(chunk) = content.next_chunk().await? {
+                        if let Some(sender) = &chunks {
+                            if sender.send(chunk).await.is_err() {
+                                chunks = None;
+                            }
+                        }
+                    }
+                },
+                Err(e) => {
+                    let error = ProxyError::Rejected { pod: self.self_uuid, reason: format!("{e:#}") };
+                    log::error!("Invalid multiplexed request: {e:#}");
+                    let mut writer = writer.lock().await;
+                    pool::write_frame(&mut *writer, tag, &error.to_response(vec![self.self_uuid]).encode()).await?;
+                }
+            }
+            // Lo que quede del frame, p. ej. una petición demasiado grande.
+            tokio::io::copy(&mut frame_reader.r, &mut tokio::io::sink()).await?;
+        }
+        info!("Conexión multiplexada terminada.");
+        Ok(())
+    }
+
+    async fn answer(&self, mut request: Request<R>, content: Content<'_>, received: Instant) -> Response {
+
+        log::info!("Received request: {:?}", request);
+        let mut route = request.previous_nodes.clone();
+        route.push(self.self_uuid);
+        match self.route_request(&mut request, content, received).await {
+            Ok(reply) => reply.into_response(route),
+            Err(e) => {
+                log::error!("Request {} failed: {e}", request.id);
+                e.to_response(route)
+            }
+        }
+    }
+
+    /// Processes the request in this pod or forwards it to the pod
+    /// chosen by the policy. The content is streamed to its destination.
+    ///
+    /// The policy only sees the pods the request has not visited yet,
+    /// plus this one, and once the request has made `max_hops` jumps it
+    /// is processed here whatever the policy says.
+    ///
+    /// Requests past their deadline fail with a timeout instead of using
+    /// the GPU, and are not forwarded to pods that have never answered
+    /// in the time left.
+    pub(crate) async fn route_request(&self, request: &mut Request<R>, content: Content<'_>, received: Instant) -> Result<Reply, ProxyError> {
+
+        if request.is_expired() {
+            log::warn!("Request {} arrived after its deadline", request.id);
+            return Err(ProxyError::Timeout { request: request.id, pod: self.self_uuid });
+        }
 
         let read_handle = self.endpoints.read().await;
-        let target_uuid = self.policy.choose_target(&mut request, &read_handle).await;
+        let (service, all_endpoints) = service_endpoints(&read_handle, request.service)
+            .ok_or(ProxyError::NoEndpoints { service: request.service, pod: self.self_uuid })?;
+        request.service = service;
 
-        let target = read_handle.get(&target_uuid).context("Endpoint dropped")?;
+        let target_uuid = if request.jumps >= self.max_hops {
+            if !all_endpoints.contains_key(&self.self_uuid) {
+                return Err(ProxyError::HopLimit { pod: self.self_uuid, limit: self.max_hops });
+            }
+            log::warn!("Request {} reached the hop limit of {}, processing locally", request.id, self.max_hops);
+            self.self_uuid
+        }
+        else {
+            let endpoints = candidates(all_endpoints, &request.previous_nodes, self.self_uuid);
+            if endpoints.is_empty() {
+                return Err(ProxyError::NoEndpoints { service, pod: self.self_uuid });
+            }
+            let target_uuid = self.policy.choose_target(request, &endpoints).await;
+            if !endpoints.contains_key(&target_uuid) {
+                log::error!("Policy chose {target_uuid}, which is not a candidate for request {}", request.id);
+                return Err(ProxyError::NoEndpoints { service, pod: self.self_uuid });
+            }
+            target_uuid
+        };
+
+        let target = all_endpoints.get(&target_uuid)
+            .ok_or(ProxyError::NoEndpoints { service, pod: self.self_uuid })?;
         
         //if target.metrics_queried_at.is_some_and(|queried_at| queried_at.elapsed() > QUERY_MAX_ELLAPSED) {
         //    self.query_annot(target_uuid, METRICS_ANNOT, &target.name);
         //}
 
         let start = Instant::now();
+        let queue = start - received;
         let result = if target_uuid != self.self_uuid {
             log::info!("EDGE_PROXY_DEBUG {} {} {}", request.id, request.jumps, target_uuid);
-            let addr = SocketAddr::from_str(&target.ip)?;
+            let fastest = target.last_results.iter().filter_map(|result| result.duration).min();
+            if let Some((remaining, fastest)) = request.remaining().zip(fastest).filter(|(remaining, fastest)| remaining < fastest) {
+                log::warn!("Request {} has {remaining:?} left and {target_uuid} takes at least {fastest:?}, not forwarding", request.id);
+                return Err(ProxyError::Timeout { request: request.id, pod: self.self_uuid });
+            }
+            let address = Arc::clone(&target.ip);
             drop(read_handle);
 
             request.jumps += 1;
             request.previous_nodes.push(self.self_uuid);
-            timeout(self.request_timeout, proxy(&mut client_conn, addr, &request))
-                .await
-                .ok()
+            let limit = request.remaining().map_or(self.request_timeout, |remaining| remaining.min(self.request_timeout));
+            match timeout(limit, proxy(&self.peers, target_uuid, &address, request, content)).await {
+                Ok(Ok(Reply::Structured(mut response))) => {
+                    let network = start.elapsed().saturating_sub(response.total_time());
+                    response.hops.insert(0, HopTiming { pod: self.self_uuid, queue, inference: Duration::ZERO, network });
+                    Ok(Reply::Structured(response))
+                },
+                // Un proxy antiguo cierra la conexión sin contestar si falla.
+                Ok(Ok(Reply::Legacy { output, .. })) if output.is_empty() => {
+                    Err(ProxyError::Backend { pod: target_uuid, source: anyhow!("Empty answer") })
+                },
+                Ok(Ok(Reply::Legacy { output, .. })) => {
+                    let hop = HopTiming { pod: self.self_uuid, queue, inference: Duration::ZERO, network: start.elapsed() };
+                    Ok(Reply::Legacy { output, hop })
+                },
+                Ok(Err(e)) => Err(e),
+                Err(_) => Err(ProxyError::Timeout { request: request.id, pod: target_uuid })
+            }
         }
         else {
             drop(read_handle);
 
-            let response = self.policy.process_locally(&request).await?;
-            let mut writer = BufWriter::new(client_conn);
-            writer.write_all(&response).await?;
-            writer.write_all("\nRoute: ".as_bytes()).await?;
-            for node in request.previous_nodes {
-                writer.write_all(node.to_string().as_bytes()).await?;
-                writer.write_all("->".as_bytes()).await?;
+            let mut route = request.previous_nodes.clone();
+            route.push(self.self_uuid);
+            let id = request.id;
+            let processed = match request.remaining() {
+                Some(remaining) => timeout(remaining, self.policy.process_locally(request, content)).await,
+                None => Ok(self.policy.process_locally(request, content).await)
+            };
+            match processed {
+                Ok(processed) => processed
+                    .map(|processed| Reply::Structured(Response {
+                        status: ResponseStatus::Ok,
+                        route,
+                        model: processed.model,
+                        hops: vec![HopTiming { pod: self.self_uuid, queue, inference: start.elapsed(), network: Duration::ZERO }],
+                        failed_at: None,
+                        payload: processed.payload
+                    }))
+                    .map_err(|source| ProxyError::Backend { pod: self.self_uuid, source }),
+                Err(_) => Err(ProxyError::Timeout { request: id, pod: self.self_uuid })
             }
-            writer.write_all(self.self_uuid.to_string().as_bytes()).await?;
-            writer.write_all("\n".as_bytes()).await?;
-            writer.flush().await?;
-            Some(Ok(()))
         };
         // On sucess, store how long it took for the target to answer the last request.
         // On timeout or error, store the instant the error.
+        let succeeded = match &result {
+            Ok(Reply::Structured(response)) => response.status == ResponseStatus::Ok,
+            Ok(Reply::Legacy { .. }) => true,
+            Err(_) => false
+        };
         let now = Instant::now();
-        let event = match &result {
-            Some(Ok(_)) => PreviousResult { duration: Some(start.elapsed()), context: request.context, instant: now },
-            _ => PreviousResult { duration: None, instant: now, context: request.context }
+        let event = match succeeded {
+            true => PreviousResult { duration: Some(start.elapsed()), context: request.context.clone(), instant: now },
+            false => PreviousResult { duration: None, instant: now, context: request.context.clone() }
         };
         let mut write_handle = self.endpoints.write().await;
-        if let Some(ep) = write_handle.get_mut(&target_uuid) {
+        if let Some(ep) = write_handle.get_mut(&request.service).and_then(|endps| endps.get_mut(&target_uuid)) {
             ep.last_results.push(event);
         }
         drop(write_handle);
-        result.with_context(|| format!("Timeout expired for request {}", request.id))??;
-        info!("Conexión terminada.");
-        Ok(())
+        result
+    }
+
+    pub(crate) async fn pool_stats(&self) -> Vec<PeerStats> {
+        self.peers.stats().await
+    }
+
+    pub(crate) fn max_request_bytes(&self) -> u64 {
+        self.max_request_bytes
+    }
+
+    pub(crate) fn uuid(&self) -> Uuid {
+        self.self_uuid
+    }
+
+    pub(crate) async fn has_endpoints(&self) -> bool {
+        self.endpoints.read().await.values().any(|endps| endps.values().any(|ep| !ep.draining))
+    }
+
+    /// Models served by the pods that are not draining.
+    pub(crate) async fn available_models(&self) -> BTreeSet<String> {
+        self.endpoints.read().await.values()
+            .flat_map(|endps| endps.values())
+            .filter(|ep| !ep.draining)
+            .flat_map(|ep| ep.models.iter().cloned())
+            .collect()
     }
 
     fn query_annot(self: &Arc<Self>, pod_uuid: Uuid, annot_name: &str, pod_name: &Arc<str>) {
@@ -245,20 +560,21 @@ where T: Policy<R> + 'static,
             server.sender.send(message).await.expect("Failed to send message.");
             let response = r.await.expect("Failed to get answer.");
             
+            // El mismo pod puede estar en varios servicios.
             let mut endp_write = server.endpoints.write().await;
-            if let Some(endp) = endp_write.get_mut(&pod_uuid) {
+            for endp in endp_write.values_mut().filter_map(|endps| endps.get_mut(&pod_uuid)) {
 
                 // TODO: cambiar esta ñapa de ifs.
                 if annot_name == METRICS_ANNOT {
                     endp.metrics_queried_at = Some(Instant::now());
-                    endp.metrics = response;
+                    endp.metrics = response.clone();
                     if endp.metrics.is_none() {
                         log::warn!("Failed to query {annot_name} for pod {pod_name}")
                     }
                 }
                 else if annot_name == HW_ANNOT {
                     println!("Received hw_info por pod {pod_uuid}");
-                    endp.hw_info = response;
+                    endp.hw_info = response.clone();
                 }
                             }
             drop(endp_write);
@@ -267,39 +583,126 @@ where T: Policy<R> + 'static,
     }
 }
 
-async fn proxy(client: &mut TcpStream, addr: SocketAddr, request: &Request<impl RequestContext>) -> Result<()> {
-    
-    let mut target = TcpSocket::new_v4()?
-        .connect(addr)
-        .await?;
-    
-    send_request(&mut target, request).await?;
+/// Answer of the next hop, structured if it supports it.
+pub(crate) enum Reply {
+    Structured(Response),
+    /// Text answer of an old proxy, and the time spent in this pod.
+    Legacy { output: Vec<u8>, hop: HopTiming }
+}
 
-    let mut response = Vec::new();
-    target.read_to_end(&mut response).await?;
-    client.write_all(&response).await?;
-    Ok(())
+impl Reply {
+    /// The answer as a `Response`, `route` is used if the next hop did
+    /// not send one.
+    pub(crate) fn into_response(self, route: Vec<Uuid>) -> Response {
+        match self {
+            Reply::Structured(response) => response,
+            Reply::Legacy { output, hop } => Response {
+                status: ResponseStatus::Ok,
+                route,
+                model: None,
+                hops: vec![hop],
+                failed_at: None,
+                payload: output
+            }
+        }
+    }
+}
+
+async fn proxy(peers: &PeerPool, pod: Uuid, address: &str, request: &Request<impl RequestContext>, content: Content<'_>) -> Result<Reply, ProxyError> {
+
+    let unreachable = |source: anyhow::Error| ProxyError::Unreachable { pod, address: address.to_string(), source };
+    let addr = SocketAddr::from_str(address).map_err(|e| unreachable(e.into()))?;
+    let (response, protocol) = peers.send(addr, request, content).await.map_err(unreachable)?;
+    if protocol.capabilities().contains(Capabilities::STRUCTURED_RESPONSE) {
+        Response::decode(&response)
+            .map(Reply::Structured)
+            .map_err(|source| ProxyError::Backend { pod, source: source.context("Invalid response") })
+    }
+    else {
+        let hop = HopTiming { pod, queue: Duration::ZERO, inference: Duration::ZERO, network: Duration::ZERO };
+        Ok(Reply::Legacy { output: response, hop })
+    }
 }
 
-async fn read_request<R: RequestContext> (stream: &mut TcpStream) -> Result<Request<R>> {
+/// Reads a request up to its content. With `HEADER_FIRST` the content is
+/// left in `reader` to be streamed, otherwise the previous nodes come
+/// after it and it has to be read into memory.
+async fn read_request<'a, R: RequestContext>(reader: &'a mut Receiver<'_>, protocol: Protocol, max_request_bytes: u64) -> Result<(Request<R>, Content<'a>)> {
 
-    let mut reader = Receiver {r: BufReader::new(stream) };
+    let too_large = |what: &str, size: u64| anyhow!("{what} of {size} bytes is larger than the limit of {max_request_bytes} bytes");
     let mut uuid_buff = [0_u8; 16];
 
     reader.r.read_exact(&mut uuid_buff).await?;
     let uuid = Uuid::from_slice(&uuid_buff)?;
+    // El formato antiguo no lleva servicio, se usa el único del pod.
+    let service = match protocol {
+        Protocol::Legacy => Uuid::nil(),
+        Protocol::Framed(_) => {
+            reader.r.read_exact(&mut uuid_buff).await?;
+            Uuid::from_slice(&uuid_buff)?
+        }
+    };
     let jumps = reader.r.read_u32().await?;
     log::info!("Recibido: JUMPS {}", jumps);
+    if jumps as u64 * 16 > max_request_bytes {
+        return Err(too_large("Route", jumps as u64 * 16));
+    }
 
     // Lectura del context
-    let context = R::receive(&mut reader).await?;
+    let context = match protocol {
+        Protocol::Legacy => R::receive(reader).await?,
+        // Con longitud, los campos que no conocemos se ignoran.
+        Protocol::Framed(_) => {
+            let context_size = reader.r.read_u32().await?;
+            if context_size as u64 > max_request_bytes {
+                return Err(too_large("Context", context_size as u64));
+            }
+            let mut context = vec![0; context_size as usize];
+            reader.r.read_exact(context.as_mut_slice()).await?;
+            R::receive(&mut Receiver { r: BufReader::new(&mut context.as_slice()) }).await?
+        }
+    };
     // let priority = reader.read_u8().await?;
     // let accuracy = reader.read_u8().await?;
+    let mut deadline = None;
+    if protocol.capabilities().contains(Capabilities::DEADLINE) {
+        let remaining = reader.r.read_u64().await?;
+        if remaining != 0 {
+            deadline = Some(Instant::now() + Duration::from_micros(remaining));
+        }
+    }
+
+    let header_first = protocol.capabilities().contains(Capabilities::HEADER_FIRST);
+    let mut previous_nodes = Vec::new();
+    if header_first {
+        previous_nodes = read_nodes(reader, jumps).await?;
+    }
 
     let request_size = reader.r.read_u64().await?;
+    if request_size > max_request_bytes {
+        return Err(too_large("Request", request_size));
+    }
+    let request = |previous_nodes| Request {
+        id: uuid,
+        service,
+        jumps,
+        context,
+        content_len: request_size,
+        previous_nodes,
+        deadline
+    };
+    if header_first {
+        return Ok((request(previous_nodes), Content::reader(&mut reader.r, request_size)));
+    }
+
     let mut content = vec![0; request_size as usize];
     reader.r.read_exact(content.as_mut_slice()).await?;
-    
+    let previous_nodes = read_nodes(reader, jumps).await?;
+    Ok((request(previous_nodes), Content::from(content)))
+}
+
+async fn read_nodes(reader: &mut Receiver<'_>, jumps: u32) -> Result<Vec<Uuid>> {
+    let mut uuid_buff = [0_u8; 16];
     let mut previous_nodes = Vec::with_capacity(jumps as usize);
     for _ in 0..jumps {
         reader.r.read_exact(&mut uuid_buff).await?;
@@ -307,43 +710,106 @@ async fn read_request<R: RequestContext> (stream: &mut TcpStream) -> Result<Requ
         log::info!("Recibido: UUID {}", uuid);
         previous_nodes.push(uuid)
     }
-
-    Ok(Request {
-        id: uuid,
-        jumps,
-        context,
-        content,
-        previous_nodes
-    })
+    Ok(previous_nodes)
 }
 
-async fn send_request<R: RequestContext>(stream: &mut TcpStream, request: &Request<R>) -> Result<()> {
+/// Everything that goes before the content length.
+pub(crate) async fn encode_request_head<R: RequestContext>(request: &Request<R>, protocol: Protocol) -> Result<Vec<u8>> {
 
-    let mut writer = Sender{ s: BufWriter::new(stream) };
+    let mut head = Vec::new();
+    let mut writer = Sender{ s: BufWriter::new(&mut head) };
     writer.s.write_all(request.id.as_bytes()).await?;
+    if let Protocol::Framed(_) = protocol {
+        writer.s.write_all(request.service.as_bytes()).await?;
+    }
     writer.s.write_u32(request.jumps).await?;
     log::info!("Enviado: JUMPS {}", request.jumps);
 
     // Enviar el context
-    R::send(&mut writer, &request.context).await?;
+    match protocol {
+        Protocol::Legacy => R::send(&mut writer, &request.context).await?,
+        Protocol::Framed(_) => {
+            let mut context = Vec::new();
+            let mut context_writer = Sender { s: BufWriter::new(&mut context) };
+            R::send(&mut context_writer, &request.context).await?;
+            context_writer.s.flush().await?;
+            drop(context_writer);
+            writer.s.write_u32(context.len() as u32).await?;
+            writer.s.write_all(&context).await?;
+        }
+    }
     //writer.write_u8(request.priority).await?;
     //writer.write_u8(request.accuracy).await?;
-    writer.s.write_u64(request.content.len() as u64).await?;
-    writer.s.write_all(&request.content).await?;
-    for uuid in request.previous_nodes.iter() {
-        writer.s.write_all(uuid.as_bytes()).await?;
-        log::info!("Enviado: UUID {}", uuid);
+    if protocol.capabilities().contains(Capabilities::DEADLINE) {
+        // 0 es sin deadline, una petición que caduca ahora manda 1.
+        let remaining = request.remaining().map(|remaining| (remaining.as_micros() as u64).max(1));
+        writer.s.write_u64(remaining.unwrap_or(0)).await?;
+    }
+    if protocol.capabilities().contains(Capabilities::HEADER_FIRST) {
+        for uuid in request.previous_nodes.iter() {
+            writer.s.write_all(uuid.as_bytes()).await?;
+        }
     }
-
     writer.s.flush().await?;
+    drop(writer);
+    Ok(head)
+}
+
+/// Size of the request written by `write_request`.
+pub(crate) fn request_len<R: RequestContext>(head: &[u8], request: &Request<R>, content_len: u64, protocol: Protocol) -> u64 {
+    let nodes = match protocol.capabilities().contains(Capabilities::HEADER_FIRST) {
+        true => 0,
+        false => request.previous_nodes.len() as u64 * 16
+    };
+    head.len() as u64 + 8 + content_len + nodes
+}
+
+/// Writes the head from `encode_request_head`, the content and, for peers
+/// without `HEADER_FIRST`, the previous nodes.
+pub(crate) async fn write_request<R: RequestContext>(stream: &mut (dyn AsyncWrite + Unpin + Send + Sync), head: &[u8], request: &Request<R>, content: Content<'_>, protocol: Protocol) -> Result<()> {
+
+    let mut writer = BufWriter::new(stream);
+    writer.write_all(head).await?;
+    writer.write_u64(content.len()).await?;
+    content.copy_to(&mut writer).await?;
+    if !protocol.capabilities().contains(Capabilities::HEADER_FIRST) {
+        for uuid in request.previous_nodes.iter() {
+            writer.write_all(uuid.as_bytes()).await?;
+            log::info!("Enviado: UUID {}", uuid);
+        }
+    }
+    writer.flush().await?;
     Ok(())
 }
 
+/// Endpoints the request can go to: this pod and those it has not
+/// visited. Only copied when some endpoint has to be left out.
+fn candidates<'a, R: RequestContext>(endpoints: &'a Endpoints<R>, previous_nodes: &[Uuid], self_uuid: Uuid) -> Cow<'a, Endpoints<R>> {
+    let visited = |uuid: &Uuid| *uuid != self_uuid && previous_nodes.contains(uuid);
+    if !endpoints.keys().any(visited) {
+        return Cow::Borrowed(endpoints);
+    }
+    Cow::Owned(endpoints.iter()
+        .filter(|(uuid, _)| !visited(uuid))
+        .map(|(uuid, ep)| (*uuid, ep.clone()))
+        .collect())
+}
+
+/// Endpoints of `service`. Requests without service (Uuid::nil()) use
+/// the only service of the pod, if it is in just one.
+fn service_endpoints<R: RequestContext>(endpoints: &ServiceEndpoints<R>, service: Uuid) -> Option<(Uuid, &Endpoints<R>)> {
+    match endpoints.get_key_value(&service) {
+        Some((service, endps)) => Some((*service, endps)),
+        None if service.is_nil() && endpoints.len() == 1 => endpoints.iter().next().map(|(service, endps)| (*service, endps)),
+        None => None
+    }
+}
+
 fn parse_endpoints<R: RequestContext>(mut json: JsonValue) -> Result<Endpoints<R>> {
     
     json.as_array_mut()
         .context("JSON is not an array.")?
-        .into_iter()
+        .iter_mut()
         .map(|item| {
             let uuid = Uuid::from_str(
                 item.get_mut("uuid")
@@ -361,13 +827,247 @@ fn parse_endpoints<R: RequestContext>(mut json: JsonValue) -> Result<Endpoints<R
                 .take();
                 
             let ip = ip_field.as_str().context("Invalid IP.")?;
+            let port = match item.get("port") {
+                Some(port) => u16::try_from(port.as_u64().context("Invalid port.")?)?,
+                None => DEFAULT_PORT
+            };
+            let text = |field: &str| item.get(field).and_then(|value| value.as_str()).map(Arc::from);
+            let (node_name, zone) = (text("node_name"), text("zone"));
+            let draining = item.get("draining").and_then(|draining| draining.as_bool()).unwrap_or(false);
+            let weight = item.get("weight").and_then(|weight| weight.as_f64());
+            let hw_info = item.get_mut("hw_info").map(JsonValue::take).filter(|hw_info| !hw_info.is_null());
+            let models = item.get_mut("models")
+                .map(|models| serde_json::from_value(models.take()))
+                .transpose()
+                .context("Invalid models.")?
+                .unwrap_or_default();
+
             Ok((uuid, Endpoint { name,
-                ip: Arc::from(format!("{ip}:9999")), // TODO: puerto configurable. 
-                hw_info: None,
+                ip: Arc::from(format!("{ip}:{port}")),
+                node_name,
+                zone,
+                hw_info,
+                models,
+                draining,
                 metrics: None,
                 metrics_queried_at: None,
+                weight,
                 last_results: AllocRingBuffer::new(5)
             }))
         })
         .collect()
 }
+
+#[cfg(test)]
+mod tests {
+    use crate::policy::Processed;
+    use super::*;
+
+    #[derive(Debug, Clone, PartialEq)]
+    struct Priority(u32);
+
+    impl RequestContext for Priority {
+        fn from_qos(qos: crate::policy::Qos) -> Self {
+            Self(qos.priority.unwrap_or(0))
+        }
+
+        async fn receive(reader: &mut Receiver<'_>) -> Result<Self, std::io::Error> {
+            Ok(Self(reader.r.read_u32().await?))
+        }
+
+        async fn send(sender: &mut Sender<'_>, req: &Self) -> Result<(), std::io::Error> {
+            sender.s.write_u32(req.0).await
+        }
+    }
+
+    /// Request as sent by the proxies and clients from before the
+    /// handshake: id, jumps, context, content length, content, hops.
+    fn baseline_request(id: Uuid, hops: &[Uuid], content: &[u8]) -> Vec<u8> {
+        let mut bytes = Vec::new();
+        bytes.extend_from_slice(id.as_bytes());
+        bytes.extend_from_slice(&(hops.len() as u32).to_be_bytes());
+        bytes.extend_from_slice(&7_u32.to_be_bytes());
+        bytes.extend_from_slice(&(content.len() as u64).to_be_bytes());
+        bytes.extend_from_slice(content);
+        for hop in hops {
+            bytes.extend_from_slice(hop.as_bytes());
+        }
+        bytes
+    }
+
+    #[tokio::test]
+    async fn legacy_request_round_trip() {
+        let id = Uuid::new_v4();
+        let hops = [Uuid::new_v4(), Uuid::new_v4()];
+        let encoded = baseline_request(id, &hops, b"imagen");
+
+        let mut input = encoded.as_slice();
+        let mut reader = Receiver { r: BufReader::new(&mut input) };
+        let (request, content) = read_request::<Priority>(&mut reader, Protocol::Legacy, 1024).await.unwrap();
+        assert_eq!(request.id, id);
+        assert!(request.service.is_nil());
+        assert_eq!(request.jumps, 2);
+        assert_eq!(request.context, Priority(7));
+        assert_eq!(request.previous_nodes, hops);
+        assert_eq!(request.deadline, None);
+
+        let content = content.read_all().await.unwrap();
+        assert_eq!(content, b"imagen");
+        let head = encode_request_head(&request, Protocol::Legacy).await.unwrap();
+        let mut output = Vec::new();
+        write_request(&mut output, &head, &request, Content::from(content), Protocol::Legacy).await.unwrap();
+        assert_eq!(output, encoded);
+        assert_eq!(request_len(&head, &request, 6, Protocol::Legacy), encoded.len() as u64);
+    }
+
+    fn failed_response() -> Response {
+        let (first, second) = (Uuid::new_v4(), Uuid::new_v4());
+        Response {
+            status: ResponseStatus::Timeout,
+            route: vec![first, second],
+            model: Some("yolo".to_string()),
+            hops: vec![HopTiming {
+                pod: first,
+                queue: Duration::from_micros(15),
+                inference: Duration::ZERO,
+                network: Duration::from_millis(3)
+            }],
+            failed_at: Some(second),
+            payload: b"sin respuesta".to_vec()
+        }
+    }
+
+    #[test]
+    fn response_round_trip() {
+        let response = failed_response();
+        assert_eq!(Response::decode(&response.encode()).unwrap(), response);
+
+        let ok = Response { status: ResponseStatus::Ok, model: None, failed_at: None, ..failed_response() };
+        assert_eq!(Response::decode(&ok.encode()).unwrap(), ok);
+    }
+
+    #[test]
+    fn response_without_failed_at_decodes() {
+        // La versión anterior terminaba la cabecera con los tiempos.
+        let response = Response { failed_at: None, ..failed_response() };
+        let encoded = response.encode();
+        let header_len = u32::from_be_bytes(encoded[..4].try_into().unwrap()) as usize - 16;
+        let mut previous = Vec::new();
+        previous.extend_from_slice(&(header_len as u32).to_be_bytes());
+        previous.extend_from_slice(&encoded[4..4 + header_len]);
+        previous.extend_from_slice(&encoded[4 + header_len + 16..]);
+
+        assert_eq!(Response::decode(&previous).unwrap(), response);
+    }
+
+    #[test]
+    fn truncated_response_is_an_error() {
+        let encoded = failed_response().encode();
+        for len in 0..encoded.len() {
+            assert!(Response::decode(&encoded[..len]).is_err(), "frame cut at {len} bytes was decoded");
+        }
+
+        // Una longitud corrupta no reserva memoria por adelantado.
+        let mut corrupt = encoded.clone();
+        corrupt[6..10].copy_from_slice(&u32::MAX.to_be_bytes());
+        assert!(Response::decode(&corrupt).is_err());
+    }
+
+    const SELF: Uuid = Uuid::from_u128(1);
+    const VISITED: Uuid = Uuid::from_u128(2);
+    const OTHER: Uuid = Uuid::from_u128(3);
+
+    /// Always forwards to `OTHER`, so any local processing comes from the hop limit.
+    struct Forward;
+
+    impl Policy<Priority> for Forward {
+        async fn choose_target(&self, _request: &Request<Priority>, _endpoints: &Endpoints<Priority>) -> Uuid {
+            OTHER
+        }
+
+        async fn process_locally(&self, _request: &Request<Priority>, _content: Content<'_>) -> Result<Processed> {
+            Ok(Processed { payload: b"local".to_vec(), model: None })
+        }
+    }
+
+    fn endpoints(uuids: &[Uuid]) -> Endpoints<Priority> {
+        uuids.iter()
+            .map(|uuid| (*uuid, Endpoint {
+                name: Arc::from(format!("pod-{uuid}")),
+                // Nadie escucha en el puerto 9: reenviar falla.
+                ip: Arc::from("127.0.0.1:9"),
+                node_name: None,
+                zone: None,
+                hw_info: None,
+                models: Vec::new(),
+                draining: false,
+                metrics: None,
+                metrics_queried_at: None,
+                weight: None,
+                last_results: AllocRingBuffer::new(5)
+            }))
+            .collect()
+    }
+
+    /// Server without listeners whose only service has `uuids` as endpoints.
+    fn server(uuids: &[Uuid], max_hops: u32) -> ProxyServer<Forward, Priority> {
+        ProxyServer {
+            self_uuid: SELF,
+            endpoints: RwLock::new(ServiceEndpoints::from([(Uuid::from_u128(0x5e), endpoints(uuids))])),
+            query_sem: Semaphore::new(MAX_CONCURRENT_METRICS_QUERY),
+            sender: mpsc::channel(1).0,
+            policy: Forward,
+            request_timeout: Duration::from_secs(1),
+            max_request_bytes: DEFAULT_MAX_REQUEST_BYTES,
+            max_hops,
+            peers: PeerPool::new(DEFAULT_MAX_REQUEST_BYTES),
+            http_port: DEFAULT_HTTP_PORT,
+            grpc_port: DEFAULT_GRPC_PORT
+        }
+    }
+
+    fn request(jumps: u32, previous_nodes: Vec<Uuid>) -> Request<Priority> {
+        Request {
+            id: Uuid::new_v4(),
+            service: Uuid::nil(),
+            jumps,
+            context: Priority(0),
+            content_len: 0,
+            previous_nodes,
+            deadline: None
+        }
+    }
+
+    #[test]
+    fn candidates_skip_visited_pods() {
+        let endpoints = endpoints(&[SELF, VISITED, OTHER]);
+
+        let filtered = candidates(&endpoints, &[VISITED, SELF], SELF);
+        assert_eq!(filtered.keys().copied().collect::<Vec<_>>(), [SELF, OTHER]);
+
+        // Sin pods visitados no se copian los endpoints.
+        assert!(matches!(candidates(&endpoints, &[Uuid::new_v4()], SELF), Cow::Borrowed(_)));
+    }
+
+    #[tokio::test]
+    async fn hop_limit_processes_locally() {
+        let server = server(&[SELF, OTHER], 2);
+        let mut request = request(2, vec![VISITED, OTHER]);
+
+        let reply = server.route_request(&mut request, Content::from(Vec::new()), Instant::now()).await.unwrap();
+        let response = reply.into_response(Vec::new());
+        assert_eq!(response.status, ResponseStatus::Ok);
+        assert_eq!(response.payload, b"local");
+        assert_eq!(response.route, [VISITED, OTHER, SELF]);
+        assert_eq!(request.jumps, 2);
+    }
+
+    #[tokio::test]
+    async fn hop_limit_without_local_endpoint_fails() {
+        let server = server(&[OTHER], 2);
+        let mut request = request(2, vec![VISITED, OTHER]);
+
+        let error = server.route_request(&mut request, Content::from(Vec::new()), Instant::now()).await.err().unwrap();
+        assert!(matches!(error, ProxyError::HopLimit { pod: SELF, limit: 2 }), "{error}");
+    }
+}
@@ -10,6 +10,7 @@ use tokio::sync::{mpsc, oneshot, Mutex};
 use tokio::task::JoinHandle;
 use crate::{Message, ENDPS_ANNOT};
 use serde_json::Value as JsonValue;
+use uuid::Uuid;
 
 
 pub struct AnnotationsWatcher {
@@ -68,19 +69,14 @@ impl AnnotationsWatcher {
                     if let Some(annots) = pod.metadata.annotations {     
                         
                         let mut previous_annots = last_annotations.lock().await;
-                        if let Some(endpoints) = annots.get(ENDPS_ANNOT) {
-                            
-                            // If endpoints have changed
-                            if !previous_annots.get(ENDPS_ANNOT).is_some_and(|prev_endps| *prev_endps == *endpoints) {
-                                
-                                match serde_json::from_str(&endpoints) {
-                                    Ok(new_endpoints) => {
-                                        sender.send(Message::EndpointsChanged(new_endpoints)).await.unwrap();
-                                        log::info!("Received new endpoints: {:?}", endpoints);
-                                    }
-                                    Err(e) => log::error!("Failed to parse received endpoints: {e}")
+                        for (service, endpoints) in changed_endpoints(&previous_annots, &annots) {
+                            match serde_json::from_str(&endpoints) {
+                                Ok(new_endpoints) => {
+                                    log::info!("Received new endpoints for service {service}: {:?}", endpoints);
+                                    sender.send(Message::EndpointsChanged { service, endpoints: new_endpoints }).await.unwrap();
                                 }
-                            }         
+                                Err(e) => log::error!("Failed to parse received endpoints: {e}")
+                            }
                         }
                         *previous_annots = annots;
                     }
@@ -152,3 +148,41 @@ impl AnnotationsWatcher {
     }
 
 }
+
+/// Service of an endpoints annotation: `endpoints.<service uid>`, or
+/// Uuid::nil() for the legacy `endpoints` annotation.
+fn endpoints_service(annot_name: &str) -> Option<Uuid> {
+    match annot_name.strip_prefix(ENDPS_ANNOT)? {
+        "" => Some(Uuid::nil()),
+        suffix => Uuid::parse_str(suffix.strip_prefix('.')?).ok()
+    }
+}
+
+/// Endpoints annotations by service. The legacy annotation is ignored if
+/// there are per-service ones, the controller keeps it for old proxies.
+fn endpoints_annotations(annotations: &BTreeMap<String, String>) -> BTreeMap<Uuid, &String> {
+    let endpoints: BTreeMap<Uuid, &String> = annotations.iter()
+        .filter_map(|(name, value)| Some((endpoints_service(name)?, value)))
+        .collect();
+    if endpoints.keys().any(|service| !service.is_nil()) {
+        return endpoints.into_iter().filter(|(service, _)| !service.is_nil()).collect();
+    }
+    endpoints
+}
+
+/// Endpoints annotations that changed between `previous` and `current`.
+/// Services whose annotation disappeared get an empty list.
+fn changed_endpoints(previous: &BTreeMap<String, String>, current: &BTreeMap<String, String>) -> Vec<(Uuid, String)> {
+
+    let (previous, current) = (endpoints_annotations(previous), endpoints_annotations(current));
+    let mut changed: Vec<(Uuid, String)> = current.iter()
+        .filter(|(service, value)| previous.get(*service) != Some(*value))
+        .map(|(service, value)| (*service, (*value).clone()))
+        .collect();
+
+    changed.extend(previous.keys()
+        .filter(|service| !current.contains_key(*service))
+        .map(|service| (*service, "[]".to_string()))
+    );
+    changed
+}
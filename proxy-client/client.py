@@ -1,7 +1,7 @@
 import struct
 import socket
 from argparse import ArgumentParser
-from uuid import uuid4
+from uuid import UUID, uuid4
 import pickle
 import numpy as np
 from PIL import Image
@@ -11,6 +11,24 @@ from random import random
 
 NAMESPACE = "kube-triton"
 
+# Cabecera del protocolo: magic, versión y capacidades.
+MAGIC = b"EDGP"
+PROTOCOL_VERSION = 1
+CAP_STRUCTURED_RESPONSE = 1 << 0
+# Sin saltos la petición es igual con y sin HEADER_FIRST.
+CAP_HEADER_FIRST = 1 << 2
+CAP_DEADLINE = 1 << 3
+CAPABILITIES = CAP_STRUCTURED_RESPONSE | CAP_HEADER_FIRST
+STATUS = {
+    0: "ok",
+    1: "backend failure",
+    2: "timeout",
+    3: "no endpoints",
+    4: "rejected",
+    5: "hop limit",
+    6: "unreachable",
+}
+
 def get_pods():
 
     service_uuid = subprocess.check_output(f"sudo kubectl --namespace={NAMESPACE} describe tservice | grep UID", shell=True).decode("utf8").split()[1]
@@ -122,6 +140,30 @@ def argument_parser() -> ArgumentParser:
         help="Quantization (int8, tf32, etc...)",
     )
 
+    parser.add_argument(
+        "-s",
+        "--service",
+        type=UUID,
+        required=False,
+        default=UUID(int=0),
+        help="EdgeService UID. Default is the only service of the pod. Ignored with --legacy.",
+    )
+
+    parser.add_argument(
+        "-d",
+        "--deadline-ms",
+        type=int,
+        required=False,
+        default=0,
+        help="Time the proxies have to answer, in ms. Default is no deadline.",
+    )
+
+    parser.add_argument(
+        "--legacy",
+        action="store_true",
+        help="Use the protocol without handshake, for proxies that predate it.",
+    )
+
     return parser
 
 def guardar_imagen_prediccion(predictions, nombre):
@@ -143,6 +185,63 @@ def guardar_imagen_prediccion(predictions, nombre):
 
     image = Image.fromarray(rgb, mode="RGB").save(nombre)
 
+def recv_exact(sock, size):
+
+    data = bytes()
+    while len(data) < size:
+        received = sock.recv(size - len(data))
+        if len(received) == 0:
+            raise ConnectionError("Connection closed during handshake")
+        data += received
+    return data
+
+def handshake(sock, capabilities):
+
+    sock.sendall(MAGIC + struct.pack(">HI", PROTOCOL_VERSION, capabilities))
+    answer = recv_exact(sock, 10)
+    if answer[:4] != MAGIC:
+        raise ConnectionError("Proxy does not speak the protocol, use --legacy")
+    version, capabilities = struct.unpack(">HI", answer[4:])
+    return version, capabilities
+
+def parse_response(frame):
+
+    header_len, = struct.unpack_from(">I", frame, 0)
+    header = frame[4:4 + header_len]
+    offset = 0
+
+    status, n_route = struct.unpack_from(">HI", header, offset)
+    offset += 6
+    route = [str(UUID(bytes=header[offset + 16 * i:offset + 16 * (i + 1)])) for i in range(n_route)]
+    offset += 16 * n_route
+    model_len, = struct.unpack_from(">I", header, offset)
+    offset += 4
+    model = header[offset:offset + model_len].decode("utf-8")
+    offset += model_len
+    n_hops, = struct.unpack_from(">I", header, offset)
+    offset += 4
+    hops = []
+    for _ in range(n_hops):
+        pod = str(UUID(bytes=header[offset:offset + 16]))
+        queue, inference, network = struct.unpack_from(">QQQ", header, offset + 16)
+        hops.append({ "pod": pod, "queue_us": queue, "inference_us": inference, "network_us": network })
+        offset += 40
+    # Pod en el que falló la petición, no lo llevan las respuestas antiguas.
+    failed_at = None
+    if len(header) >= offset + 16 and header[offset:offset + 16] != bytes(16):
+        failed_at = str(UUID(bytes=header[offset:offset + 16]))
+
+    payload_len, = struct.unpack_from(">Q", frame, 4 + header_len)
+    payload = frame[12 + header_len:12 + header_len + payload_len]
+    return {
+        "status": STATUS.get(status, "backend failure"),
+        "route": route,
+        "model": model or None,
+        "hops": hops,
+        "failed_at": failed_at,
+        "payload": payload
+    }
+
 def main():
     parser = argument_parser()
     args = parser.parse_args()
@@ -162,15 +261,29 @@ def main():
             img = file.read()
             file.close()
 
-            sock.send(uuid.bytes)
-            sock.send(struct.pack(">I", 0))
-            sock.send(struct.pack(">I", args.priority))
-            sock.send(struct.pack(">I", args.accuracy))
-            if args.quantization:
-                sock.send(struct.pack(">I", len(args.quantization)))
-                sock.sendall(args.quantization.encode("utf-8"))
+            if args.legacy:
+                sock.send(uuid.bytes)
+                sock.send(struct.pack(">I", 0))
+                sock.send(struct.pack(">I", args.priority))
+                sock.send(struct.pack(">I", args.accuracy))
+                if args.quantization:
+                    sock.send(struct.pack(">I", len(args.quantization)))
+                    sock.sendall(args.quantization.encode("utf-8"))
+                else:
+                    sock.send(struct.pack(">I", 4))
             else:
-                sock.send(struct.pack(">I", 4))
+                ours = CAPABILITIES | (CAP_DEADLINE if args.deadline_ms else 0)
+                _, capabilities = handshake(sock, ours)
+                structured = capabilities & CAP_STRUCTURED_RESPONSE
+                model = args.quantization.encode("utf-8") if args.quantization else b""
+                context = struct.pack(">III", args.priority, args.accuracy, len(model)) + model
+                sock.send(uuid.bytes)
+                sock.send(args.service.bytes)
+                sock.send(struct.pack(">I", 0))
+                sock.send(struct.pack(">I", len(context)))
+                sock.sendall(context)
+                if capabilities & CAP_DEADLINE:
+                    sock.send(struct.pack(">Q", args.deadline_ms * 1000))
 
             sock.send(struct.pack(">Q", len(img)))
             sock.send(img)
@@ -183,7 +296,18 @@ def main():
                 response += received
 
             t2 = time_ns()
-            print(response.decode("utf-8"))
+            if not args.legacy and structured:
+                response = parse_response(response)
+                print("Status:", response["status"])
+                if response["failed_at"]:
+                    print("Failed at:", response["failed_at"])
+                print("Model:", response["model"])
+                print("Route:", "->".join(response["route"]))
+                for hop in response["hops"]:
+                    print(f"  {hop['pod']}: queue {hop['queue_us']} us, inference {hop['inference_us']} us, network {hop['network_us']} us")
+                print(response["payload"].decode("utf-8", errors="replace"))
+            else:
+                print(response.decode("utf-8"))
         except TimeoutError as e:
             t2 = None
             print("Error cliente:", e)